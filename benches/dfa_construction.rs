@@ -0,0 +1,79 @@
+//! Tracks DFA state counts and build times for large terminal sets.
+//!
+//! Run with `cargo bench --bench dfa_construction`.
+
+use sep1::finite_automata::{eat_u8_set, greedy_group, groups, rep, Expr, ExprGroup, ExprGroups};
+use sep1::u8set::U8Set;
+use std::time::{Duration, Instant};
+
+const PYTHON_KEYWORDS: [&str; 35] = [
+    "False", "None", "True", "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del",
+    "elif", "else", "except", "finally", "for", "from", "global", "if", "import", "in", "is", "lambda", "nonlocal",
+    "not", "or", "pass", "raise", "return", "try", "while", "with", "yield",
+];
+
+/// Deterministic pseudo-random lowercase words, so runs are comparable.
+fn words(count: usize, seed: u64) -> Vec<String> {
+    let mut state = seed;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    (0..count)
+        .map(|_| {
+            let len = 3 + (next() % 8) as usize;
+            (0..len).map(|_| (b'a' + (next() % 26) as u8) as char).collect()
+        })
+        .collect()
+}
+
+fn literal(word: &str) -> Expr {
+    Expr::U8Seq(word.as_bytes().to_vec())
+}
+
+fn identifier() -> Expr {
+    let alpha = U8Set::from_range(b'a', b'z').union(&U8Set::from_range(b'A', b'Z')).union(&U8Set::from_byte(b'_'));
+    Expr::Seq(vec![eat_u8_set(alpha), rep(eat_u8_set(alpha.union(&U8Set::from_range(b'0', b'9'))))])
+}
+
+/// One group per keyword, plus an identifier group that overlaps all of them.
+fn keywords_and_identifier(words: &[String]) -> ExprGroups {
+    let mut groups_vec: Vec<ExprGroup> = words.iter().map(|word| greedy_group(literal(word))).collect();
+    groups_vec.push(greedy_group(identifier()));
+    groups(groups_vec)
+}
+
+/// A single group matching any of the quoted strings, like a JSON-schema `enum`.
+fn json_enum(words: &[String]) -> ExprGroups {
+    groups(vec![greedy_group(Expr::Choice(words.iter().map(|word| literal(&format!("\"{}\"", word))).collect()))])
+}
+
+fn bench(name: &str, expr_groups: impl Fn() -> ExprGroups) {
+    const RUNS: usize = 5;
+    let mut times: Vec<Duration> = Vec::new();
+    let mut num_states = 0;
+    for _ in 0..RUNS {
+        let expr_groups = expr_groups();
+        let start = Instant::now();
+        let regex = expr_groups.build();
+        times.push(start.elapsed());
+        num_states = regex.dfa.states.len();
+    }
+    times.sort();
+    println!("{:<40} {:>8} states {:>12.3?} (median of {})", name, num_states, times[RUNS / 2], RUNS);
+}
+
+fn main() {
+    let python_keywords: Vec<String> = PYTHON_KEYWORDS.iter().map(|word| word.to_string()).collect();
+    bench("python keywords (single group)", || {
+        groups(vec![greedy_group(Expr::Choice(python_keywords.iter().map(|word| literal(word)).collect()))])
+    });
+    bench("python keywords + identifier", || keywords_and_identifier(&python_keywords));
+    for count in [100, 500, 2000] {
+        let words = words(count, 42);
+        bench(&format!("{} keywords + identifier", count), || keywords_and_identifier(&words));
+        bench(&format!("{}-value JSON enum", count), || json_enum(&words));
+    }
+}
@@ -0,0 +1,113 @@
+//! Compares lexing throughput of `Regex` and its `CompiledRegex` form, per byte of input and for precomputing a
+//! vocabulary.
+//!
+//! Both tokenizers are called through the `Tokenizer` trait, so the `Regex` rows measure the uncompiled DFA that
+//! `Regex::find` and `Regex::execute_from_state` use.
+//!
+//! Run with `cargo bench --bench tokenizer_throughput`.
+
+use bimap::BiBTreeMap;
+use sep1::finite_automata::{eat_u8, eat_u8_set, greedy_group, groups, rep, rep1, Expr, ExprGroups};
+use sep1::precompute::{precompute, LLMTokenID, Tokenizer};
+use sep1::u8set::U8Set;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+/// Deterministic pseudo-random bytes, so runs are comparable.
+fn random_bytes(count: usize, seed: u64, alphabet: &[u8]) -> Vec<u8> {
+    let mut state = seed;
+    (0..count)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            alphabet[(state % alphabet.len() as u64) as usize]
+        })
+        .collect()
+}
+
+/// Identifiers, numbers, whitespace and punctuation, like a small programming language.
+fn code_tokenizer() -> ExprGroups {
+    let alpha = U8Set::from_range(b'a', b'z').union(&U8Set::from_byte(b'_'));
+    let digit = U8Set::from_range(b'0', b'9');
+    groups(vec![
+        greedy_group(Expr::U8Seq(b"def".to_vec())),
+        greedy_group(Expr::U8Seq(b"return".to_vec())),
+        greedy_group(Expr::Seq(vec![eat_u8_set(alpha), rep(eat_u8_set(alpha.union(&digit)))])),
+        greedy_group(rep1(eat_u8_set(digit))),
+        greedy_group(rep1(eat_u8_set(U8Set::from_bytes(b" \n")))),
+        greedy_group(eat_u8(b'(')),
+        greedy_group(eat_u8(b')')),
+        greedy_group(eat_u8(b':')),
+        greedy_group(eat_u8(b'+')),
+    ])
+}
+
+fn median_time(runs: usize, mut f: impl FnMut()) -> Duration {
+    let mut times: Vec<Duration> = (0..runs)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .collect();
+    times.sort();
+    times[runs / 2]
+}
+
+/// Lexes `text` from the initial state, restarting after every token, and returns the number of tokens.
+fn lex(tokenizer: &impl Tokenizer, text: &[u8]) -> usize {
+    let mut position = 0;
+    let mut num_tokens = 0;
+    while position < text.len() {
+        let result = tokenizer.execute_from_state(&text[position..], tokenizer.initial_state_id());
+        let Some(width) = result.matches.iter().map(|token| token.width).max() else {
+            // Skip bytes that no token matches.
+            position += 1;
+            continue;
+        };
+        position += width;
+        num_tokens += 1;
+    }
+    num_tokens
+}
+
+fn bench_lex(name: &str, tokenizer: &impl Tokenizer, text: &[u8]) {
+    const RUNS: usize = 5;
+    let time = median_time(RUNS, || {
+        black_box(lex(tokenizer, black_box(text)));
+    });
+    let megabytes_per_second = text.len() as f64 / time.as_secs_f64() / 1e6;
+    println!("{:<40} {:>12.3?} {:>10.1} MB/s (median of {})", name, time, megabytes_per_second, RUNS);
+}
+
+fn bench_precompute(name: &str, tokenizer: &impl Tokenizer, llm_tokens: &BiBTreeMap<Vec<u8>, LLMTokenID>) {
+    const RUNS: usize = 3;
+    let max_llm_token_id = llm_tokens.len();
+    let time = median_time(RUNS, || {
+        black_box(precompute(tokenizer, llm_tokens, LLMTokenID(max_llm_token_id), max_llm_token_id).unwrap());
+    });
+    println!("{:<40} {:>12.3?} (median of {})", name, time, RUNS);
+}
+
+fn main() {
+    let regex = code_tokenizer().build();
+    let compiled = regex.compile();
+    let alphabet = b"abcdefghijklmnopqrstuvwxyz_0123456789  \n():+";
+
+    let text = random_bytes(1 << 20, 42, alphabet);
+    bench_lex("lex 1 MiB: Regex", &regex, &text);
+    bench_lex("lex 1 MiB: CompiledRegex", &compiled, &text);
+
+    // A synthetic vocabulary of short random byte strings.
+    let mut llm_tokens = BiBTreeMap::new();
+    for (i, length) in (0..2000).map(|i| (i, 1 + i % 6)) {
+        let llm_token = random_bytes(length, i as u64 + 1, alphabet);
+        if !llm_tokens.contains_left(&llm_token) {
+            let llm_token_id = LLMTokenID(llm_tokens.len());
+            llm_tokens.insert(llm_token, llm_token_id);
+        }
+    }
+    bench_precompute("precompute 2000 tokens: Regex", &regex, &llm_tokens);
+    bench_precompute("precompute 2000 tokens: CompiledRegex", &compiled, &llm_tokens);
+}
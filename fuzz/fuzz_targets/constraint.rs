@@ -0,0 +1,32 @@
+// fuzz/fuzz_targets/constraint.rs
+//! Builds a constraint for a random grammar and vocabulary, and checks it against the brute-force
+//! `ReferenceConstraint` on random walks.
+#![no_main]
+
+use arbitrary::Unstructured;
+use bimap::BiBTreeMap;
+use libfuzzer_sys::fuzz_target;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use sep1::consistency::check_consistency;
+use sep1::constraint::GrammarConstraint;
+use sep1::interface::Grammar;
+use sep1::precompute::LLMTokenID;
+use sep1_fuzz::{arbitrary_grammar_exprs, arbitrary_llm_tokens};
+
+fuzz_target!(|data: &[u8]| {
+    let mut u = Unstructured::new(data);
+    let (Ok(exprs), Ok(llm_tokens), Ok(seed)) = (arbitrary_grammar_exprs(&mut u), arbitrary_llm_tokens(&mut u), u.arbitrary::<u64>()) else {
+        return;
+    };
+    let grammar = Grammar::from_exprs(exprs);
+    let llm_token_map: BiBTreeMap<Vec<u8>, LLMTokenID> = llm_tokens.iter().enumerate().map(|(i, llm_token)| (llm_token.clone(), LLMTokenID(i))).collect();
+    let grammar_constraint = GrammarConstraint::from_grammar(grammar.compile(), llm_token_map, llm_tokens.len(), llm_tokens.len());
+    let mut grammar_constraint_state = grammar_constraint.init();
+    let mut rng = StdRng::seed_from_u64(seed);
+    // The parser and the reference are exponential in the input length on highly ambiguous grammars, so keep the
+    // walks short.
+    if let Err(inconsistency) = check_consistency(&mut grammar_constraint_state, &mut rng, 3, 5) {
+        panic!("{:?}", inconsistency);
+    }
+});
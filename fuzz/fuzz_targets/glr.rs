@@ -0,0 +1,39 @@
+// fuzz/fuzz_targets/glr.rs
+//! Parses random terminal sequences with random grammars, and checks the result against a brute-force
+//! recognizer.
+#![no_main]
+
+use arbitrary::Unstructured;
+use libfuzzer_sys::fuzz_target;
+use sep1::glr::grammar::{NonTerminal, Terminal};
+use sep1::glr::table::generate_glr_parser;
+use sep1_fuzz::{arbitrary_productions, brute_force_recognize};
+
+fuzz_target!(|data: &[u8]| {
+    let mut u = Unstructured::new(data);
+    let Ok(productions) = arbitrary_productions(&mut u) else {
+        return;
+    };
+    let parser = generate_glr_parser(&productions, 0);
+    // Only terminals that appear in the grammar have IDs.
+    let terminals: Vec<&Terminal> = parser.terminal_map.left_values().filter(|terminal| terminal.0 != "$").collect();
+    if terminals.is_empty() {
+        return;
+    }
+    let Ok(len) = u.int_in_range(0..=6) else {
+        return;
+    };
+    let Ok(input) = (0..len).map(|_| u.choose(&terminals).map(|&terminal| terminal.clone())).collect::<arbitrary::Result<Vec<Terminal>>>() else {
+        return;
+    };
+    let input_ids: Vec<_> = input.iter().map(|terminal| *parser.terminal_map.get_by_left(terminal).unwrap()).collect();
+
+    let glr_parse_state = parser.parse(&input_ids);
+    assert_eq!(
+        glr_parse_state.fully_matches(),
+        brute_force_recognize(&productions, &NonTerminal("start".to_string()), &input),
+        "{:?} on {:?}",
+        productions,
+        input,
+    );
+});
@@ -0,0 +1,26 @@
+// fuzz/fuzz_targets/regex.rs
+//! Checks which prefixes of a random input fully match a random regex, against the `regex` crate.
+#![no_main]
+
+use arbitrary::Unstructured;
+use libfuzzer_sys::fuzz_target;
+use sep1_fuzz::{arbitrary_expr, arbitrary_text, to_full_match_regex};
+
+fuzz_target!(|data: &[u8]| {
+    let mut u = Unstructured::new(data);
+    let (Ok(expr), Ok(text)) = (arbitrary_expr(&mut u, 0), arbitrary_text(&mut u, 8)) else {
+        return;
+    };
+    let expected = to_full_match_regex(&expr);
+    let regex = expr.clone().build();
+    for end in 0..=text.len() {
+        let prefix = &text[..end];
+        assert_eq!(
+            regex.definitely_fully_matches(prefix),
+            expected.is_match(prefix),
+            "{:?} on {:?}",
+            expr,
+            String::from_utf8_lossy(prefix),
+        );
+    }
+});
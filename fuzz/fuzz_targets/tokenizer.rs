@@ -0,0 +1,80 @@
+// fuzz/fuzz_targets/tokenizer.rs
+//! Runs the `Tokenizer` implementations on random groups and inputs:
+//! - `Regex`, `CompiledRegex` and `LazyRegex` must find the same matches,
+//! - each match of a group without lookahead must be its longest match, according to the `regex` crate,
+//! - executing the input in two parts, through the intermediate state, must find the same matches as executing it
+//!   at once.
+#![no_main]
+
+use arbitrary::Unstructured;
+use libfuzzer_sys::fuzz_target;
+use sep1::finite_automata::{groups, ExprGroup};
+use sep1::precompute::{ExecuteResult, Tokenizer};
+use sep1_fuzz::{arbitrary_expr_with_lookahead, arbitrary_text, to_full_match_regex};
+use std::collections::{BTreeMap, BTreeSet};
+
+fn matches(execute_result: &ExecuteResult) -> BTreeMap<usize, usize> {
+    execute_result.matches.iter().map(|token| (token.id, token.width)).collect()
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut u = Unstructured::new(data);
+    let Ok(num_groups) = u.int_in_range(1..=3) else {
+        return;
+    };
+    let Ok(exprs) = (0..num_groups).map(|_| arbitrary_expr_with_lookahead(&mut u)).collect::<arbitrary::Result<Vec<_>>>() else {
+        return;
+    };
+    let Ok(text) = arbitrary_text(&mut u, 8) else {
+        return;
+    };
+    let expr_groups = groups(exprs.iter().map(|(expr, _)| ExprGroup::from(expr.clone())).collect());
+    let regex = expr_groups.clone().build();
+    let compiled_regex = regex.compile();
+    let lazy_regex = expr_groups.build_lazy(16);
+
+    // State IDs differ between the implementations, so compare the groups that can still match instead. An
+    // implementation may stop early where no group can match anymore.
+    fn run(tokenizer: &impl Tokenizer, text: &[u8]) -> (BTreeMap<usize, usize>, BTreeSet<usize>) {
+        let result = tokenizer.execute_from_state(text, tokenizer.initial_state_id());
+        let accessible = result.new_state.map_or(BTreeSet::new(), |state| tokenizer.tokens_accessible_from_state(state).into_iter().collect());
+        (matches(&result), accessible)
+    }
+    let expected = run(&regex, &text);
+    assert_eq!(run(&compiled_regex, &text), expected, "{:?} on {:?}", exprs, String::from_utf8_lossy(&text));
+    assert_eq!(run(&lazy_regex, &text), expected, "{:?} on {:?}", exprs, String::from_utf8_lossy(&text));
+
+    let initial_state = regex.initial_state_id();
+    let result = regex.execute_from_state(&text, initial_state);
+
+    for (group_id, (expr, has_lookahead)) in exprs.iter().enumerate() {
+        if *has_lookahead {
+            continue;
+        }
+        let full_match_regex = to_full_match_regex(expr);
+        let longest_match = (1..=text.len()).rev().find(|&end| full_match_regex.is_match(&text[..end]));
+        assert_eq!(matches(&result).get(&group_id).copied(), longest_match, "{:?} on {:?}", expr, String::from_utf8_lossy(&text));
+    }
+
+    if exprs.iter().any(|(_, has_lookahead)| *has_lookahead) {
+        return;
+    }
+    for split in 0..=text.len() {
+        let first = regex.execute_from_state(&text[..split], initial_state);
+        let Some(state) = first.new_state else {
+            // Nothing can match past `split`.
+            assert_eq!(matches(&first), matches(&result));
+            assert_eq!(result.new_state, None);
+            continue;
+        };
+        let second = regex.execute_from_state(&text[split..], state);
+        let mut combined = matches(&first);
+        for (group_id, width) in matches(&second) {
+            if width > 0 {
+                combined.insert(group_id, split + width);
+            }
+        }
+        assert_eq!(combined, matches(&result), "split at {}", split);
+        assert_eq!(second.new_state, result.new_state, "split at {}", split);
+    }
+});
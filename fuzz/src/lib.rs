@@ -0,0 +1,184 @@
+// fuzz/src/lib.rs
+//! Generators and brute-force oracles shared by the fuzz targets. Run a target with e.g.
+//! `cargo +nightly fuzz run constraint` from the repository root.
+//!
+//! Everything is generated over a small alphabet, so that random inputs actually match the random regexes and
+//! grammars.
+use arbitrary::{Result, Unstructured};
+use sep1::finite_automata::{eat_u8_set, lookahead, negative_lookahead, Expr, QuantifierType};
+use sep1::glr::grammar::{NonTerminal, Production, Symbol, Terminal};
+use sep1::interface::GrammarExpr;
+use sep1::u8set::U8Set;
+use std::collections::BTreeSet;
+
+pub const ALPHABET: &[u8] = b"abc";
+
+const MAX_DEPTH: usize = 3;
+
+pub fn arbitrary_byte(u: &mut Unstructured) -> Result<u8> {
+    u.choose(ALPHABET).copied()
+}
+
+pub fn arbitrary_text(u: &mut Unstructured, max_len: usize) -> Result<Vec<u8>> {
+    let len = u.int_in_range(0..=max_len)?;
+    (0..len).map(|_| arbitrary_byte(u)).collect()
+}
+
+/// Returns a set of bytes of the alphabet, which may be empty.
+fn arbitrary_u8set(u: &mut Unstructured) -> Result<U8Set> {
+    let mut u8set = U8Set::none();
+    for &byte in ALPHABET {
+        if u.arbitrary()? {
+            u8set.insert(byte);
+        }
+    }
+    Ok(u8set)
+}
+
+fn arbitrary_exprs(u: &mut Unstructured, depth: usize) -> Result<Vec<Expr>> {
+    let len = u.int_in_range(1..=3)?;
+    (0..len).map(|_| arbitrary_expr(u, depth + 1)).collect()
+}
+
+/// Returns a random regex without lookaheads, which the `regex` crate can also express.
+pub fn arbitrary_expr(u: &mut Unstructured, depth: usize) -> Result<Expr> {
+    let max_kind = if depth >= MAX_DEPTH { 2 } else { 5 };
+    Ok(match u.int_in_range(0..=max_kind)? {
+        0 => Expr::U8Seq((0..u.int_in_range(1..=3)?).map(|_| arbitrary_byte(u)).collect::<Result<_>>()?),
+        1 => Expr::U8Class(arbitrary_u8set(u)?),
+        2 => Expr::Epsilon,
+        3 => {
+            let quantifier_type = u.choose(&[QuantifierType::ZeroOrMore, QuantifierType::OneOrMore, QuantifierType::ZeroOrOne])?.clone();
+            Expr::Quantifier(Box::new(arbitrary_expr(u, depth + 1)?), quantifier_type)
+        }
+        4 => Expr::Choice(arbitrary_exprs(u, depth)?),
+        _ => Expr::Seq(arbitrary_exprs(u, depth)?),
+    })
+}
+
+/// Like `arbitrary_expr`, but may end with a (negative) lookahead. Returns whether it does.
+pub fn arbitrary_expr_with_lookahead(u: &mut Unstructured) -> Result<(Expr, bool)> {
+    let expr = arbitrary_expr(u, 0)?;
+    Ok(match u.int_in_range(0..=2)? {
+        0 => (Expr::Seq(vec![expr, lookahead(eat_u8_set(arbitrary_u8set(u)?))]), true),
+        1 => (Expr::Seq(vec![expr, negative_lookahead(eat_u8_set(arbitrary_u8set(u)?))]), true),
+        _ => (expr, false),
+    })
+}
+
+/// Writes `expr` in the syntax of the `regex` crate, for `regex::bytes::Regex`. `expr` must not contain
+/// lookaheads or the boolean operators.
+pub fn to_regex_syntax(expr: &Expr) -> String {
+    fn byte(byte: u8) -> String {
+        format!("\\x{:02X}", byte)
+    }
+    match expr {
+        Expr::U8Seq(bytes) => format!("(?:{})", bytes.iter().map(|&b| byte(b)).collect::<String>()),
+        Expr::U8Class(u8set) if u8set.is_empty() => "[^\\x00-\\xFF]".to_string(),
+        Expr::U8Class(u8set) => format!("[{}]", u8set.iter().map(byte).collect::<String>()),
+        Expr::Quantifier(expr, QuantifierType::ZeroOrMore) => format!("(?:{})*", to_regex_syntax(expr)),
+        Expr::Quantifier(expr, QuantifierType::OneOrMore) => format!("(?:{})+", to_regex_syntax(expr)),
+        Expr::Quantifier(expr, QuantifierType::ZeroOrOne) => format!("(?:{})?", to_regex_syntax(expr)),
+        Expr::Choice(exprs) => format!("(?:{})", exprs.iter().map(to_regex_syntax).collect::<Vec<_>>().join("|")),
+        Expr::Seq(exprs) => format!("(?:{})", exprs.iter().map(to_regex_syntax).collect::<String>()),
+        Expr::Epsilon => "(?:)".to_string(),
+        _ => panic!("{:?} can't be written for the regex crate", expr),
+    }
+}
+
+/// Compiles `expr` with the `regex` crate, anchored at both ends.
+pub fn to_full_match_regex(expr: &Expr) -> regex::bytes::Regex {
+    regex::bytes::Regex::new(&format!("(?s-u)^(?:{})$", to_regex_syntax(expr))).unwrap()
+}
+
+/// Returns random productions over the non-terminals `N0`.. and terminals `T0`.., starting with `start -> N0`.
+pub fn arbitrary_productions(u: &mut Unstructured) -> Result<Vec<Production>> {
+    let num_non_terminals = u.int_in_range(1..=3)?;
+    let num_terminals = u.int_in_range(1..=3)?;
+    let mut productions = vec![Production { lhs: NonTerminal("start".to_string()), rhs: vec![Symbol::NonTerminal(NonTerminal("N0".to_string()))] }];
+    for non_terminal in 0..num_non_terminals {
+        for _ in 0..u.int_in_range(1..=3)? {
+            let rhs = (0..u.int_in_range(0..=3)?).map(|_| {
+                Ok(if u.arbitrary()? {
+                    Symbol::NonTerminal(NonTerminal(format!("N{}", u.int_in_range(0..=num_non_terminals - 1)?)))
+                } else {
+                    Symbol::Terminal(Terminal(format!("T{}", u.int_in_range(0..=num_terminals - 1)?)))
+                })
+            }).collect::<Result<_>>()?;
+            productions.push(Production { lhs: NonTerminal(format!("N{}", non_terminal)), rhs });
+        }
+    }
+    Ok(productions)
+}
+
+/// Returns true if `productions` derive `input` from `start`, by computing every (non-terminal, start, end) that
+/// derives the input between start and end until nothing changes.
+pub fn brute_force_recognize(productions: &[Production], start: &NonTerminal, input: &[Terminal]) -> bool {
+    let mut derivations: BTreeSet<(NonTerminal, usize, usize)> = BTreeSet::new();
+    loop {
+        let num_derivations = derivations.len();
+        for production in productions {
+            for start in 0..=input.len() {
+                let mut ends = BTreeSet::from([start]);
+                for symbol in &production.rhs {
+                    ends = ends.into_iter().flat_map(|end| -> Vec<usize> {
+                        match symbol {
+                            Symbol::Terminal(terminal) => (input.get(end) == Some(terminal)).then_some(end + 1).into_iter().collect(),
+                            Symbol::NonTerminal(non_terminal) => derivations.iter()
+                                .filter(|(other, other_start, _)| other == non_terminal && *other_start == end)
+                                .map(|&(_, _, other_end)| other_end)
+                                .collect(),
+                        }
+                    }).collect();
+                }
+                for end in ends {
+                    derivations.insert((production.lhs.clone(), start, end));
+                }
+            }
+        }
+        if derivations.len() == num_derivations {
+            return derivations.contains(&(start.clone(), 0, input.len()));
+        }
+    }
+}
+
+/// Returns a random regex for a grammar terminal. It never matches the empty string.
+fn arbitrary_terminal_expr(u: &mut Unstructured) -> Result<Expr> {
+    let mut u8set = arbitrary_u8set(u)?;
+    u8set.insert(arbitrary_byte(u)?);
+    Ok(match u.int_in_range(0..=2)? {
+        0 => Expr::U8Seq((0..u.int_in_range(1..=2)?).map(|_| arbitrary_byte(u)).collect::<Result<_>>()?),
+        1 => Expr::U8Class(u8set),
+        _ => Expr::Quantifier(Box::new(Expr::U8Class(u8set)), QuantifierType::OneOrMore),
+    })
+}
+
+fn arbitrary_grammar_expr(u: &mut Unstructured, num_non_terminals: usize, depth: usize) -> Result<GrammarExpr> {
+    let max_kind = if depth >= MAX_DEPTH { 1 } else { 5 };
+    let children = |u: &mut Unstructured| {
+        (0..u.int_in_range(1..=3)?).map(|_| arbitrary_grammar_expr(u, num_non_terminals, depth + 1)).collect::<Result<Vec<_>>>()
+    };
+    Ok(match u.int_in_range(0..=max_kind)? {
+        0 => GrammarExpr::RegexExpr(arbitrary_terminal_expr(u)?),
+        1 => GrammarExpr::Ref(format!("N{}", u.int_in_range(0..=num_non_terminals - 1)?)),
+        2 => GrammarExpr::Sequence(children(u)?),
+        3 => GrammarExpr::Choice(children(u)?),
+        4 => GrammarExpr::Optional(Box::new(arbitrary_grammar_expr(u, num_non_terminals, depth + 1)?)),
+        _ => GrammarExpr::Repeat(Box::new(arbitrary_grammar_expr(u, num_non_terminals, depth + 1)?)),
+    })
+}
+
+/// Returns the rules of a random grammar for `Grammar::from_exprs`, over the non-terminals `N0`...
+pub fn arbitrary_grammar_exprs(u: &mut Unstructured) -> Result<Vec<(String, GrammarExpr)>> {
+    let num_non_terminals = u.int_in_range(1..=3)?;
+    (0..num_non_terminals).map(|non_terminal| Ok((format!("N{}", non_terminal), arbitrary_grammar_expr(u, num_non_terminals, 0)?))).collect()
+}
+
+/// Returns a random LLM vocabulary of distinct, non-empty tokens.
+pub fn arbitrary_llm_tokens(u: &mut Unstructured) -> Result<Vec<Vec<u8>>> {
+    let mut llm_tokens: BTreeSet<Vec<u8>> = ALPHABET.iter().map(|&byte| vec![byte]).collect();
+    for _ in 0..u.int_in_range(0..=4)? {
+        llm_tokens.insert((0..u.int_in_range(2..=3)?).map(|_| arbitrary_byte(u)).collect::<Result<_>>()?);
+    }
+    Ok(llm_tokens.into_iter().collect())
+}
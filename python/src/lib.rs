@@ -1,6 +1,7 @@
 // python/src/lib.rs
 use sep1::finite_automata::{Expr as RegexExpr, ExprGroups as RegexGroups, greedy_group, non_greedy_group, groups as regex_groups, _choice as regex_choice, eat_u8, eat_u8_negation, eat_u8_set, eps, opt, prec, rep, rep1, _seq as regex_seq};
 use sep1::finite_automata::Regex;
+use sep1::compiled_regex::CompiledRegex;
 use pyo3::prelude::*;
 use pyo3::types::{PyByteArray, PyBytes, PyDict};
 use sep1::glr::grammar::{NonTerminal, Production, Symbol, Terminal};
@@ -9,11 +10,32 @@ use sep1::glr::table::{generate_glr_parser, StateID};
 use sep1::interface::{Grammar, GrammarExpr, choice as grammar_choice, optional as grammar_optional, regex as grammar_regex, repeat as grammar_repeat, r#ref as grammar_ref, sequence as grammar_sequence};
 use sep1::constraint::{GrammarConstraint, GrammarConstraintState};
 use sep1::precompute::{print_precomputed, LLMTokenID, Tokenizer};
+use sep1::vocab::LLMVocab;
+use sep1::error::Error;
+use pyo3::create_exception;
+use pyo3::exceptions::PyValueError;
 use std::collections::{BTreeMap, BTreeSet};
 use bimap::BiBTreeMap;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 use numpy::{IntoPyArray, PyArray1, ToPyArray};
 use sep1::u8set::U8Set;
 
+create_exception!(_sep1, Sep1Error, PyValueError, "Base class for errors raised by sep1.");
+create_exception!(_sep1, GrammarError, Sep1Error, "The grammar is invalid, e.g. it refers to an undefined rule.");
+create_exception!(_sep1, TokenError, Sep1Error, "An LLM token ID is out of range or has the wrong kind.");
+create_exception!(_sep1, VocabError, Sep1Error, "The vocabulary file couldn't be read or isn't supported.");
+
+fn to_py_err(err: Error) -> PyErr {
+    let message = err.to_string();
+    match err {
+        Error::InvalidGrammar(_) | Error::EmptyMatchingTerminal(_) => GrammarError::new_err(message),
+        Error::LLMTokenIDOutOfRange { .. } | Error::SpecialTokenIsText(_) | Error::NotText(_) => TokenError::new_err(message),
+        Error::Vocab(_) => VocabError::new_err(message),
+        Error::MissingTokenizerState(_) | Error::InvalidSavedData(_) => Sep1Error::new_err(message),
+    }
+}
+
 #[pyclass]
 #[derive(Clone)]
 struct PyGrammarExpr {
@@ -183,9 +205,13 @@ pub struct PyGrammar {
 #[pymethods]
 impl PyGrammar {
     #[new]
-    fn new(exprs: Vec<(String, PyGrammarExpr)>) -> Self {
-        let inner = Grammar::from_exprs(exprs.into_iter().map(|(s, e)| (s, e.inner)).collect());
-        Self { inner }
+    #[pyo3(signature = (exprs, ignore=vec![]))]
+    fn new(exprs: Vec<(String, PyGrammarExpr)>, ignore: Vec<PyRegexExpr>) -> PyResult<Self> {
+        let inner = Grammar::try_from_exprs_with_ignore(
+            exprs.into_iter().map(|(s, e)| (s, e.inner)).collect(),
+            ignore.into_iter().map(|e| e.inner).collect(),
+        ).map_err(to_py_err)?;
+        Ok(Self { inner })
     }
 
     fn glr_parser(&self) -> PyGLRParser {
@@ -206,7 +232,7 @@ pub struct PyGLRParser {
 #[pyclass]
 #[derive(Clone)]
 pub struct PyGrammarConstraint {
-    inner: GrammarConstraint<Regex>,
+    inner: GrammarConstraint<CompiledRegex>,
 }
 
 #[pymethods]
@@ -221,7 +247,26 @@ impl PyGrammarConstraint {
             llm_token_map.insert(token.to_vec(), LLMTokenID(id));
         }
 
-        let inner = GrammarConstraint::from_grammar(grammar.inner, llm_token_map, eof_llm_token_id, max_llm_token_id);
+        let inner = GrammarConstraint::try_from_grammar(grammar.inner.compile(), llm_token_map, eof_llm_token_id, max_llm_token_id).map_err(to_py_err)?;
+        Ok(Self { inner })
+    }
+
+    /// Builds the constraint for the vocabulary in a HuggingFace `tokenizer.json` file.
+    #[staticmethod]
+    fn from_tokenizer_json(grammar: PyGrammar, path: &str) -> PyResult<Self> {
+        let vocab = LLMVocab::from_tokenizer_json_file(path).map_err(|err| to_py_err(err.into()))?;
+        let special_tokens = vocab.special_token_policy();
+        let inner = GrammarConstraint::try_from_grammar_with_special_tokens(grammar.inner.compile(), vocab.llm_tokens, special_tokens, vocab.max_llm_token_id).map_err(to_py_err)?;
+        Ok(Self { inner })
+    }
+
+    /// Builds the constraint for the vocabulary in a tiktoken rank file, with the given special tokens.
+    #[staticmethod]
+    fn from_tiktoken(grammar: PyGrammar, path: &str, special_tokens: BTreeMap<String, usize>) -> PyResult<Self> {
+        let special_tokens: Vec<(&str, usize)> = special_tokens.iter().map(|(content, &id)| (content.as_str(), id)).collect();
+        let vocab = LLMVocab::from_tiktoken_file(path, &special_tokens).map_err(|err| to_py_err(err.into()))?;
+        let special_tokens = vocab.special_token_policy();
+        let inner = GrammarConstraint::try_from_grammar_with_special_tokens(grammar.inner.compile(), vocab.llm_tokens, special_tokens, vocab.max_llm_token_id).map_err(to_py_err)?;
         Ok(Self { inner })
     }
 
@@ -233,7 +278,7 @@ impl PyGrammarConstraint {
 
 #[pyclass]
 pub struct PyGrammarConstraintState {
-    inner: GrammarConstraintState<Regex>,
+    inner: GrammarConstraintState<CompiledRegex>,
 }
 
 #[pymethods]
@@ -243,15 +288,75 @@ impl PyGrammarConstraintState {
         Self { inner: grammar_constraint.inner.init() }
     }
 
+    /// Generation is free until `trigger` has been generated, or until `activate` if it's `None`.
+    #[staticmethod]
+    #[pyo3(signature = (grammar_constraint, trigger=None))]
+    fn with_trigger(grammar_constraint: PyGrammarConstraint, trigger: Option<&[u8]>) -> Self {
+        Self { inner: grammar_constraint.inner.init_with_trigger(trigger) }
+    }
+
+    fn is_active(&self) -> bool {
+        self.inner.is_active()
+    }
+
+    fn activate(&mut self) {
+        self.inner.activate();
+    }
+
+    fn deactivate(&mut self) {
+        self.inner.deactivate();
+    }
+
+    fn fully_matches(&self) -> bool {
+        self.inner.fully_matches()
+    }
+
     fn get_mask<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyArray1<bool>>> { // Correct return type
-        let bitset = self.inner.get_mask();
+        let bitset = self.inner.try_get_mask().map_err(to_py_err)?;
         let bools: Vec<bool> = bitset.iter().map(|bit_ref| *bit_ref).collect();
         let array = bools.into_pyarray_bound(py); // Correct usage
         Ok(array)
     }
 
-    fn commit(&mut self, llm_token_id: usize) {
-        self.inner.commit(LLMTokenID(llm_token_id));
+    fn commit(&mut self, llm_token_id: usize) -> PyResult<()> {
+        self.inner.try_commit(LLMTokenID(llm_token_id)).map_err(to_py_err)
+    }
+
+    fn feed_bytes(&mut self, bytes: &[u8]) -> PyResult<()> {
+        self.inner.try_feed_bytes(bytes).map_err(to_py_err)
+    }
+
+    fn forced_bytes<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
+        let forced_bytes = self.inner.try_forced_bytes().map_err(to_py_err)?;
+        Ok(PyBytes::new_bound(py, &forced_bytes))
+    }
+
+    fn forced_tokens(&mut self) -> PyResult<Vec<usize>> {
+        let forced_tokens = self.inner.try_forced_tokens().map_err(to_py_err)?;
+        Ok(forced_tokens.into_iter().map(|llm_token_id| llm_token_id.0).collect())
+    }
+
+    /// Removes the last `k` tokens of `prompt` for token healing, and returns the rest.
+    fn heal_prompt(&mut self, prompt: Vec<usize>, k: usize) -> PyResult<Vec<usize>> {
+        let prompt: Vec<LLMTokenID> = prompt.into_iter().map(LLMTokenID).collect();
+        let kept = self.inner.try_heal_prompt(&prompt, k).map_err(to_py_err)?;
+        Ok(kept.iter().map(|llm_token_id| llm_token_id.0).collect())
+    }
+
+    fn shortest_completions(&mut self, n: usize, max_tokens: usize) -> PyResult<Vec<Vec<usize>>> {
+        let completions = self.inner.try_shortest_completions(n, max_tokens).map_err(to_py_err)?;
+        Ok(completions.into_iter().map(|completion| completion.into_iter().map(|llm_token_id| llm_token_id.0).collect()).collect())
+    }
+
+    fn random_walk_completion(&mut self, seed: u64, max_tokens: usize) -> PyResult<Option<Vec<usize>>> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let completion = self.inner.try_random_walk_completion(&mut rng, max_tokens).map_err(to_py_err)?;
+        Ok(completion.map(|completion| completion.into_iter().map(|llm_token_id| llm_token_id.0).collect()))
+    }
+
+    fn enumerate_completions<'py>(&mut self, py: Python<'py>, max_len: usize) -> PyResult<Vec<Bound<'py, PyBytes>>> {
+        let completions = self.inner.try_enumerate_completions(max_len).map_err(to_py_err)?;
+        Ok(completions.iter().map(|text| PyBytes::new_bound(py, text)).collect())
     }
 }
 
@@ -267,5 +372,9 @@ fn _sep1(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<PyGrammar>()?;
     m.add_class::<PyGrammarConstraint>()?;
     m.add_class::<PyGrammarConstraintState>()?;
+    m.add("Sep1Error", m.py().get_type_bound::<Sep1Error>())?;
+    m.add("GrammarError", m.py().get_type_bound::<GrammarError>())?;
+    m.add("TokenError", m.py().get_type_bound::<TokenError>())?;
+    m.add("VocabError", m.py().get_type_bound::<VocabError>())?;
     Ok(())
 }
\ No newline at end of file
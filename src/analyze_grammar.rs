@@ -1,4 +1,5 @@
 use std::collections::{BTreeMap, BTreeSet};
+use crate::finite_automata::Expr;
 use crate::glr::grammar::{prod, NonTerminal, Production, Symbol};
 
 pub fn validate(productions: &[Production]) -> Result<(), String> {
@@ -24,6 +25,21 @@ pub fn validate(productions: &[Production]) -> Result<(), String> {
     Ok(())
 }
 
+/// Returns a warning for each pair of terminals that can match the same string. The tokenizer can't tell such
+/// terminals apart on its own, so which one applies is left to the parser (or the tokenizer's tie-breaking).
+pub fn validate_terminals(terminals: &BTreeMap<String, Expr>) -> Vec<String> {
+    let regexes: Vec<_> = terminals.iter().map(|(name, expr)| (name, expr.clone().build())).collect();
+    let mut warnings = Vec::new();
+    for (i, (name, regex)) in regexes.iter().enumerate() {
+        for (other_name, other_regex) in &regexes[i + 1..] {
+            if let Some(witness) = regex.intersection_witness(other_regex) {
+                warnings.push(format!("Terminals {} and {} overlap: both match {:?}", name, other_name, String::from_utf8_lossy(&witness)));
+            }
+        }
+    }
+    warnings
+}
+
 pub fn drop_dead(productions: &[Production]) -> Vec<Production> {
     // todo: this function is broken
     // Ensure all nonterminals have a productions
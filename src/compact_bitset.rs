@@ -0,0 +1,227 @@
+// src/compact_bitset.rs
+use crate::error::Error;
+use crate::frozen_trie::{read_u64, write_u64};
+use bitvec::prelude::BitVec;
+
+/// A bitset that is stored as a sorted list of set bits when few are set, and as a `BitVec` otherwise.
+///
+/// The precompute values hold one bitset over the LLM vocabulary per (node, next grammar token), and most of
+/// them have only a handful of bits set. They're built with `insert`, which switches to the dense form only once
+/// it's smaller, so no node ever holds a full-vocabulary `BitVec` for a few bits.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum CompactBitSet {
+    Sparse(Vec<u32>),
+    Dense(BitVec),
+}
+
+impl CompactBitSet {
+    pub fn new() -> Self {
+        CompactBitSet::Sparse(Vec::new())
+    }
+
+    /// Picks whichever representation of `bitset` is smaller.
+    pub fn from_bitvec(bitset: &BitVec) -> Self {
+        let num_ones = bitset.count_ones();
+        if num_ones * std::mem::size_of::<u32>() < std::mem::size_of_val(bitset.as_raw_slice()) {
+            CompactBitSet::Sparse(bitset.iter_ones().map(|i| i as u32).collect())
+        } else {
+            CompactBitSet::Dense(bitset.clone())
+        }
+    }
+
+    /// Sets bit `index`. `len` is the number of bits in the set, which decides when the dense form is smaller.
+    ///
+    /// Panics if `index` isn't less than `len`. The precompute only inserts LLM token IDs that it has checked against
+    /// `max_llm_token_id`, and `load` validates saved sets without inserting.
+    pub fn insert(&mut self, index: usize, len: usize) {
+        assert!(index < len);
+        match self {
+            CompactBitSet::Sparse(indices) => {
+                let Err(position) = indices.binary_search(&(index as u32)) else { return };
+                if (indices.len() + 1) * std::mem::size_of::<u32>() < dense_size_in_bytes(len) {
+                    indices.insert(position, index as u32);
+                } else {
+                    let mut bitset = BitVec::new();
+                    bitset.resize(len, false);
+                    for &i in indices.iter() {
+                        bitset.set(i as usize, true);
+                    }
+                    bitset.set(index, true);
+                    *self = CompactBitSet::Dense(bitset);
+                }
+            }
+            CompactBitSet::Dense(bitset) => bitset.set(index, true),
+        }
+    }
+
+    pub fn contains(&self, index: usize) -> bool {
+        match self {
+            CompactBitSet::Sparse(indices) => indices.binary_search(&(index as u32)).is_ok(),
+            CompactBitSet::Dense(bitset) => bitset.get(index).is_some_and(|bit| *bit),
+        }
+    }
+
+    pub fn count_ones(&self) -> usize {
+        match self {
+            CompactBitSet::Sparse(indices) => indices.len(),
+            CompactBitSet::Dense(bitset) => bitset.count_ones(),
+        }
+    }
+
+    pub fn iter_ones(&self) -> Box<dyn Iterator<Item = usize> + '_> {
+        match self {
+            CompactBitSet::Sparse(indices) => Box::new(indices.iter().map(|&i| i as usize)),
+            CompactBitSet::Dense(bitset) => Box::new(bitset.iter_ones()),
+        }
+    }
+
+    /// Sets the bits of `self` in `dense`, which must be long enough to hold them.
+    pub fn or_into(&self, dense: &mut BitVec) {
+        match self {
+            CompactBitSet::Sparse(indices) => {
+                for &i in indices {
+                    dense.set(i as usize, true);
+                }
+            }
+            CompactBitSet::Dense(bitset) => *dense |= bitset,
+        }
+    }
+
+    /// Heap memory used by the set bits.
+    pub fn size_in_bytes(&self) -> usize {
+        match self {
+            CompactBitSet::Sparse(indices) => std::mem::size_of_val(indices.as_slice()),
+            CompactBitSet::Dense(bitset) => std::mem::size_of_val(bitset.as_raw_slice()),
+        }
+    }
+
+    /// Appends `self` to `out`, keeping its representation. Dense sets are stored as one bit per index.
+    pub fn save(&self, out: &mut Vec<u8>) {
+        match self {
+            CompactBitSet::Sparse(indices) => {
+                write_u64(out, 0);
+                write_u64(out, indices.len() as u64);
+                for &i in indices {
+                    out.extend_from_slice(&i.to_le_bytes());
+                }
+            }
+            CompactBitSet::Dense(bitset) => {
+                write_u64(out, 1);
+                write_u64(out, bitset.len() as u64);
+                out.extend(bitset.chunks(8).map(|chunk| chunk.iter_ones().fold(0u8, |byte, i| byte | 1 << i)));
+            }
+        }
+    }
+
+    /// Reads a set of `len` bits written by `save` from the start of `input`, advancing it. Returns
+    /// `Error::InvalidSavedData` if the data is truncated, or doesn't describe a valid set of that many bits.
+    pub fn load(input: &mut &[u8], len: usize) -> Result<Self, Error> {
+        let invalid = |message: &str| Error::InvalidSavedData(message.to_string());
+        let kind = read_u64(input)?;
+        let saved_len = read_u64(input)? as usize;
+        match kind {
+            0 => {
+                let bytes = take(input, saved_len.checked_mul(4).ok_or_else(|| invalid("bitset too large"))?)?;
+                let indices: Vec<u32> = bytes.chunks(4).map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap())).collect();
+                // `contains` binary searches the indices, and `or_into` sets them in a mask of `len` bits.
+                if indices.windows(2).any(|pair| pair[0] >= pair[1]) {
+                    return Err(invalid("sparse bitset indices aren't sorted and unique"));
+                }
+                if indices.last().is_some_and(|&i| i as usize >= len) {
+                    return Err(invalid("a sparse bitset index is out of range"));
+                }
+                Ok(CompactBitSet::Sparse(indices))
+            }
+            1 => {
+                if saved_len != len {
+                    return Err(invalid("a dense bitset has the wrong length"));
+                }
+                let bytes = take(input, len.div_ceil(8))?;
+                let mut bitset: BitVec = bytes.iter().flat_map(|&byte| (0..8).map(move |i| byte & 1 << i != 0)).collect();
+                bitset.truncate(len);
+                Ok(CompactBitSet::Dense(bitset))
+            }
+            _ => Err(Error::InvalidSavedData(format!("unknown bitset kind {}", kind))),
+        }
+    }
+}
+
+impl Default for CompactBitSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The heap memory of a dense set of `len` bits.
+fn dense_size_in_bytes(len: usize) -> usize {
+    len.div_ceil(usize::BITS as usize) * std::mem::size_of::<usize>()
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8], Error> {
+    let (bytes, rest) = input.split_at_checked(len).ok_or_else(|| Error::InvalidSavedData("unexpected end of data".to_string()))?;
+    *input = rest;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compact_bitset() {
+        let mut bitset = BitVec::new();
+        bitset.resize(1000, false);
+        bitset.set(3, true);
+        bitset.set(700, true);
+        let sparse = CompactBitSet::from_bitvec(&bitset);
+        assert!(matches!(sparse, CompactBitSet::Sparse(_)));
+        assert!(sparse.contains(700) && !sparse.contains(701));
+        assert_eq!(sparse.iter_ones().collect::<Vec<_>>(), vec![3, 700]);
+        assert_eq!(sparse.size_in_bytes(), 8);
+
+        let mut mask = BitVec::new();
+        mask.resize(1000, false);
+        mask.set(5, true);
+        sparse.or_into(&mut mask);
+        assert_eq!(mask.iter_ones().collect::<Vec<_>>(), vec![3, 5, 700]);
+
+        let dense = CompactBitSet::from_bitvec(&!bitset.clone());
+        assert!(matches!(dense, CompactBitSet::Dense(_)));
+        assert_eq!(dense.count_ones(), 998);
+        dense.or_into(&mut mask);
+        assert_eq!(mask.count_ones(), 1000);
+
+        let mut saved = Vec::new();
+        sparse.save(&mut saved);
+        dense.save(&mut saved);
+        let mut input = &saved[..];
+        assert_eq!(CompactBitSet::load(&mut input, 1000).unwrap(), sparse);
+        assert_eq!(CompactBitSet::load(&mut input, 1000).unwrap(), dense);
+        assert!(input.is_empty());
+
+        // Corrupt sets are rejected rather than giving wrong answers or panicking later.
+        assert!(matches!(CompactBitSet::load(&mut &saved[..], 700), Err(Error::InvalidSavedData(_))));
+        for indices in [vec![700, 3], vec![3, 3]] {
+            let mut saved = Vec::new();
+            CompactBitSet::Sparse(indices).save(&mut saved);
+            assert!(matches!(CompactBitSet::load(&mut &saved[..], 1000), Err(Error::InvalidSavedData(_))));
+        }
+        let mut saved = Vec::new();
+        dense.save(&mut saved);
+        assert!(matches!(CompactBitSet::load(&mut &saved[..], 999), Err(Error::InvalidSavedData(_))));
+
+        // Inserting stays sparse, and sorted, until the dense form is smaller.
+        let mut inserted = CompactBitSet::new();
+        for i in [700, 3, 700] {
+            inserted.insert(i, 1000);
+        }
+        assert_eq!(inserted, sparse);
+        let mut expected = bitset.clone();
+        for i in 0..1000 {
+            inserted.insert(i, 1000);
+            expected.set(i, true);
+            assert_eq!(inserted, CompactBitSet::from_bitvec(&expected));
+        }
+        assert!(matches!(inserted, CompactBitSet::Dense(_)));
+    }
+}
@@ -0,0 +1,341 @@
+// src/compiled_regex.rs
+use crate::finite_automata::{GroupID, Match, Regex, DFA};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Marks a missing transition in `CompiledRegex::transitions`.
+const DEAD: u32 = u32::MAX;
+
+/// A compact, read-only form of a `Regex`'s DFA for fast execution.
+///
+/// - Bytes that every state treats the same way share a byte class, so each state only needs one
+///   transition per class.
+/// - Transitions are stored in one dense `u32` array, indexed by `state * num_classes + class`.
+/// - Per-state group sets (finalizers, possible groups, ...) are stored as bitsets of `u64` words.
+///
+/// State IDs are the same as in the DFA it was compiled from, so it can be used in place of the `Regex`
+/// (including as a `Tokenizer`) with identical semantics. `Regex` itself always runs on the DFA; only code that is
+/// given a `CompiledRegex` (see `Regex::compile` and `Grammar::compile`) uses this form. In
+/// `benches/tokenizer_throughput.rs` it lexes roughly twice as fast as the `Regex`, but precomputing a vocabulary
+/// is only slightly faster, since most of that time is spent outside the tokenizer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompiledRegex {
+    byte_to_class: [u8; 256],
+    num_classes: usize,
+    transitions: Vec<u32>,
+    words_per_state: usize,
+    finalizers: Vec<u64>,
+    eof_finalizers: Vec<u64>,
+    possible_group_ids: Vec<u64>,
+    non_greedy_finalizers: Vec<u64>,
+    lookahead_group_ids: Vec<u64>,
+    has_transitions: Vec<bool>,
+    num_states: usize,
+    num_groups: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompiledRegexState<'a> {
+    pub regex: &'a CompiledRegex,
+    pub position: usize,
+    pub current_state: usize,
+    /// Match position for each group, if it has matched.
+    match_positions: Vec<Option<usize>>,
+    /// Bitset of the groups in `match_positions` that have matched.
+    matched: Vec<u64>,
+    pub done: bool,
+}
+
+fn bitset_words(num_groups: usize) -> usize {
+    num_groups.div_ceil(64).max(1)
+}
+
+fn set_bits(words: &mut [u64], group_ids: &BTreeSet<GroupID>) {
+    for &group_id in group_ids {
+        words[group_id / 64] |= 1 << (group_id % 64);
+    }
+}
+
+fn contains_bit(words: &[u64], group_id: GroupID) -> bool {
+    words[group_id / 64] & (1 << (group_id % 64)) != 0
+}
+
+fn iter_bits(words: &[u64]) -> impl Iterator<Item = GroupID> + '_ {
+    words.iter().enumerate().flat_map(|(i, &word)| {
+        let mut word = word;
+        std::iter::from_fn(move || {
+            (word != 0).then(|| {
+                let bit = word.trailing_zeros() as usize;
+                word &= word - 1;
+                i * 64 + bit
+            })
+        })
+    })
+}
+
+impl CompiledRegex {
+    pub fn new(dfa: &DFA) -> Self {
+        // Group bytes by the column of transitions they select. Bytes with the same column form a class.
+        let mut class_for_column: BTreeMap<Vec<u32>, u8> = BTreeMap::new();
+        let mut byte_to_class = [0; 256];
+        let mut class_representatives = Vec::new();
+        for byte in 0..=255u8 {
+            let column: Vec<u32> = dfa.states.iter()
+                .map(|state| state.transitions.get(byte).map_or(DEAD, |&next_state| next_state as u32))
+                .collect();
+            let next_class = class_for_column.len();
+            let class = *class_for_column.entry(column).or_insert_with(|| {
+                class_representatives.push(byte);
+                next_class as u8
+            });
+            byte_to_class[byte as usize] = class;
+        }
+        let num_classes = class_representatives.len();
+
+        let num_groups = dfa.states.iter()
+            .flat_map(|state| state.possible_group_ids.iter().chain(&state.eof_finalizers))
+            .max()
+            .map_or(0, |&max_group_id| max_group_id + 1);
+        let words_per_state = bitset_words(num_groups);
+
+        let num_states = dfa.states.len();
+        let mut transitions = Vec::with_capacity(num_states * num_classes);
+        let mut finalizers = vec![0; num_states * words_per_state];
+        let mut eof_finalizers = vec![0; num_states * words_per_state];
+        let mut possible_group_ids = vec![0; num_states * words_per_state];
+        for (state_id, state) in dfa.states.iter().enumerate() {
+            for &byte in &class_representatives {
+                transitions.push(state.transitions.get(byte).map_or(DEAD, |&next_state| next_state as u32));
+            }
+            let words = state_id * words_per_state..(state_id + 1) * words_per_state;
+            set_bits(&mut finalizers[words.clone()], &state.finalizers);
+            set_bits(&mut eof_finalizers[words.clone()], &state.eof_finalizers);
+            set_bits(&mut possible_group_ids[words], &state.possible_group_ids);
+        }
+        let mut non_greedy_finalizers = vec![0; words_per_state];
+        set_bits(&mut non_greedy_finalizers, &dfa.non_greedy_finalizers);
+        let mut lookahead_group_ids = vec![0; words_per_state];
+        set_bits(&mut lookahead_group_ids, &dfa.lookahead_group_ids);
+
+        Self {
+            byte_to_class,
+            num_classes,
+            transitions,
+            words_per_state,
+            finalizers,
+            eof_finalizers,
+            possible_group_ids,
+            non_greedy_finalizers,
+            lookahead_group_ids,
+            has_transitions: dfa.states.iter().map(|state| !state.transitions.is_empty()).collect(),
+            num_states,
+            num_groups,
+        }
+    }
+
+    pub fn num_states(&self) -> usize {
+        self.num_states
+    }
+
+    pub fn num_classes(&self) -> usize {
+        self.num_classes
+    }
+
+    fn state_words<'a>(&self, words: &'a [u64], state: usize) -> &'a [u64] {
+        &words[state * self.words_per_state..(state + 1) * self.words_per_state]
+    }
+
+    #[inline]
+    fn next_state(&self, state: usize, byte: u8) -> Option<usize> {
+        let next_state = self.transitions[state * self.num_classes + self.byte_to_class[byte as usize] as usize];
+        (next_state != DEAD).then_some(next_state as usize)
+    }
+
+    pub fn is_lookahead_group(&self, group_id: GroupID) -> bool {
+        contains_bit(&self.lookahead_group_ids, group_id)
+    }
+
+    pub fn possible_group_ids(&self, state: usize) -> impl Iterator<Item = GroupID> + '_ {
+        iter_bits(self.state_words(&self.possible_group_ids, state))
+    }
+
+    pub fn eof_finalizers(&self, state: usize) -> impl Iterator<Item = GroupID> + '_ {
+        iter_bits(self.state_words(&self.eof_finalizers, state))
+    }
+
+    pub fn init_to_state(&self, state: usize) -> CompiledRegexState<'_> {
+        let mut regex_state = CompiledRegexState {
+            regex: self,
+            position: 0,
+            current_state: state,
+            match_positions: vec![None; self.num_groups],
+            matched: vec![0; self.words_per_state],
+            done: !self.has_transitions[state],
+        };
+        // Finalizers of lookahead groups in the initial state belong to a match that ended before this input.
+        for group_id in iter_bits(self.state_words(&self.finalizers, state)) {
+            if !self.is_lookahead_group(group_id) {
+                regex_state.record_match(group_id, 0);
+            }
+        }
+        regex_state
+    }
+
+    pub fn init(&self) -> CompiledRegexState<'_> {
+        self.init_to_state(0)
+    }
+
+    pub fn find(&self, text: &[u8]) -> Option<(GroupID, usize)> {
+        let mut regex_state = self.init();
+        regex_state.execute(text);
+        regex_state.matches().into_iter().next()
+    }
+}
+
+impl CompiledRegexState<'_> {
+    fn record_match(&mut self, group_id: GroupID, position: usize) {
+        if contains_bit(&self.regex.non_greedy_finalizers, group_id) && self.match_positions[group_id].is_some() {
+            return;
+        }
+        self.match_positions[group_id] = Some(position);
+        self.matched[group_id / 64] |= 1 << (group_id % 64);
+    }
+
+    /// Same as `RegexState::execute`.
+    pub fn execute(&mut self, text: &[u8]) {
+        if self.done {
+            self.position += text.len();
+            return;
+        }
+        let regex = self.regex;
+        let words_per_state = regex.words_per_state;
+        let mut local_position = 0;
+        while local_position < text.len() {
+            let Some(next_state) = regex.next_state(self.current_state, text[local_position]) else {
+                // No matching transition, we're done
+                self.position += text.len();
+                self.done = true;
+                return;
+            };
+            self.current_state = next_state;
+            local_position += 1;
+
+            let words = next_state * words_per_state..(next_state + 1) * words_per_state;
+            let finalizers = &regex.finalizers[words.clone()];
+            if finalizers.iter().any(|&word| word != 0) {
+                for group_id in iter_bits(finalizers) {
+                    // Groups with a lookahead are finalized after the lookahead byte, which isn't part of the match.
+                    let position = if regex.is_lookahead_group(group_id) {
+                        self.position + local_position - 1
+                    } else {
+                        self.position + local_position
+                    };
+                    self.record_match(group_id, position);
+                }
+            }
+
+            // Only continue if it's possible to match a greedy group, or a non-greedy group that hasn't matched yet.
+            let should_terminate = regex.possible_group_ids[words].iter()
+                .zip(&self.matched)
+                .zip(&regex.non_greedy_finalizers)
+                .all(|((&possible, &matched), &non_greedy)| possible & !(matched & non_greedy) == 0);
+            if should_terminate {
+                self.position += text.len();
+                self.done = true;
+                return;
+            }
+        }
+        self.position += text.len();
+        if !regex.has_transitions[self.current_state] {
+            self.done = true;
+        }
+    }
+
+    /// Same as `RegexState::end_of_input`.
+    pub fn end_of_input(&mut self) {
+        if !self.done {
+            for group_id in self.regex.eof_finalizers(self.current_state).collect::<Vec<_>>() {
+                self.record_match(group_id, self.position);
+            }
+        }
+        self.done = true;
+    }
+
+    pub fn matches(&self) -> BTreeMap<GroupID, usize> {
+        self.match_positions.iter().enumerate()
+            .filter_map(|(group_id, position)| position.map(|position| (group_id, position)))
+            .collect()
+    }
+
+    /// Same as `RegexState::get_greedy_match`.
+    pub fn get_greedy_match(&self) -> Option<Match> {
+        let mut greedy_match: Option<Match> = None;
+        for (group_id, position) in self.matches() {
+            if greedy_match.as_ref().is_none_or(|m| position > m.position) {
+                greedy_match = Some(Match { group_id, position });
+            }
+        }
+        greedy_match
+    }
+}
+
+impl Regex {
+    /// Compiles the DFA into its compact form. See `CompiledRegex`.
+    pub fn compile(&self) -> CompiledRegex {
+        CompiledRegex::new(&self.dfa)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::finite_automata::{eat_u8, eat_u8_set, lookahead, negative_lookahead, non_greedy_group, rep, rep1, Expr};
+    use crate::precompute::Tokenizer;
+    use crate::u8set::U8Set;
+    use crate::{choice, groups, seq};
+
+    #[test]
+    fn test_compiled_regex_matches_regex() {
+        let name = rep1(eat_u8_set(U8Set::from_range(b'a', b'z')));
+        let regex = groups![
+            Expr::U8Seq(b"if".to_vec()),
+            seq![name.clone(), negative_lookahead(eat_u8(b'('))],
+            seq![name, lookahead(eat_u8(b'('))],
+            rep1(eat_u8_set(U8Set::from_range(b'0', b'9'))),
+            choice![eat_u8(b' '), eat_u8(b'\n')],
+            non_greedy_group(seq![eat_u8(b'"'), rep(eat_u8_set(U8Set::all())), eat_u8(b'"')]),
+        ].build();
+        let compiled = regex.compile();
+        assert!(compiled.num_classes() < 256);
+        assert_eq!(compiled.num_states(), regex.dfa.states.len());
+
+        let texts: [&[u8]; 9] = [b"if", b"ifx(", b"foo)", b"foo", b"123a", b" \n", b"\"a\"b\"", b"", b"!"];
+        for text in texts {
+            for state in 0..regex.dfa.states.len() {
+                let mut regex_state = regex.init_to_state(state);
+                let mut compiled_state = compiled.init_to_state(state);
+                // Split the text to check that execution resumes correctly.
+                let (left, right) = text.split_at(text.len() / 2);
+                regex_state.execute(left);
+                regex_state.execute(right);
+                compiled_state.execute(left);
+                compiled_state.execute(right);
+                assert_eq!(compiled_state.matches(), regex_state.matches, "text {:?}, state {}", text, state);
+                assert_eq!(compiled_state.current_state, regex_state.current_state);
+                assert_eq!(compiled_state.done, regex_state.done);
+                assert_eq!(compiled_state.get_greedy_match(), regex_state.get_greedy_match());
+
+                regex_state.end_of_input();
+                compiled_state.end_of_input();
+                assert_eq!(compiled_state.matches(), regex_state.matches);
+            }
+            assert_eq!(compiled.find(text), regex.find(text));
+            for state in 0..regex.dfa.states.len() {
+                let result = regex.execute_from_state(text, state);
+                let compiled_result = compiled.execute_from_state(text, state);
+                assert_eq!(compiled_result.matches, result.matches);
+                assert_eq!(compiled_result.new_state, result.new_state);
+                assert_eq!(compiled.tokens_accessible_from_state(state), regex.tokens_accessible_from_state(state));
+                assert_eq!(compiled.tokens_matching_at_end_of_input(state), regex.tokens_matching_at_end_of_input(state));
+            }
+        }
+    }
+}
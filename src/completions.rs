@@ -0,0 +1,149 @@
+// src/completions.rs
+use crate::constraint::GrammarConstraintState;
+use crate::error::Error;
+use crate::precompute::{LLMTokenID, Tokenizer};
+use rand::Rng;
+use std::collections::{BTreeSet, VecDeque};
+
+// These walk the constraint with `try_get_mask` and `try_commit`, so they only use text LLM tokens (special tokens
+// have no text), and their cost grows with the vocabulary size. They're meant for tests and synthetic data with small
+// vocabularies, e.g. one token per byte.
+
+impl<T: Tokenizer> GrammarConstraintState<T> {
+    /// Concatenates the text of `llm_token_ids`, skipping special tokens.
+    pub fn decode(&self, llm_token_ids: &[LLMTokenID]) -> Vec<u8> {
+        llm_token_ids.iter()
+            .filter_map(|&llm_token_id| self.parent.llm_tokens.get_by_right(&self.parent.special_tokens.resolve_alias(llm_token_id)))
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    /// Returns the text LLM tokens that are allowed next.
+    fn allowed_text_llm_tokens(&self) -> Result<Vec<LLMTokenID>, Error> {
+        Ok(self.try_get_mask()?.iter_ones().map(LLMTokenID).filter(|llm_token_id| self.parent.llm_tokens.contains_right(llm_token_id)).collect())
+    }
+
+    /// Returns up to `n` token sequences that complete the grammar, fewest tokens first, with at most `max_tokens`
+    /// tokens each. Leaves the state unchanged.
+    pub fn shortest_completions(&mut self, n: usize, max_tokens: usize) -> Vec<Vec<LLMTokenID>> {
+        self.try_shortest_completions(n, max_tokens).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `shortest_completions`, but returns an error instead of panicking.
+    pub fn try_shortest_completions(&mut self, n: usize, max_tokens: usize) -> Result<Vec<Vec<LLMTokenID>>, Error> {
+        self.with_scratch_state(|state| state.take_shortest_completions(n, max_tokens))
+    }
+
+    fn take_shortest_completions(&mut self, n: usize, max_tokens: usize) -> Result<Vec<Vec<LLMTokenID>>, Error> {
+        let mut completions = Vec::new();
+        let mut queue = VecDeque::from([(Vec::new(), self.snapshot())]);
+        while let Some((llm_token_ids, snapshot)) = queue.pop_front() {
+            if completions.len() == n {
+                break;
+            }
+            self.restore(snapshot.clone());
+            if self.fully_matches() {
+                completions.push(llm_token_ids.clone());
+            }
+            if llm_token_ids.len() == max_tokens {
+                continue;
+            }
+            for llm_token_id in self.allowed_text_llm_tokens()? {
+                self.restore(snapshot.clone());
+                self.try_commit(llm_token_id)?;
+                let mut next_llm_token_ids = llm_token_ids.clone();
+                next_llm_token_ids.push(llm_token_id);
+                queue.push_back((next_llm_token_ids, self.snapshot()));
+            }
+        }
+        Ok(completions)
+    }
+
+    /// Returns a random completion of at most `max_tokens` tokens, found by a random walk: each step picks uniformly
+    /// among the allowed tokens and stopping (where the grammar is complete). This is not uniform over completions:
+    /// those reached through fewer choices are more likely, e.g. a short completion next to a long family of them.
+    /// The walk backtracks out of dead ends, so it only returns `None` if there's no completion, though it may then
+    /// explore every token sequence of at most `max_tokens` tokens. Leaves the state unchanged.
+    pub fn random_walk_completion(&mut self, rng: &mut impl Rng, max_tokens: usize) -> Option<Vec<LLMTokenID>> {
+        self.try_random_walk_completion(rng, max_tokens).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `random_walk_completion`, but returns an error instead of panicking.
+    pub fn try_random_walk_completion(&mut self, rng: &mut impl Rng, max_tokens: usize) -> Result<Option<Vec<LLMTokenID>>, Error> {
+        self.with_scratch_state(|state| state.take_random_walk_completion(rng, max_tokens))
+    }
+
+    fn take_random_walk_completion(&mut self, rng: &mut impl Rng, max_tokens: usize) -> Result<Option<Vec<LLMTokenID>>, Error> {
+        let mut llm_token_ids = Vec::new();
+        // The state after each token of `llm_token_ids`, and the choices there that haven't been tried yet, where
+        // `None` is stopping.
+        let mut steps = vec![(self.snapshot(), self.walk_choices(llm_token_ids.len(), max_tokens)?)];
+        let completion = loop {
+            let Some((snapshot, choices)) = steps.last_mut() else {
+                break None;
+            };
+            if choices.is_empty() {
+                steps.pop();
+                llm_token_ids.pop();
+                continue;
+            }
+            let Some(llm_token_id) = choices.swap_remove(rng.gen_range(0..choices.len())) else {
+                break Some(llm_token_ids);
+            };
+            self.restore(snapshot.clone());
+            self.try_commit(llm_token_id)?;
+            llm_token_ids.push(llm_token_id);
+            steps.push((self.snapshot(), self.walk_choices(llm_token_ids.len(), max_tokens)?));
+        };
+        Ok(completion)
+    }
+
+    /// The choices of `random_walk_completion` after `num_tokens` tokens.
+    fn walk_choices(&self, num_tokens: usize, max_tokens: usize) -> Result<Vec<Option<LLMTokenID>>, Error> {
+        let mut choices: Vec<_> = if num_tokens < max_tokens { self.allowed_text_llm_tokens()?.into_iter().map(Some).collect() } else { vec![] };
+        if self.fully_matches() {
+            choices.push(None);
+        }
+        Ok(choices)
+    }
+
+    /// Returns every text of at most `max_len` bytes that completes the grammar, however it's tokenized. Each text
+    /// is explored once per distinct state it leads to, not once per tokenization. Leaves the state unchanged.
+    pub fn enumerate_completions(&mut self, max_len: usize) -> BTreeSet<Vec<u8>> {
+        self.try_enumerate_completions(max_len).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `enumerate_completions`, but returns an error instead of panicking.
+    pub fn try_enumerate_completions(&mut self, max_len: usize) -> Result<BTreeSet<Vec<u8>>, Error> {
+        self.with_scratch_state(|state| state.take_enumerate_completions(max_len))
+    }
+
+    fn take_enumerate_completions(&mut self, max_len: usize) -> Result<BTreeSet<Vec<u8>>, Error> {
+        let initial = self.snapshot();
+        let mut completions = BTreeSet::new();
+        let mut seen = BTreeSet::from([(Vec::new(), initial.clone())]);
+        let mut stack = vec![(Vec::new(), initial)];
+        while let Some((text, snapshot)) = stack.pop() {
+            self.restore(snapshot.clone());
+            if self.fully_matches() {
+                completions.insert(text.clone());
+            }
+            for llm_token_id in self.allowed_text_llm_tokens()? {
+                let llm_token = &self.parent.llm_tokens.get_by_right(&llm_token_id).unwrap();
+                if text.len() + llm_token.len() > max_len {
+                    continue;
+                }
+                let mut next_text = text.clone();
+                next_text.extend_from_slice(llm_token);
+                self.restore(snapshot.clone());
+                self.try_commit(llm_token_id)?;
+                let next = (next_text, self.snapshot());
+                if seen.insert(next.clone()) {
+                    stack.push(next);
+                }
+            }
+        }
+        Ok(completions)
+    }
+}
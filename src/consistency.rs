@@ -0,0 +1,86 @@
+// src/consistency.rs
+use crate::constraint::{ConstraintMode, GrammarConstraintState};
+use crate::precompute::{LLMTokenID, Tokenizer};
+use crate::reference::ReferenceConstraint;
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+/// A disagreement between `get_mask`, `commit` and `ReferenceConstraint`, found by `check_consistency`.
+/// `prefix` is the sequence of LLM tokens committed before `llm_token_id`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Inconsistency {
+    /// `get_mask` allows (`allowed`) or rejects `llm_token_id`, but the reference does the opposite.
+    MaskMismatch { prefix: Vec<LLMTokenID>, llm_token_id: LLMTokenID, allowed: bool },
+    /// `get_mask` allows `llm_token_id`, but after committing it nothing is allowed.
+    DeadEnd { prefix: Vec<LLMTokenID>, llm_token_id: LLMTokenID },
+    /// `get_mask` rejects `llm_token_id`, but after committing it something is still allowed.
+    NotRejected { prefix: Vec<LLMTokenID>, llm_token_id: LLMTokenID },
+}
+
+/// Checks `state` on `num_walks` random walks of up to `max_tokens` LLM tokens each:
+/// - `get_mask` must agree with `ReferenceConstraint`, which lexes the whole output in every possible way and
+///   parses each token sequence,
+/// - committing an allowed token (other than EOS) must leave something allowed, if only EOS,
+/// - committing a rejected token must leave nothing allowed.
+///
+/// `state` must be at the start of the grammar, as returned by `GrammarConstraint::init`. Every walk commits every
+/// token at every step, so this is only practical for small vocabularies. Leaves the state unchanged.
+pub fn check_consistency<T: Tokenizer + Clone>(state: &mut GrammarConstraintState<T>, rng: &mut impl Rng, num_walks: usize, max_tokens: usize) -> Result<(), Inconsistency> {
+    assert_eq!(state.mode, ConstraintMode::Active, "the consistency check needs an active constraint");
+    let reference = ReferenceConstraint::from_constraint(&state.parent);
+    let initial = state.snapshot();
+    let result = (0..num_walks).try_for_each(|_| {
+        state.restore(initial.clone());
+        check_walk(state, reference.clone(), rng, max_tokens)
+    });
+    state.restore(initial);
+    result
+}
+
+fn check_walk<T: Tokenizer>(state: &mut GrammarConstraintState<T>, mut reference: ReferenceConstraint<T>, rng: &mut impl Rng, max_tokens: usize) -> Result<(), Inconsistency> {
+    // Every token the constraint knows about, except EOS, which ends the walk.
+    let llm_token_ids: Vec<LLMTokenID> = state.parent.llm_tokens.right_values()
+        .chain(state.parent.special_tokens.terminal_llm_token_ids.keys())
+        .chain(state.parent.special_tokens.alias_llm_token_ids.keys())
+        .copied()
+        .filter(|llm_token_id| !state.parent.special_tokens.eos_llm_token_ids.contains(llm_token_id))
+        .collect();
+    let mut prefix = Vec::new();
+    loop {
+        let mask = state.get_mask();
+        let expected_mask = reference.get_mask();
+        if let Some(llm_token_id) = (mask.clone() ^ expected_mask).first_one() {
+            return Err(Inconsistency::MaskMismatch { prefix, llm_token_id: LLMTokenID(llm_token_id), allowed: mask[llm_token_id] });
+        }
+
+        let snapshot = state.snapshot();
+        let mut allowed_llm_token_ids = Vec::new();
+        for &llm_token_id in &llm_token_ids {
+            let allowed = mask[llm_token_id.0];
+            let forbidden = state.parent.special_tokens.forbidden_llm_token_ids.contains(&llm_token_id);
+            state.commit(llm_token_id);
+            let anything_allowed = state.get_mask().any();
+            state.restore(snapshot.clone());
+            if allowed && !anything_allowed {
+                return Err(Inconsistency::DeadEnd { prefix, llm_token_id });
+            }
+            // Forbidden tokens are only masked out, the grammar may still accept them.
+            if !allowed && !forbidden && anything_allowed {
+                return Err(Inconsistency::NotRejected { prefix, llm_token_id });
+            }
+            if allowed {
+                allowed_llm_token_ids.push(llm_token_id);
+            }
+        }
+
+        if prefix.len() == max_tokens {
+            return Ok(());
+        }
+        let Some(&llm_token_id) = allowed_llm_token_ids.choose(rng) else {
+            return Ok(());
+        };
+        state.commit(llm_token_id);
+        reference.commit(llm_token_id);
+        prefix.push(llm_token_id);
+    }
+}
@@ -1,44 +1,109 @@
 // src/constraint.rs
+use crate::error::Error;
 use crate::glr::parser::{GLRParser, GLRParserState, InsertWith, ParseState, ParseStateKey};
-use crate::glr::table::{StateID, TerminalID};
-use crate::{dbgprintln2, precompute};
-use crate::precompute::{LLMTokenID, Token, TokenID, Tokenizer, TokenizerStateInfoForLLMToken};
+use crate::glr::table::{Stage7ShiftsAndReduces, StateID, TerminalID};
+use crate::precompute;
+use crate::precompute::{freeze_precomputed, llm_tokens_consistent_with_prefix, precompute_state, record_llm_token_end, LLMTokenID, FrozenPrecomputedNodeValue, PrecomputedNodeValue, TokenID, Tokenizer};
 use bitvec::prelude::*;
-use std::collections::{BTreeMap, BTreeSet};
-use std::fmt::Write;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::{Display, Formatter};
 use std::sync::{Arc, Mutex};
 use crate::trie::TrieNode;
+use crate::frozen_trie::FrozenTrie;
+use crate::compact_bitset::CompactBitSet;
 use bimap::BiBTreeMap;
 
 type LLMToken = Vec<u8>;
+
+/// Stops `forced_bytes` and `forced_tokens` for grammars that force an infinite sequence.
+const MAX_FORCED_LEN: usize = 4096;
+/// Bounds `GrammarConstraint::healing_masks`, which is cleared when it's full.
+const MAX_CACHED_HEALING_MASKS: usize = 64;
 type LLMTokenMap = BiBTreeMap<Vec<u8>, LLMTokenID>;
+type PrecomputedTrie = FrozenTrie<TokenID, FrozenPrecomputedNodeValue>;
 
 // TODO: should this *really* derive `Clone`? Users probably shouldn't clone this, should they?
 #[derive(Debug, Clone)]
 pub struct GrammarConstraint<T: Tokenizer> {
     pub(crate) tokenizer: T,
     pub(crate) parser: GLRParser,
-    pub precomputed: BTreeMap<StateID, TrieNode<TokenID, (BTreeMap<LLMTokenID, TokenizerStateInfoForLLMToken>, BTreeMap<TokenID, BitVec>, Option<BitVec>)>>,
+    pub precomputed: BTreeMap<StateID, FrozenTrie<TokenID, FrozenPrecomputedNodeValue>>,
+    /// Tries for tokenizer states that weren't known when `precomputed` was built. See `with_trie`.
+    pub(crate) lazily_precomputed: Arc<Mutex<BTreeMap<StateID, Arc<PrecomputedTrie>>>>,
+    /// The LLM tokens consistent with each healing prefix seen so far. See `healing_mask`.
+    pub(crate) healing_masks: Arc<Mutex<HashMap<Vec<u8>, Arc<BitVec>>>>,
     pub(crate) max_llm_token_id: usize,
+    /// Grammar tokens that are skipped between any two other grammar tokens without stepping the parser.
+    pub(crate) ignore_terminal_ids: BTreeSet<TokenID>,
+    pub(crate) llm_tokens: LLMTokenMap,
+    pub(crate) special_tokens: SpecialTokenPolicy,
 }
 
 #[derive(Debug, Clone)]
 pub struct GrammarConstraintState<T: Tokenizer> {
     pub(crate) parent: GrammarConstraint<T>,
     pub(crate) states: Vec<(ParseState, BTreeSet<StateID>)>,
+    pub(crate) mode: ConstraintMode,
+    /// Whether the grammar stops applying once it's complete, rather than only allowing EOS.
+    pub(crate) finish_when_complete: bool,
+    /// Text that the next LLM tokens must start with. See `heal_prompt`.
+    pub(crate) healing_prefix: Vec<u8>,
+}
+
+/// See `GrammarConstraintState::snapshot`.
+pub(crate) type GrammarConstraintSnapshot = (Vec<(ParseState, BTreeSet<StateID>)>, ConstraintMode, Vec<u8>);
+
+/// Whether the grammar applies to the tokens being generated. See `GrammarConstraint::init_with_trigger`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ConstraintMode {
+    /// Free text. The grammar activates once `trigger` has been generated (if given), or on `activate`.
+    Waiting {
+        trigger: Option<Vec<u8>>,
+        /// The end of the text generated so far, long enough to find a trigger that spans LLM tokens.
+        recent_bytes: Vec<u8>,
+    },
+    /// Every token must continue the grammar.
+    Active,
+    /// The grammar fully matched and the next token didn't continue it (or nothing can continue it), the text after
+    /// the trigger couldn't start the grammar, an EOS token was generated, or the constraint was deactivated.
+    /// Generation is free again.
+    Finished,
 }
 
 impl<T: Tokenizer> GrammarConstraint<T> {
     pub fn new(tokenizer: T, parser: GLRParser, llm_tokens: LLMTokenMap, eof_llm_token_id: usize, max_llm_token_id: usize) -> Self {
-        let mut precomputed = precompute::precompute(&tokenizer, &llm_tokens, LLMTokenID(eof_llm_token_id), max_llm_token_id);
-        precompute_add_eof(&mut precomputed, LLMTokenID(eof_llm_token_id), parser.eof_terminal_id.0, max_llm_token_id);
+        Self::new_with_special_tokens(tokenizer, parser, llm_tokens, SpecialTokenPolicy::eos(eof_llm_token_id), max_llm_token_id)
+    }
 
-        Self {
+    /// Like `new`, but with any number of EOS tokens and other special tokens.
+    pub fn new_with_special_tokens(tokenizer: T, parser: GLRParser, llm_tokens: LLMTokenMap, special_tokens: SpecialTokenPolicy, max_llm_token_id: usize) -> Self {
+        Self::try_new_with_special_tokens(tokenizer, parser, llm_tokens, special_tokens, max_llm_token_id).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `new`, but returns an error instead of panicking, e.g. if the tokenizer matches the empty string or a
+    /// token ID is larger than `max_llm_token_id`.
+    pub fn try_new(tokenizer: T, parser: GLRParser, llm_tokens: LLMTokenMap, eof_llm_token_id: usize, max_llm_token_id: usize) -> Result<Self, Error> {
+        Self::try_new_with_special_tokens(tokenizer, parser, llm_tokens, SpecialTokenPolicy::eos(eof_llm_token_id), max_llm_token_id)
+    }
+
+    /// Like `new_with_special_tokens`, but returns an error instead of panicking.
+    pub fn try_new_with_special_tokens(tokenizer: T, parser: GLRParser, llm_tokens: LLMTokenMap, special_tokens: SpecialTokenPolicy, max_llm_token_id: usize) -> Result<Self, Error> {
+        validate_llm_token_ids(&llm_tokens, &special_tokens, max_llm_token_id)?;
+        let precomputed = precompute::precompute_frozen(&tokenizer, &llm_tokens, max_llm_token_id, |state_id, trie| {
+            precompute_add_special_tokens(state_id, trie, &tokenizer, &special_tokens, parser.eof_terminal_id.0, max_llm_token_id);
+        })?;
+
+        Ok(Self {
             tokenizer,
             parser,
             precomputed,
+            lazily_precomputed: Default::default(),
+            healing_masks: Default::default(),
             max_llm_token_id,
-        }
+            ignore_terminal_ids: BTreeSet::new(),
+            llm_tokens,
+            special_tokens,
+        })
     }
 
     pub fn init(self) -> GrammarConstraintState<T> {
@@ -60,115 +125,688 @@ impl<T: Tokenizer> GrammarConstraint<T> {
         GrammarConstraintState {
             parent: self,
             states: vec![(parser_initial_state, BTreeSet::from([tokenizer_initial_state_id]))],
+            mode: ConstraintMode::Active,
+            finish_when_complete: false,
+            healing_prefix: Vec::new(),
         }
     }
+
+    /// Like `init`, but generation is free until `trigger` has been generated (e.g. `b"Answer: "`). The grammar
+    /// starts right after the trigger. Once it fully matches, any token is allowed: one that continues the grammar
+    /// keeps it active, and any other one ends it and is free text. With no trigger, the grammar only starts on
+    /// `activate`.
+    pub fn init_with_trigger(self, trigger: Option<&[u8]>) -> GrammarConstraintState<T> {
+        let mut state = self.init();
+        state.finish_when_complete = true;
+        // An empty trigger has already been generated.
+        if trigger.is_some_and(<[u8]>::is_empty) {
+            return state;
+        }
+        state.mode = ConstraintMode::Waiting { trigger: trigger.map(<[u8]>::to_vec), recent_bytes: Vec::new() };
+        state
+    }
 }
 
-pub fn precompute_add_eof(
-    precomputed: &mut BTreeMap<StateID, TrieNode<TokenID, (BTreeMap<LLMTokenID, TokenizerStateInfoForLLMToken>, BTreeMap<TokenID, BitVec>, Option<BitVec>)>>,
-    eof_llm_token_id: LLMTokenID,
+/// Size statistics for a `GrammarConstraint`. See `GrammarConstraint::stats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrammarConstraintStats {
+    pub num_tokenizer_states: usize,
+    pub num_lr_states: usize,
+    /// Number of (LR state, terminal) pairs with a conflict, where the GLR parser splits.
+    pub num_lr_splits: usize,
+    /// Number of precompute trie nodes for each tokenizer state.
+    pub trie_nodes: BTreeMap<StateID, usize>,
+    /// Number of distinct precompute trie nodes, after sharing identical subtrees within and across tokenizer
+    /// states.
+    pub unique_trie_nodes: usize,
+    /// Number of precompute trie edges for each tokenizer state.
+    pub trie_edges: BTreeMap<StateID, usize>,
+    /// Memory used by the distinct LLM token bitsets in the precompute tries.
+    pub bitset_bytes: usize,
+    /// The LLM tokens that lex to the most token sequences, summed over tokenizer states, with the number of
+    /// sequences, most first. A trie node shared by several sequences counts once per sequence.
+    pub largest_llm_token_fan_outs: Vec<(LLMTokenID, usize)>,
+}
+
+impl Display for GrammarConstraintStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Tokenizer states: {}", self.num_tokenizer_states)?;
+        writeln!(f, "LR states: {} ({} splits)", self.num_lr_states, self.num_lr_splits)?;
+        writeln!(f, "Precompute trie nodes: {} ({} unique)", self.trie_nodes.values().sum::<usize>(), self.unique_trie_nodes)?;
+        writeln!(f, "Precompute trie edges: {}", self.trie_edges.values().sum::<usize>())?;
+        writeln!(f, "Bitset bytes: {}", self.bitset_bytes)?;
+        writeln!(f, "Largest tries:")?;
+        let mut trie_nodes: Vec<_> = self.trie_nodes.iter().collect();
+        trie_nodes.sort_by_key(|&(_, &num_nodes)| std::cmp::Reverse(num_nodes));
+        for (tokenizer_state, num_nodes) in trie_nodes.into_iter().take(10) {
+            writeln!(f, "  Tokenizer state {}: {} nodes, {} edges", tokenizer_state.0, num_nodes, self.trie_edges[tokenizer_state])?;
+        }
+        writeln!(f, "Largest LLM token fan-outs:")?;
+        for (llm_token_id, fan_out) in &self.largest_llm_token_fan_outs {
+            writeln!(f, "  LLM token {}: {} token sequences", llm_token_id.0, fan_out)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Tokenizer> GrammarConstraint<T> {
+    /// Reports the sizes of the tokenizer, the parse table and the precompute tries, to find what makes a
+    /// constraint large. `max_fan_outs` bounds the number of LLM tokens in `largest_llm_token_fan_outs`.
+    pub fn stats(&self, max_fan_outs: usize) -> GrammarConstraintStats {
+        let num_lr_splits = self.parser.stage_7_table.values()
+            .flat_map(|row| row.shifts_and_reduces.values())
+            .filter(|action| matches!(action, Stage7ShiftsAndReduces::Split { .. }))
+            .count();
+
+        // Bitsets are shared between nodes, so count each one once.
+        let mut seen_bitsets: HashSet<*const CompactBitSet> = HashSet::new();
+        let mut total_bitset_bytes = 0;
+        let mut fan_outs: BTreeMap<LLMTokenID, usize> = BTreeMap::new();
+        // Tries frozen together share an arena, so count each arena once.
+        let mut arenas: Vec<&PrecomputedTrie> = Vec::new();
+        for trie in self.precomputed.values() {
+            if !arenas.iter().any(|arena| arena.shares_arena_with(trie)) {
+                arenas.push(trie);
+            }
+            for (node, num_paths) in trie.num_paths() {
+                let (llm_token_ends, possible_next_tokens, clean_end_llm_tokens) = trie.value(node);
+                for &llm_token_id in llm_token_ends.keys() {
+                    let fan_out = fan_outs.entry(llm_token_id).or_default();
+                    *fan_out = fan_out.saturating_add(num_paths);
+                }
+                for bitset in possible_next_tokens.values().chain(clean_end_llm_tokens) {
+                    if seen_bitsets.insert(Arc::as_ptr(bitset)) {
+                        total_bitset_bytes += bitset.size_in_bytes();
+                    }
+                }
+            }
+        }
+        let mut largest_llm_token_fan_outs: Vec<_> = fan_outs.into_iter().collect();
+        largest_llm_token_fan_outs.sort_by_key(|&(llm_token_id, fan_out)| (std::cmp::Reverse(fan_out), llm_token_id));
+        largest_llm_token_fan_outs.truncate(max_fan_outs);
+
+        GrammarConstraintStats {
+            num_tokenizer_states: self.tokenizer.max_state(),
+            num_lr_states: self.parser.stage_7_table.len(),
+            num_lr_splits,
+            trie_nodes: self.precomputed.iter().map(|(&state_id, trie)| (state_id, trie.num_nodes())).collect(),
+            unique_trie_nodes: arenas.iter().map(|trie| trie.arena_num_nodes()).sum(),
+            trie_edges: self.precomputed.iter().map(|(&state_id, trie)| (state_id, trie.num_edges())).collect(),
+            bitset_bytes: total_bitset_bytes,
+            largest_llm_token_fan_outs,
+        }
+    }
+}
+
+impl<T: Tokenizer> GrammarConstraint<T> {
+    /// Calls `f` with the precompute trie for `tokenizer_state_id`. Tokenizers that build their states lazily (see
+    /// `LazyRegex`) can reach states that weren't known when the constraint was built. Their tries are precomputed
+    /// the first time they're needed.
+    pub(crate) fn with_trie<R>(&self, tokenizer_state_id: StateID, f: impl FnOnce(&FrozenTrie<TokenID, FrozenPrecomputedNodeValue>) -> R) -> Result<R, Error> {
+        if let Some(trie) = self.precomputed.get(&tokenizer_state_id) {
+            return Ok(f(trie));
+        }
+        if tokenizer_state_id.0 >= self.tokenizer.max_state() {
+            return Err(Error::MissingTokenizerState(tokenizer_state_id));
+        }
+        let existing_trie = self.lazily_precomputed.lock().unwrap().get(&tokenizer_state_id).cloned();
+        let trie = match existing_trie {
+            Some(trie) => trie,
+            None => {
+                let mut trie = precompute_state(&self.tokenizer, &self.llm_tokens, tokenizer_state_id.0, self.max_llm_token_id);
+                precompute_add_special_tokens(tokenizer_state_id, &mut trie, &self.tokenizer, &self.special_tokens, self.parser.eof_terminal_id.0, self.max_llm_token_id);
+                let trie = Arc::new(freeze_precomputed(&BTreeMap::from([(tokenizer_state_id, trie)])).remove(&tokenizer_state_id).unwrap());
+                self.lazily_precomputed.lock().unwrap().entry(tokenizer_state_id).or_insert(trie).clone()
+            }
+        };
+        Ok(f(&trie))
+    }
+
+    /// Returns the LLM tokens consistent with the healing prefix `prefix` (see `llm_tokens_consistent_with_prefix`).
+    /// The masks are cached, since `get_mask` needs the same one until tokens covering the prefix are committed.
+    fn healing_mask(&self, prefix: &[u8]) -> Arc<BitVec> {
+        let mut healing_masks = self.healing_masks.lock().unwrap();
+        if let Some(mask) = healing_masks.get(prefix) {
+            return mask.clone();
+        }
+        if healing_masks.len() >= MAX_CACHED_HEALING_MASKS {
+            healing_masks.clear();
+        }
+        let mask = Arc::new(llm_tokens_consistent_with_prefix(&self.llm_tokens, prefix, self.max_llm_token_id));
+        healing_masks.insert(prefix.to_vec(), mask.clone());
+        mask
+    }
+
+    /// Returns the parse states that have an action for `token_id`, so that tokenizer matches that are
+    /// impossible in the current parse context can be pruned before stepping the GLR parser.
+    fn accepting_parse_states(&self, parse_states: &[ParseState], token_id: TokenID) -> Vec<ParseState> {
+        parse_states
+            .iter()
+            .filter(|parse_state| self.parser.accepts_terminal_id(parse_state, TerminalID(token_id)))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the parse states after the grammar token `token_id`, or `None` if none of `parse_states` can
+    /// consume it.
+    fn step_parse_states(&self, parse_states: &[ParseState], token_id: TokenID) -> Option<Vec<ParseState>> {
+        if self.ignore_terminal_ids.contains(&token_id) {
+            return Some(parse_states.to_vec());
+        }
+        // Contextual lexing: only keep the parse states that can accept this token.
+        let accepting_parse_states = self.accepting_parse_states(parse_states, token_id);
+        if accepting_parse_states.is_empty() {
+            return None;
+        }
+        let mut glr_parse_state = self.parser.init_glr_parser_from_parse_states(accepting_parse_states);
+        glr_parse_state.step(TerminalID(token_id));
+        Some(glr_parse_state.active_states).filter(|active_states| !active_states.is_empty())
+    }
+
+    /// Walks the precompute trie `trie` from `parse_state`, stepping the parser along each grammar token edge, and
+    /// calls `process` on each node with the parse states that reach it. Edges whose grammar token the parse states
+    /// can't consume are pruned with their subtrees, so tokenizer matches that are impossible in the current parse
+    /// context are never explored.
+    pub(crate) fn walk_trie(
+        &self,
+        trie: &FrozenTrie<TokenID, FrozenPrecomputedNodeValue>,
+        parse_state: &ParseState,
+        process: impl FnMut(&FrozenPrecomputedNodeValue, &Vec<ParseState>),
+    ) {
+        trie.special_map(
+            vec![parse_state.clone()],
+            |current_parse_states, token_id, _| self.step_parse_states(current_parse_states, *token_id),
+            |parse_states: Vec<Vec<ParseState>>| {
+                let mut new_glr_parse_state = self.parser.init_glr_parser_from_parse_states(parse_states.concat());
+                new_glr_parse_state.merge_active_states();
+                new_glr_parse_state.active_states
+            },
+            process,
+        );
+    }
+}
+
+/// How the constraint treats LLM tokens that aren't text. Token IDs that are in neither the text vocabulary nor
+/// this policy are never allowed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SpecialTokenPolicy {
+    /// Tokens that end generation. Each is allowed wherever the input can end.
+    pub eos_llm_token_ids: BTreeSet<LLMTokenID>,
+    /// Tokens that are never allowed, even if they are in the text vocabulary.
+    pub forbidden_llm_token_ids: BTreeSet<LLMTokenID>,
+    /// Tokens that stand for a single grammar token (e.g. a tool call start marker), by the grammar token they
+    /// produce. They are allowed wherever that grammar token is, as long as they don't cut a grammar token short.
+    /// Give the grammar token a regex that matches nothing if the special token should be the only way to produce it.
+    pub terminal_llm_token_ids: BTreeMap<LLMTokenID, TokenID>,
+    /// Tokens with the same text as a token in the text vocabulary, which is a bijection and can't hold both (e.g.
+    /// `<0x20>` and `▁` in SentencePiece vocabularies), by that token. They're allowed wherever it is.
+    pub alias_llm_token_ids: BTreeMap<LLMTokenID, LLMTokenID>,
+}
+
+impl SpecialTokenPolicy {
+    /// A policy with a single EOS token and nothing else.
+    pub fn eos(eof_llm_token_id: usize) -> Self {
+        SpecialTokenPolicy { eos_llm_token_ids: BTreeSet::from([LLMTokenID(eof_llm_token_id)]), ..Default::default() }
+    }
+
+    /// Returns the text token that `llm_token_id` is an alias of, or `llm_token_id` itself.
+    pub(crate) fn resolve_alias(&self, llm_token_id: LLMTokenID) -> LLMTokenID {
+        self.alias_llm_token_ids.get(&llm_token_id).copied().unwrap_or(llm_token_id)
+    }
+
+    /// Allows each alias in `mask` wherever the token it stands for is allowed, unless it's forbidden.
+    pub(crate) fn apply_aliases(&self, mask: &mut BitVec) {
+        for (alias_llm_token_id, llm_token_id) in &self.alias_llm_token_ids {
+            let allowed = mask[llm_token_id.0] && !self.forbidden_llm_token_ids.contains(alias_llm_token_id);
+            mask.set(alias_llm_token_id.0, allowed);
+        }
+    }
+}
+
+/// Checks that every LLM token ID fits in a mask of `max_llm_token_id + 1` bits, and that special tokens that stand
+/// for a grammar token have no text.
+pub(crate) fn validate_llm_token_ids(llm_tokens: &LLMTokenMap, special_tokens: &SpecialTokenPolicy, max_llm_token_id: usize) -> Result<(), Error> {
+    let llm_token_ids = llm_tokens.right_values()
+        .chain(&special_tokens.eos_llm_token_ids)
+        .chain(&special_tokens.forbidden_llm_token_ids)
+        .chain(special_tokens.terminal_llm_token_ids.keys())
+        .chain(special_tokens.alias_llm_token_ids.keys());
+    for &llm_token_id in llm_token_ids {
+        if llm_token_id.0 > max_llm_token_id {
+            return Err(Error::LLMTokenIDOutOfRange { llm_token_id, max_llm_token_id });
+        }
+    }
+    if let Some(&llm_token_id) = special_tokens.terminal_llm_token_ids.keys().find(|llm_token_id| llm_tokens.contains_right(llm_token_id)) {
+        return Err(Error::SpecialTokenIsText(llm_token_id));
+    }
+    if let Some(&llm_token_id) = special_tokens.alias_llm_token_ids.values().find(|llm_token_id| !llm_tokens.contains_right(llm_token_id)) {
+        return Err(Error::NotText(llm_token_id));
+    }
+    Ok(())
+}
+
+/// Drains the part of `healing_prefix` that `bytes` covers. Returns false if `bytes` disagrees with the prefix.
+fn consume_healing_prefix(healing_prefix: &mut Vec<u8>, bytes: &[u8]) -> bool {
+    let len = bytes.len().min(healing_prefix.len());
+    if bytes[..len] != healing_prefix[..len] {
+        return false;
+    }
+    healing_prefix.drain(..len);
+    true
+}
+
+/// Returns the child of `node` along `token_id`, creating it if necessary. A child that is shared with other paths
+/// is replaced by a copy first, so that it can be modified.
+fn unshared_child(node: &mut TrieNode<TokenID, PrecomputedNodeValue>, token_id: TokenID) -> Arc<Mutex<TrieNode<TokenID, PrecomputedNodeValue>>> {
+    match node.get(&token_id) {
+        Some(child) if child.try_lock().unwrap().is_shared() => node.replace_child_with_clone(&token_id),
+        Some(child) => child,
+        None => {
+            let child = Arc::new(Mutex::new(TrieNode::new((BTreeMap::new(), BTreeMap::new(), None))));
+            node.insert(token_id, child.clone());
+            child
+        }
+    }
+}
+
+/// Adds the special tokens of `special_tokens` to the precompute trie `root` of tokenizer state `state_id`: EOS
+/// tokens produce the EOF grammar token, and the tokens in `terminal_llm_token_ids` produce their grammar token.
+pub fn precompute_add_special_tokens(
+    state_id: StateID,
+    root: &mut TrieNode<TokenID, PrecomputedNodeValue>,
+    tokenizer: &impl Tokenizer,
+    special_tokens: &SpecialTokenPolicy,
     eof_grammar_token_id: TokenID,
     max_llm_token_id: usize,
 ) {
-    let mut bitset = BitVec::new();
-    bitset.resize(max_llm_token_id + 1, false);
-    bitset.set(eof_llm_token_id.0, true);
-    let node = precomputed.get_mut(&StateID(0)).unwrap();
-    assert!(!node.value.1.contains_key(&eof_grammar_token_id));
-    node.value.1.insert(eof_grammar_token_id, bitset);
+
+    // Special tokens can only be used at a grammar token boundary. In a dirty tokenizer state, that means the input
+    // must end the current token first (e.g. one with a trailing negative lookahead).
+    let boundaries: Vec<Arc<Mutex<TrieNode<TokenID, PrecomputedNodeValue>>>> = if state_id == StateID(0) {
+        vec![]
+    } else {
+        tokenizer.tokens_matching_at_end_of_input(state_id.0).into_iter().map(|token_id| unshared_child(root, token_id)).collect()
+    };
+    let add_special_tokens = |node: &mut TrieNode<TokenID, PrecomputedNodeValue>| {
+        if !special_tokens.eos_llm_token_ids.is_empty() {
+            let eof_bitset = node.value.1.entry(eof_grammar_token_id).or_default();
+            for eos_llm_token_id in &special_tokens.eos_llm_token_ids {
+                eof_bitset.insert(eos_llm_token_id.0, max_llm_token_id + 1);
+            }
+        }
+        for (&llm_token_id, &grammar_token_id) in &special_tokens.terminal_llm_token_ids {
+            let child = unshared_child(node, grammar_token_id);
+            record_llm_token_end(&mut child.try_lock().unwrap().value, tokenizer, llm_token_id, 0, None, max_llm_token_id);
+        }
+    };
+    if state_id == StateID(0) {
+        add_special_tokens(root);
+    }
+    for boundary in boundaries {
+        add_special_tokens(&mut boundary.try_lock().unwrap());
+    }
 }
 
 impl<'a, T: Tokenizer> GrammarConstraintState<T> {
+    pub fn mode(&self) -> &ConstraintMode {
+        &self.mode
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.mode == ConstraintMode::Active
+    }
+
+    /// Starts the grammar from the beginning, whatever the current mode.
+    pub fn activate(&mut self) {
+        let parser_initial_state = self.parent.parser.init_parse_state();
+        let tokenizer_initial_state_id = StateID(self.parent.tokenizer.initial_state_id());
+        self.states = vec![(parser_initial_state, BTreeSet::from([tokenizer_initial_state_id]))];
+        self.mode = ConstraintMode::Active;
+    }
+
+    /// Stops applying the grammar.
+    pub fn deactivate(&mut self) {
+        self.mode = ConstraintMode::Finished;
+    }
+
+    /// Returns the LLM tokens that are allowed next.
     pub fn get_mask(&self) -> BitVec {
+        self.try_get_mask().unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `get_mask`, but returns an error instead of panicking.
+    pub fn try_get_mask(&self) -> Result<BitVec, Error> {
+        let mut result = if self.is_active() && !self.may_finish() {
+            self.grammar_mask()?
+        } else {
+            let mut result = BitVec::new();
+            result.resize(self.parent.max_llm_token_id + 1, true);
+            for forbidden_llm_token_id in &self.parent.special_tokens.forbidden_llm_token_ids {
+                result.set(forbidden_llm_token_id.0, false);
+            }
+            result
+        };
+        if !self.healing_prefix.is_empty() {
+            result &= &*self.parent.healing_mask(&self.healing_prefix);
+        }
+        self.parent.special_tokens.apply_aliases(&mut result);
+        Ok(result)
+    }
+
+    /// Token healing for a prompt that may end in the middle of a token: removes the last `k` tokens of `prompt`
+    /// and returns the rest, to be used as the prompt instead. The next LLM tokens must then start with the removed
+    /// text, so the model can choose how to tokenize it together with what follows.
+    ///
+    /// The removed text is generated again, so the state must be at its start (i.e. it must not have been fed the
+    /// removed text).
+    pub fn heal_prompt<'p>(&mut self, prompt: &'p [LLMTokenID], k: usize) -> &'p [LLMTokenID] {
+        self.try_heal_prompt(prompt, k).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `heal_prompt`, but returns an error if one of the removed tokens isn't text. The state is unchanged on
+    /// error.
+    pub fn try_heal_prompt<'p>(&mut self, prompt: &'p [LLMTokenID], k: usize) -> Result<&'p [LLMTokenID], Error> {
+        let (kept, removed) = prompt.split_at(prompt.len().saturating_sub(k));
+        let mut healing_prefix = Vec::new();
+        for &llm_token_id in removed {
+            let llm_token = self.parent.llm_tokens.get_by_right(&self.parent.special_tokens.resolve_alias(llm_token_id));
+            healing_prefix.extend_from_slice(llm_token.ok_or(Error::NotText(llm_token_id))?);
+        }
+        self.healing_prefix.extend(healing_prefix);
+        Ok(kept)
+    }
+
+    /// Returns true if the text committed so far is a complete match of the grammar.
+    pub fn fully_matches(&self) -> bool {
+        self.states.iter().any(|(parse_state, tokenizer_state_ids)| tokenizer_state_ids.iter().any(|tokenizer_state_id| {
+            let parse_states = if *tokenizer_state_id == StateID(0) {
+                vec![parse_state.clone()]
+            } else {
+                // The input must end the current grammar token.
+                self.parent.tokenizer.tokens_matching_at_end_of_input(tokenizer_state_id.0).into_iter().flat_map(|token_id| {
+                    if self.parent.ignore_terminal_ids.contains(&token_id) {
+                        return vec![parse_state.clone()];
+                    }
+                    let mut glr_parse_state = self.parent.parser.init_glr_parser_from_parse_state(parse_state.clone());
+                    glr_parse_state.step(TerminalID(token_id));
+                    glr_parse_state.active_states
+                }).collect()
+            };
+            let mut glr_parse_state = self.parent.parser.init_glr_parser_from_parse_states(parse_states);
+            glr_parse_state.parse_eof();
+            glr_parse_state.fully_matches()
+        }))
+    }
+
+    fn grammar_mask(&self) -> Result<BitVec, Error> {
         let mut result = BitVec::new();
         result.resize(self.parent.max_llm_token_id + 1, false);
-        dbgprintln2!("Getting mask");
         for (parse_state, tokenizer_state_ids) in &self.states {
-            dbgprintln2!("Getting mask for parse state {:?}, tokenizer states {:?}", parse_state, tokenizer_state_ids);
             for tokenizer_state in tokenizer_state_ids {
-                let token_sequence_map = &self.parent.precomputed[tokenizer_state];
-                TrieNode::special_map(
-                    // todo (performance): unnecessary clone
-                    Arc::new(Mutex::new(token_sequence_map.clone())),
-                    vec![parse_state.clone()],
-                    // todo: it's messy that we need to access the value in dst_node here.
-                    |current_parse_states, token_id, dst_node| {
-                        // todo: this is introducing redundancy... ?
-                        let mut glr_parse_state = self.parent.parser.init_glr_parser_from_parse_states(current_parse_states.clone());
-                        // println!("token_id: {:?}", token_id);
-                        crate::dbgprintln!("Stepping");
-                        glr_parse_state.step(TerminalID(*token_id));
-                        crate::dbgprintln!("Done stepping");
-                        glr_parse_state.active_states
-                    },
-                    |mut parse_states: Vec<Vec<ParseState>>| {
-                        let mut all_parse_states = parse_states.pop().unwrap();
-                        for mut other_parse_state in parse_states {
-                            all_parse_states.append(&mut other_parse_state)
-                        }
-                        let mut new_glr_parse_state = self.parent.parser.init_glr_parser_from_parse_states(all_parse_states);
-                        crate::dbgprintln!("Merging active states");
-                        new_glr_parse_state.merge_active_states();
-                        crate::dbgprintln!("Done merging active states");
-                        new_glr_parse_state.active_states
-                    },
+                self.parent.with_trie(*tokenizer_state, |trie| self.parent.walk_trie(
+                    trie,
+                    parse_state,
                     |(_, bitsets, maybe_clean_end_bitset), current_parse_states| {
                         let mut glr_parse_state = self.parent.parser.init_glr_parser_from_parse_states(current_parse_states.clone());
                         if glr_parse_state.is_ok() {
                             // dbg!(&bitsets);
+                            let acceptable_terminal_ids = glr_parse_state.acceptable_terminal_ids();
                             for (possible_next_grammar_token, bitset) in bitsets {
+                                if self.parent.ignore_terminal_ids.contains(possible_next_grammar_token) {
+                                    bitset.or_into(&mut result);
+                                    continue;
+                                }
+                                if !acceptable_terminal_ids.contains(&TerminalID(*possible_next_grammar_token)) {
+                                    continue;
+                                }
                                 let mut new_glr_parse_state = glr_parse_state.clone();
                                 let possible_next_grammar_token_id = TerminalID(*possible_next_grammar_token);
                                 crate::dbgprintln!("Stepping for possible next grammar token {:?}", possible_next_grammar_token);
                                 new_glr_parse_state.step(possible_next_grammar_token_id);
                                 crate::dbgprintln!("Done stepping for possible next grammar token");
-                                // panic!();
-                                // todo: remove this
-                                println!("possible_next_grammar_token: {:?}", possible_next_grammar_token);
-                                // result |= bitset;
                                 if new_glr_parse_state.is_ok() {
                                     // dbg!(&bitset);
-                                    result |= bitset;
+                                    bitset.or_into(&mut result);
                                 }
                             }
                             if let Some(bitset) = maybe_clean_end_bitset {
                                 // dbg!(&maybe_clean_end_bitset);
-                                result |= bitset;
+                                bitset.or_into(&mut result);
                             }
                         }
                     },
-                );
-                dbgprintln2!("Done");
+                ))?;
             }
         }
-        result
+        for forbidden_llm_token_id in &self.parent.special_tokens.forbidden_llm_token_ids {
+            result.set(forbidden_llm_token_id.0, false);
+        }
+        Ok(result)
     }
 
     pub fn commit(&mut self, llm_token_id: LLMTokenID) {
+        self.commit_token(llm_token_id).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `commit`, but returns an error instead of panicking, including for a token ID larger than
+    /// `max_llm_token_id` (which `commit` treats as a token the grammar doesn't allow).
+    pub fn try_commit(&mut self, llm_token_id: LLMTokenID) -> Result<(), Error> {
+        if llm_token_id.0 > self.parent.max_llm_token_id {
+            return Err(Error::LLMTokenIDOutOfRange { llm_token_id, max_llm_token_id: self.parent.max_llm_token_id });
+        }
+        self.commit_token(llm_token_id)
+    }
+
+    fn commit_token(&mut self, llm_token_id: LLMTokenID) -> Result<(), Error> {
+        let llm_token_id = self.parent.special_tokens.resolve_alias(llm_token_id);
+        let healed = match self.parent.llm_tokens.get_by_right(&llm_token_id) {
+            Some(llm_token) => consume_healing_prefix(&mut self.healing_prefix, llm_token),
+            None => self.healing_prefix.is_empty(),
+        };
+        if !healed {
+            self.reject();
+            return Ok(());
+        }
+        match &self.mode {
+            ConstraintMode::Active => {
+                if self.finish_when_complete && self.parent.special_tokens.eos_llm_token_ids.contains(&llm_token_id) {
+                    self.mode = ConstraintMode::Finished;
+                    return Ok(());
+                }
+                let states = self.advance(None, llm_token_id)?;
+                self.set_states_or_finish(states)?;
+            }
+            ConstraintMode::Waiting { trigger: Some(_), .. } => {
+                if let Some(token) = self.parent.llm_tokens.get_by_right(&llm_token_id) {
+                    self.advance_bytes(&token.clone())?;
+                }
+            }
+            ConstraintMode::Waiting { trigger: None, .. } | ConstraintMode::Finished => {}
+        }
+        Ok(())
+    }
+
+    /// Advances over `bytes` as if they had been generated, e.g. a prefix of the structured output that's already
+    /// in the prompt. The bytes don't need to line up with LLM tokens or grammar tokens: if the last grammar token
+    /// is incomplete, the next LLM token continues it.
+    pub fn feed_bytes(&mut self, bytes: &[u8]) {
+        self.try_feed_bytes(bytes).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `feed_bytes`, but returns an error instead of panicking.
+    pub fn try_feed_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        if !consume_healing_prefix(&mut self.healing_prefix, bytes) {
+            self.reject();
+            return Ok(());
+        }
+        self.advance_bytes(bytes)
+    }
+
+    /// Leaves nothing allowed, after text that the constraint doesn't allow (e.g. a token outside the healing
+    /// mask).
+    fn reject(&mut self) {
+        self.states.clear();
+        self.healing_prefix.clear();
+        self.mode = ConstraintMode::Active;
+    }
+
+    fn advance_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        match &mut self.mode {
+            ConstraintMode::Active => {
+                if bytes.is_empty() {
+                    return Ok(());
+                }
+                // Precompute the tries for `bytes` as if they were a single LLM token, just for the tokenizer
+                // states we're in.
+                let llm_token_map = BiBTreeMap::from_iter([(bytes.to_vec(), LLMTokenID(0))]);
+                let tokenizer_state_ids: BTreeSet<StateID> = self.states.iter().flat_map(|(_, tokenizer_state_ids)| tokenizer_state_ids.iter().copied()).collect();
+                let tries = tokenizer_state_ids.into_iter()
+                    .map(|tokenizer_state_id| (tokenizer_state_id, precompute_state(&self.parent.tokenizer, &llm_token_map, tokenizer_state_id.0, 0)))
+                    .collect();
+                let states = self.advance(Some(&freeze_precomputed(&tries)), LLMTokenID(0))?;
+                self.set_states_or_finish(states)?;
+            }
+            ConstraintMode::Waiting { trigger: Some(trigger), recent_bytes } => {
+                recent_bytes.extend_from_slice(bytes);
+                if let Some(position) = recent_bytes.windows(trigger.len()).position(|window| window == &trigger[..]) {
+                    // The grammar starts right after the trigger, even in the middle of an LLM token. If the rest
+                    // of the token can't start the grammar, the grammar doesn't apply to this output at all.
+                    let rest = recent_bytes[position + trigger.len()..].to_vec();
+                    self.activate();
+                    self.advance_bytes(&rest)?;
+                    if self.is_active() && self.states.is_empty() {
+                        self.mode = ConstraintMode::Finished;
+                    }
+                } else {
+                    let keep = recent_bytes.len().min(trigger.len() - 1);
+                    recent_bytes.drain(..recent_bytes.len() - keep);
+                }
+            }
+            ConstraintMode::Waiting { trigger: None, .. } | ConstraintMode::Finished => {}
+        }
+        Ok(())
+    }
+
+    /// Returns the longest text that every valid continuation starts with, e.g. `": "` after `{"name"` in a JSON
+    /// schema. It can be appended (with `feed_bytes`) without running the model. Leaves the state unchanged.
+    pub fn forced_bytes(&mut self) -> Vec<u8> {
+        self.try_forced_bytes().unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `forced_bytes`, but returns an error instead of panicking.
+    pub fn try_forced_bytes(&mut self) -> Result<Vec<u8>, Error> {
+        self.with_scratch_state(Self::take_forced_bytes)
+    }
+
+    fn take_forced_bytes(state: &mut Self) -> Result<Vec<u8>, Error> {
+        let mut forced_bytes = Vec::new();
+        while forced_bytes.len() < MAX_FORCED_LEN {
+            let mask = state.try_get_mask()?;
+            let mut llm_tokens = Vec::new();
+            for llm_token_id in mask.iter_ones() {
+                // Special tokens (e.g. EOS) have no text.
+                let Some(llm_token) = state.parent.llm_tokens.get_by_right(&LLMTokenID(llm_token_id)) else {
+                    return Ok(forced_bytes);
+                };
+                llm_tokens.push(llm_token);
+            }
+            let Some((first, rest)) = llm_tokens.split_first() else {
+                break;
+            };
+            let common_prefix_len = rest.iter().fold(first.len(), |len, llm_token| {
+                first.iter().zip(llm_token.iter()).take(len).take_while(|(a, b)| a == b).count()
+            });
+            if common_prefix_len == 0 {
+                break;
+            }
+            let common_prefix = first[..common_prefix_len].to_vec();
+            state.try_feed_bytes(&common_prefix)?;
+            forced_bytes.extend(common_prefix);
+        }
+        Ok(forced_bytes)
+    }
+
+    /// Returns the longest sequence of LLM tokens where each is the only one allowed, including a final EOS token if
+    /// that's the only option. They can be committed without running the model. Leaves the state unchanged.
+    pub fn forced_tokens(&mut self) -> Vec<LLMTokenID> {
+        self.try_forced_tokens().unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `forced_tokens`, but returns an error instead of panicking.
+    pub fn try_forced_tokens(&mut self) -> Result<Vec<LLMTokenID>, Error> {
+        self.with_scratch_state(Self::take_forced_tokens)
+    }
+
+    fn take_forced_tokens(state: &mut Self) -> Result<Vec<LLMTokenID>, Error> {
+        let mut forced_tokens = Vec::new();
+        while forced_tokens.len() < MAX_FORCED_LEN {
+            let mask = state.try_get_mask()?;
+            if mask.count_ones() != 1 {
+                break;
+            }
+            let llm_token_id = LLMTokenID(mask.first_one().unwrap());
+            forced_tokens.push(llm_token_id);
+            state.try_commit(llm_token_id)?;
+        }
+        Ok(forced_tokens)
+    }
+
+    /// Runs `f`, then restores the state to what it was before, even if `f` fails. This avoids cloning `parent`.
+    pub(crate) fn with_scratch_state<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        let snapshot = self.snapshot();
+        let result = f(self);
+        self.restore(snapshot);
+        result
+    }
+
+    /// Returns the parts of the state that change as tokens are committed.
+    pub(crate) fn snapshot(&self) -> GrammarConstraintSnapshot {
+        (self.states.clone(), self.mode.clone(), self.healing_prefix.clone())
+    }
+
+    pub(crate) fn restore(&mut self, snapshot: GrammarConstraintSnapshot) {
+        (self.states, self.mode, self.healing_prefix) = snapshot;
+    }
+
+    /// Whether the grammar stops applying at the next token if that token doesn't continue it.
+    fn may_finish(&self) -> bool {
+        self.finish_when_complete && self.fully_matches()
+    }
+
+    /// Moves to `states`, the states after the latest input. If the input didn't continue a complete grammar, the
+    /// grammar ends before it instead, as it does once nothing can continue it.
+    fn set_states_or_finish(&mut self, states: Vec<(ParseState, BTreeSet<StateID>)>) -> Result<(), Error> {
+        if states.is_empty() && self.may_finish() {
+            self.mode = ConstraintMode::Finished;
+            return Ok(());
+        }
+        self.states = states;
+        if self.may_finish() && !self.can_continue()? {
+            self.mode = ConstraintMode::Finished;
+        }
+        Ok(())
+    }
+
+    /// Returns true if some token other than EOS can continue the grammar.
+    fn can_continue(&self) -> Result<bool, Error> {
+        let mut mask = self.grammar_mask()?;
+        for eos_llm_token_id in &self.parent.special_tokens.eos_llm_token_ids {
+            mask.set(eos_llm_token_id.0, false);
+        }
+        Ok(mask.any())
+    }
+
+    /// Returns the states after `llm_token_id`, following the precompute tries `tries`, or the constraint's own
+    /// tries if `None`.
+    fn advance(&self, tries: Option<&BTreeMap<StateID, FrozenTrie<TokenID, FrozenPrecomputedNodeValue>>>, llm_token_id: LLMTokenID) -> Result<Vec<(ParseState, BTreeSet<StateID>)>, Error> {
         let mut new_states: BTreeMap<(ParseStateKey, BTreeSet<StateID>), ParseState> = BTreeMap::new();
         for (parse_state, tokenizer_state_ids) in &self.states {
             for tokenizer_state_id in tokenizer_state_ids {
                 // todo: should be able to do the below loop more efficiently by optimising the precomputed
                 //  stuff for earlier llm token lookup
-                TrieNode::special_map(
-                    Arc::new(Mutex::new(self.parent.precomputed[&tokenizer_state_id].clone())),
-                    vec![parse_state.clone()],
-                    // todo: it's messy that we need to access the value in dst_node here.
-                    |current_parse_states, token_id, dst_node| {
-                        // todo: this is introducing redundancy... ?
-                        let mut glr_parse_state = self.parent.parser.init_glr_parser_from_parse_states(current_parse_states.clone());
-                        glr_parse_state.step(TerminalID(*token_id));
-                        glr_parse_state.active_states
-                    },
-                    |mut parse_states: Vec<Vec<ParseState>>| {
-                        let mut all_parse_states = parse_states.pop().unwrap();
-                        for mut other_parse_state in parse_states {
-                            all_parse_states.append(&mut other_parse_state)
-                        }
-                        let mut new_glr_parse_state = self.parent.parser.init_glr_parser_from_parse_states(all_parse_states);
-                        new_glr_parse_state.merge_active_states();
-                        new_glr_parse_state.active_states
-                    },
+                let mut walk = |trie: &FrozenTrie<TokenID, FrozenPrecomputedNodeValue>| self.parent.walk_trie(
+                    trie,
+                    parse_state,
                     |(llm_token_id_to_state_id, _, _), current_parse_states| {
                         let mut new_glr_parse_state = self.parent.parser.init_glr_parser_from_parse_states(current_parse_states.clone());
                         if let Some(info) = llm_token_id_to_state_id.get(&llm_token_id) {
@@ -183,12 +821,16 @@ impl<'a, T: Tokenizer> GrammarConstraintState<T> {
                             }
                         }
                     },
-                )
+                );
+                match tries {
+                    Some(tries) => walk(tries.get(tokenizer_state_id).ok_or(Error::MissingTokenizerState(*tokenizer_state_id))?),
+                    None => self.parent.with_trie(*tokenizer_state_id, &mut walk)?,
+                }
             }
         }
-        self.states = new_states.into_iter().map(|((key, tokenizer_state_ids), parse_state)| {
+        Ok(new_states.into_iter().map(|((_, tokenizer_state_ids), parse_state)| {
             (parse_state, tokenizer_state_ids)
-        }).collect();
+        }).collect())
     }
 
     pub fn commit_many(&mut self, llm_token_ids: &[LLMTokenID]) {
@@ -0,0 +1,59 @@
+// src/error.rs
+use crate::glr::table::StateID;
+use crate::precompute::LLMTokenID;
+use crate::vocab::VocabError;
+use std::fmt::{Display, Formatter};
+
+/// Errors from building or driving a constraint. The `try_` constructors and methods return these for bad
+/// grammars, vocabularies and token IDs; their infallible counterparts panic with the same message.
+#[derive(Debug)]
+pub enum Error {
+    /// The grammar has no rules, refers to a rule that isn't defined, ignores one of its own terminals, or has a
+    /// lookahead that `ExprGroups::build` doesn't support.
+    InvalidGrammar(String),
+    /// A grammar token matches the empty string, so any text could be lexed into infinitely many grammar tokens.
+    EmptyMatchingTerminal(String),
+    /// An LLM token ID is larger than `max_llm_token_id`.
+    LLMTokenIDOutOfRange { llm_token_id: LLMTokenID, max_llm_token_id: usize },
+    /// A special token that stands for a grammar token is also in the text vocabulary.
+    SpecialTokenIsText(LLMTokenID),
+    /// An LLM token that must be text (e.g. in a prompt to heal) is a special token.
+    NotText(LLMTokenID),
+    /// The constraint reached a tokenizer state that nothing was precomputed for.
+    MissingTokenizerState(StateID),
+    /// Saved precompute tries (see `load_precomputed`) are truncated or corrupt.
+    InvalidSavedData(String),
+    Vocab(VocabError),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidGrammar(message) => write!(f, "invalid grammar: {}", message),
+            Error::EmptyMatchingTerminal(terminal) => write!(f, "grammar token {} matches the empty string", terminal),
+            Error::LLMTokenIDOutOfRange { llm_token_id, max_llm_token_id } => {
+                write!(f, "LLM token ID {} is larger than the maximum LLM token ID {}", llm_token_id.0, max_llm_token_id)
+            }
+            Error::SpecialTokenIsText(llm_token_id) => write!(f, "special LLM token {} is also a text token", llm_token_id.0),
+            Error::NotText(llm_token_id) => write!(f, "LLM token {} is not a text token", llm_token_id.0),
+            Error::MissingTokenizerState(state_id) => write!(f, "no precomputed trie for tokenizer state {}", state_id.0),
+            Error::InvalidSavedData(message) => write!(f, "invalid saved data: {}", message),
+            Error::Vocab(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Vocab(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<VocabError> for Error {
+    fn from(err: VocabError) -> Self {
+        Error::Vocab(err)
+    }
+}
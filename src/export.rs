@@ -0,0 +1,340 @@
+// src/export.rs
+//! DOT (Graphviz) and JSON exporters for the regex automata, the LR automaton and the precompute tries.
+//!
+//! Render DOT output with e.g. `dot -Tsvg automaton.dot -o automaton.svg`.
+use crate::finite_automata::{GroupID, DFA, NFA};
+use crate::glr::grammar::Symbol;
+use crate::glr::items::Item;
+use crate::glr::parser::GLRParser;
+use crate::glr::table::{StateID, Stage7ShiftsAndReduces};
+use crate::frozen_trie::FrozenTrie;
+use crate::precompute::{FrozenPrecomputedNodeValue, TokenID};
+use crate::compact_bitset::CompactBitSet;
+use crate::u8set::U8Set;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn json_bitset(bitset: &CompactBitSet) -> Value {
+    json!(bitset.iter_ones().collect::<Vec<_>>())
+}
+
+/// Transitions grouped by target (see `group_transitions`), as `{"bytes": [...], "to": target}` objects.
+fn json_transitions(transitions: impl IntoIterator<Item = (u8, usize)>) -> Value {
+    group_transitions(transitions).into_iter()
+        .map(|(target, u8set)| json!({"bytes": u8set.iter().collect::<Vec<_>>(), "to": target}))
+        .collect()
+}
+
+/// Groups transitions by target, so that each pair of states gets a single edge labelled with a byte set.
+fn group_transitions(transitions: impl IntoIterator<Item = (u8, usize)>) -> BTreeMap<usize, U8Set> {
+    let mut grouped: BTreeMap<usize, U8Set> = BTreeMap::new();
+    for (u8, target) in transitions {
+        grouped.entry(target).or_insert_with(U8Set::none).insert(u8);
+    }
+    grouped
+}
+
+fn finalizers_label(label: &mut String, name: &str, group_ids: &BTreeSet<GroupID>) {
+    if !group_ids.is_empty() {
+        write!(label, "\n{}: {:?}", name, group_ids).unwrap();
+    }
+}
+
+fn item_to_string(item: &Item) -> String {
+    let mut result = format!("{} ->", item.production.lhs.0);
+    for (i, symbol) in item.production.rhs.iter().enumerate() {
+        if i == item.dot_position {
+            result.push_str(" •");
+        }
+        match symbol {
+            Symbol::Terminal(terminal) => write!(result, " {:?}", terminal.0).unwrap(),
+            Symbol::NonTerminal(non_terminal) => write!(result, " {}", non_terminal.0).unwrap(),
+        }
+    }
+    if item.dot_position == item.production.rhs.len() {
+        result.push_str(" •");
+    }
+    result
+}
+
+impl NFA {
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph NFA {\n  rankdir=LR;\n  start [shape=point];\n");
+        writeln!(dot, "  start -> {};", self.start_state).unwrap();
+        for (state_index, state) in self.states.iter().enumerate() {
+            let mut label = state_index.to_string();
+            finalizers_label(&mut label, "final", &state.finalizers);
+            finalizers_label(&mut label, "non-greedy", &state.non_greedy_finalizers);
+            finalizers_label(&mut label, "eof", &state.eof_finalizers);
+            let shape = if state.finalizers.is_empty() && state.eof_finalizers.is_empty() { "circle" } else { "doublecircle" };
+            writeln!(dot, "  {} [shape={}, label=\"{}\"];", state_index, shape, dot_escape(&label)).unwrap();
+            let transitions = state.transitions.iter().flat_map(|(u8, targets)| targets.iter().map(move |&target| (u8, target)));
+            for (target, u8set) in group_transitions(transitions) {
+                writeln!(dot, "  {} -> {} [label=\"{}\"];", state_index, target, dot_escape(&u8set.to_string())).unwrap();
+            }
+            for target in &state.epsilon_transitions {
+                writeln!(dot, "  {} -> {} [label=\"ε\", style=dashed];", state_index, target).unwrap();
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    pub fn to_json(&self) -> String {
+        let states: Vec<Value> = self.states.iter().enumerate().map(|(state_index, state)| {
+            let transitions = state.transitions.iter().flat_map(|(u8, targets)| targets.iter().map(move |&target| (u8, target)));
+            json!({
+                "id": state_index,
+                "transitions": json_transitions(transitions),
+                "epsilon_transitions": state.epsilon_transitions,
+                "finalizers": state.finalizers,
+                "non_greedy_finalizers": state.non_greedy_finalizers,
+                "eof_finalizers": state.eof_finalizers,
+            })
+        }).collect();
+        json!({
+            "start_state": self.start_state,
+            "lookahead_group_ids": self.lookahead_group_ids,
+            "states": states,
+        }).to_string()
+    }
+}
+
+impl DFA {
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph DFA {\n  rankdir=LR;\n  start [shape=point];\n");
+        writeln!(dot, "  start -> {};", self.start_state).unwrap();
+        for (state_index, state) in self.states.iter().enumerate() {
+            let mut label = state_index.to_string();
+            finalizers_label(&mut label, "final", &state.finalizers);
+            finalizers_label(&mut label, "eof", &state.eof_finalizers);
+            let shape = if state.finalizers.is_empty() && state.eof_finalizers.is_empty() { "circle" } else { "doublecircle" };
+            writeln!(dot, "  {} [shape={}, label=\"{}\"];", state_index, shape, dot_escape(&label)).unwrap();
+            for (target, u8set) in group_transitions(state.transitions.iter().map(|(u8, &target)| (u8, target))) {
+                writeln!(dot, "  {} -> {} [label=\"{}\"];", state_index, target, dot_escape(&u8set.to_string())).unwrap();
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    pub fn to_json(&self) -> String {
+        let states: Vec<Value> = self.states.iter().enumerate().map(|(state_index, state)| {
+            json!({
+                "id": state_index,
+                "transitions": json_transitions(state.transitions.iter().map(|(u8, &target)| (u8, target))),
+                "finalizers": state.finalizers,
+                "eof_finalizers": state.eof_finalizers,
+                "possible_group_ids": state.possible_group_ids,
+            })
+        }).collect();
+        json!({
+            "start_state": self.start_state,
+            "non_greedy_finalizers": self.non_greedy_finalizers,
+            "lookahead_group_ids": self.lookahead_group_ids,
+            "states": states,
+        }).to_string()
+    }
+}
+
+/// An action on a terminal, as (terminal name, shift target, reduces as (nonterminal name, production ID, len)).
+type ExportedAction = (String, Option<StateID>, Vec<(String, usize, usize)>);
+
+impl GLRParser {
+    fn export_actions(&self, state_id: StateID) -> Vec<ExportedAction> {
+        let non_terminal_name = |non_terminal_id| self.non_terminal_map.get_by_right(non_terminal_id).unwrap().0.clone();
+        self.stage_7_table[&state_id].shifts_and_reduces.iter().map(|(terminal_id, action)| {
+            let terminal_name = self.terminal_map.get_by_right(terminal_id).unwrap().0.clone();
+            match action {
+                Stage7ShiftsAndReduces::Shift(next_state_id) => (terminal_name, Some(*next_state_id), vec![]),
+                Stage7ShiftsAndReduces::Reduce { production_id, nonterminal_id, len } => {
+                    (terminal_name, None, vec![(non_terminal_name(nonterminal_id), production_id.0, *len)])
+                }
+                Stage7ShiftsAndReduces::Split { shift, reduces } => {
+                    let reduces = reduces.iter()
+                        .flat_map(|(len, nt_id_to_prod_ids)| nt_id_to_prod_ids.iter().flat_map(move |(nt_id, prod_ids)| {
+                            prod_ids.iter().map(move |prod_id| (non_terminal_name(nt_id), prod_id.0, *len))
+                        }))
+                        .collect();
+                    (terminal_name, *shift, reduces)
+                }
+            }
+        }).collect()
+    }
+
+    /// Exports the LR item-set automaton. States with conflicts (i.e. where the GLR parser splits) are red.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph LR {\n  node [shape=box];\n  start [shape=point];\n");
+        writeln!(dot, "  start -> {};", self.start_state_id.0).unwrap();
+        for (&state_id, row) in &self.stage_7_table {
+            let mut label = format!("State {}\\l", state_id.0);
+            for item in self.item_set_map.get_by_right(&state_id).unwrap() {
+                write!(label, "{}\\l", dot_escape(&item_to_string(item))).unwrap();
+            }
+            let mut has_conflict = false;
+            for (terminal_name, shift, reduces) in self.export_actions(state_id) {
+                has_conflict |= reduces.len() + usize::from(shift.is_some()) > 1;
+                if let Some(next_state_id) = shift {
+                    writeln!(dot, "  {} -> {} [label=\"{}\"];", state_id.0, next_state_id.0, dot_escape(&format!("{:?}", terminal_name))).unwrap();
+                }
+                for (non_terminal_name, _, len) in reduces {
+                    write!(label, "{}", dot_escape(&format!("on {:?}: reduce {} (len {})", terminal_name, non_terminal_name, len))).unwrap();
+                    label.push_str("\\l");
+                }
+            }
+            let color = if has_conflict { ", color=red" } else { "" };
+            writeln!(dot, "  {} [label=\"{}\"{}];", state_id.0, label, color).unwrap();
+            for (non_terminal_id, next_state_id) in &row.gotos {
+                let non_terminal = self.non_terminal_map.get_by_right(non_terminal_id).unwrap();
+                writeln!(dot, "  {} -> {} [label=\"{}\", style=dashed];", state_id.0, next_state_id.0, dot_escape(&non_terminal.0)).unwrap();
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    pub fn to_json(&self) -> String {
+        let states: Vec<Value> = self.stage_7_table.iter().map(|(&state_id, row)| {
+            let items: Vec<String> = self.item_set_map.get_by_right(&state_id).unwrap().iter().map(item_to_string).collect();
+            let mut shifts = Vec::new();
+            let mut reduces = Vec::new();
+            for (terminal_name, shift, state_reduces) in self.export_actions(state_id) {
+                if let Some(next_state_id) = shift {
+                    shifts.push(json!({"terminal": terminal_name, "to": next_state_id.0}));
+                }
+                for (non_terminal_name, production_id, len) in state_reduces {
+                    reduces.push(json!({"terminal": terminal_name, "nonterminal": non_terminal_name, "production": production_id, "len": len}));
+                }
+            }
+            let gotos: Vec<Value> = row.gotos.iter().map(|(non_terminal_id, next_state_id)| {
+                let non_terminal = self.non_terminal_map.get_by_right(non_terminal_id).unwrap();
+                json!({"nonterminal": non_terminal.0, "to": next_state_id.0})
+            }).collect();
+            json!({"id": state_id.0, "items": items, "shifts": shifts, "reduces": reduces, "gotos": gotos})
+        }).collect();
+        json!({"start_state": self.start_state_id.0, "states": states}).to_string()
+    }
+}
+
+/// Exports the precompute tries, with one cluster per tokenizer state. Edges are labelled with grammar token IDs.
+/// Each node lists the LLM tokens that end there, with the tokenizer state they end in if they end mid-token.
+pub fn precomputed_to_dot(precomputed: &BTreeMap<StateID, FrozenTrie<TokenID, FrozenPrecomputedNodeValue>>) -> String {
+    let mut dot = String::from("digraph Precomputed {\n  node [shape=box];\n");
+    for (tokenizer_state, trie) in precomputed {
+        writeln!(dot, "  subgraph cluster_{} {{\n    label=\"Tokenizer state {}\";", tokenizer_state.0, tokenizer_state.0).unwrap();
+        for i in trie.nodes() {
+            let (llm_token_ends, possible_next_tokens, clean_end_llm_tokens) = trie.value(i);
+            let mut label = String::new();
+            for (llm_token_id, info) in llm_token_ends {
+                match info.dirty_end_state {
+                    Some(state) => write!(label, "LLM token {} -> state {}\\l", llm_token_id.0, state.0).unwrap(),
+                    None => write!(label, "LLM token {} (clean)\\l", llm_token_id.0).unwrap(),
+                }
+            }
+            for (token_id, bitset) in possible_next_tokens {
+                write!(label, "next token {}: {} LLM tokens\\l", token_id, bitset.count_ones()).unwrap();
+            }
+            if let Some(bitset) = clean_end_llm_tokens {
+                write!(label, "clean end: {} LLM tokens\\l", bitset.count_ones()).unwrap();
+            }
+            writeln!(dot, "    s{}_{} [label=\"{}\"];", tokenizer_state.0, i, label).unwrap();
+            for (token_id, child) in trie.children(i) {
+                writeln!(dot, "    s{}_{} -> s{}_{} [label=\"{}\"];", tokenizer_state.0, i, tokenizer_state.0, child, token_id).unwrap();
+            }
+        }
+        dot.push_str("  }\n");
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+pub fn precomputed_to_json(precomputed: &BTreeMap<StateID, FrozenTrie<TokenID, FrozenPrecomputedNodeValue>>) -> String {
+    let tries: Vec<Value> = precomputed.iter().map(|(tokenizer_state, trie)| {
+        let nodes: Vec<Value> = trie.nodes().into_iter().map(|i| {
+            let (llm_token_ends, possible_next_tokens, clean_end_llm_tokens) = trie.value(i);
+            let llm_token_ends: Vec<Value> = llm_token_ends.iter().map(|(llm_token_id, info)| json!({
+                "llm_token": llm_token_id.0,
+                "position": info.position_in_llm_token,
+                "dirty_end_state": info.dirty_end_state.map(|state| state.0),
+            })).collect();
+            let possible_next_tokens: serde_json::Map<String, Value> = possible_next_tokens.iter()
+                .map(|(token_id, bitset)| (token_id.to_string(), json_bitset(bitset)))
+                .collect();
+            let children: Vec<Value> = trie.children(i).map(|(token_id, child)| json!({"token": token_id, "node": child})).collect();
+            json!({
+                "id": i,
+                "llm_token_ends": llm_token_ends,
+                "possible_next_tokens": possible_next_tokens,
+                "clean_end_llm_tokens": clean_end_llm_tokens.as_ref().map(|bitset| json_bitset(bitset)),
+                "children": children,
+            })
+        }).collect();
+        json!({"tokenizer_state": tokenizer_state.0, "root": trie.root(), "nodes": nodes})
+    }).collect();
+    Value::from(tries).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::finite_automata::{eat_u8, eat_u8_set, rep1, Expr};
+    use crate::glr::grammar::NonTerminal;
+    use crate::interface::{choice, regex, sequence, Grammar};
+    use crate::precompute::{freeze_precomputed, precompute, LLMTokenID};
+    use crate::groups;
+    use bimap::BiBTreeMap;
+
+    #[test]
+    fn test_export() {
+        let expr_groups = groups![
+            Expr::U8Seq(b"if".to_vec()),
+            rep1(eat_u8_set(U8Set::from_range(b'a', b'z'))),
+            eat_u8(b'"'),
+        ];
+        let nfa = expr_groups.clone().build_nfa();
+        let tokenizer = expr_groups.build();
+
+        let dot = tokenizer.dfa.to_dot();
+        assert!(dot.starts_with("digraph DFA {"));
+        assert!(dot.contains("0 -> 1 [label=\"['\\\"']\"]"), "{}", dot);
+        assert_eq!(dot.matches(" -> ").count(), 1 + tokenizer.dfa.states.iter().map(|state| group_transitions(state.transitions.iter().map(|(u8, &target)| (u8, target))).len()).sum::<usize>());
+        let json: Value = serde_json::from_str(&tokenizer.dfa.to_json()).unwrap();
+        assert_eq!(json["start_state"], 0);
+        assert_eq!(json["states"].as_array().unwrap().len(), tokenizer.dfa.states.len());
+        assert!(json["states"][0]["transitions"].as_array().unwrap().contains(&json!({"bytes": [b'"'], "to": 1})), "{}", json);
+        assert!(nfa.to_dot().contains("style=dashed"));
+        let json: Value = serde_json::from_str(&nfa.to_json()).unwrap();
+        assert_eq!(json["start_state"], nfa.start_state);
+
+        let grammar = Grammar::from_exprs(vec![
+            ("S".to_string(), choice(vec![regex(eat_u8(b'a')), sequence(vec![regex(eat_u8(b'a')), regex(eat_u8(b'b'))])])),
+        ]);
+        let parser = grammar.glr_parser();
+        let dot = parser.to_dot();
+        assert!(dot.contains("start' -> • S") && dot.contains("reduce S (len 1)"), "{}", dot);
+        let s_id = parser.non_terminal_map.get_by_left(&NonTerminal("S".to_string())).unwrap();
+        let goto_s = parser.stage_7_table[&parser.start_state_id].gotos[s_id];
+        let json: Value = serde_json::from_str(&parser.to_json()).unwrap();
+        let start_state = json["states"].as_array().unwrap().iter().find(|state| state["id"] == parser.start_state_id.0).unwrap();
+        assert!(start_state["gotos"].as_array().unwrap().contains(&json!({"nonterminal": "S", "to": goto_s.0})), "{}", json);
+        // Items quote their terminal names, which must survive escaping.
+        let items: Vec<&str> = json["states"].as_array().unwrap().iter()
+            .flat_map(|state| state["items"].as_array().unwrap())
+            .map(|item| item.as_str().unwrap())
+            .collect();
+        assert!(items.contains(&"start' -> • S") && items.iter().any(|item| item.contains('"')), "{:?}", items);
+
+        let llm_token_map: BiBTreeMap<Vec<u8>, LLMTokenID> = [(b"a".to_vec(), LLMTokenID(0)), (b"ab".to_vec(), LLMTokenID(1))].into_iter().collect();
+        let precomputed = freeze_precomputed(&precompute(&grammar.tokenizer, &llm_token_map, LLMTokenID(2), 2).unwrap());
+        assert!(precomputed_to_dot(&precomputed).contains("subgraph cluster_0"));
+        let json: Value = serde_json::from_str(&precomputed_to_json(&precomputed)).unwrap();
+        assert_eq!(json[0]["tokenizer_state"], 0);
+        let llm_token_ends: Vec<&Value> = json[0]["nodes"].as_array().unwrap().iter().flat_map(|node| node["llm_token_ends"].as_array().unwrap()).collect();
+        assert!(llm_token_ends.contains(&&json!({"llm_token": 1, "position": 2, "dirty_end_state": null})), "{}", json);
+    }
+}
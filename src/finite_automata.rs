@@ -1,29 +1,34 @@
 use crate::charmap::TrieMap;
-use crate::frozenset::FrozenSet;
+use crate::error::Error;
 use crate::u8set::U8Set;
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt::{Debug, Formatter};
 
 pub type GroupID = usize;
 
 #[derive(Debug, Clone)]
 pub struct NFAState {
-    transitions: TrieMap<Vec<usize>>,
-    epsilon_transitions: Vec<usize>,
-    finalizers: BTreeSet<GroupID>,
-    non_greedy_finalizers: BTreeSet<GroupID>,
+    pub(crate) transitions: TrieMap<Vec<usize>>,
+    pub(crate) epsilon_transitions: Vec<usize>,
+    pub(crate) finalizers: BTreeSet<GroupID>,
+    pub(crate) non_greedy_finalizers: BTreeSet<GroupID>,
+    pub(crate) eof_finalizers: BTreeSet<GroupID>,
 }
 
 #[derive(Clone)]
 pub struct NFA {
-    states: Vec<NFAState>,
-    start_state: usize,
+    pub(crate) states: Vec<NFAState>,
+    pub(crate) start_state: usize,
+    pub(crate) lookahead_group_ids: BTreeSet<GroupID>,
 }
 
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct DFAState {
     pub transitions: TrieMap<usize>,
     pub finalizers: BTreeSet<GroupID>,
+    /// Groups that match (ending at the current position) if the input ends here. Only used by groups
+    /// with a trailing negative lookahead, which is satisfied at the end of the input.
+    pub eof_finalizers: BTreeSet<GroupID>,
     pub possible_group_ids: BTreeSet<GroupID>,
     pub group_id_to_u8set: BTreeMap<GroupID, U8Set>,
 }
@@ -33,6 +38,9 @@ pub struct DFA {
     pub states: Vec<DFAState>,
     pub start_state: usize,
     pub non_greedy_finalizers: BTreeSet<GroupID>,
+    /// Groups with a trailing lookahead. Their finalizers are reached one byte after the match ends,
+    /// once the lookahead byte has been checked.
+    pub lookahead_group_ids: BTreeSet<GroupID>,
 }
 
 // TODO: should this *really* derive `Clone`? Users probably shouldn't clone this, should they?
@@ -70,6 +78,11 @@ pub enum Expr {
     Choice(Vec<Expr>),
     Seq(Vec<Expr>),
     Epsilon, // Explicit epsilon transition
+    Lookahead(Box<Expr>),         // (?=...), only allowed at the end of a group
+    NegativeLookahead(Box<Expr>), // (?!...), only allowed at the end of a group
+    And(Box<Expr>, Box<Expr>),    // Strings matched by both
+    Not(Box<Expr>),               // Strings not matched
+    Diff(Box<Expr>, Box<Expr>),   // Strings matched by the first but not the second
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, PartialOrd, Ord, Hash)]
@@ -126,6 +139,35 @@ pub fn opt<T: Into<Expr>>(expr: T) -> Expr {
     Expr::Quantifier(Box::new(expr.into()), QuantifierType::ZeroOrOne)
 }
 
+/// Asserts that the next byte matches `expr`, without consuming it. `expr` must match exactly one byte.
+///
+/// Lookahead is bounded to a single byte so that a match ending at an LLM token boundary is always
+/// decided by the first byte of the next LLM token.
+pub fn lookahead<T: Into<Expr>>(expr: T) -> Expr {
+    Expr::Lookahead(Box::new(expr.into()))
+}
+
+/// Asserts that the next byte does not match `expr` (or that the input ends), without consuming it.
+/// `expr` must match exactly one byte.
+pub fn negative_lookahead<T: Into<Expr>>(expr: T) -> Expr {
+    Expr::NegativeLookahead(Box::new(expr.into()))
+}
+
+/// Matches the strings matched by both `a` and `b`.
+pub fn and<A: Into<Expr>, B: Into<Expr>>(a: A, b: B) -> Expr {
+    Expr::And(Box::new(a.into()), Box::new(b.into()))
+}
+
+/// Matches every string not matched by `expr` (including the empty string, if `expr` doesn't match it).
+pub fn not<T: Into<Expr>>(expr: T) -> Expr {
+    Expr::Not(Box::new(expr.into()))
+}
+
+/// Matches the strings matched by `a` but not by `b`.
+pub fn diff<A: Into<Expr>, B: Into<Expr>>(a: A, b: B) -> Expr {
+    Expr::Diff(Box::new(a.into()), Box::new(b.into()))
+}
+
 pub fn prec<T: Into<Expr>>(_precedence: isize, expr: T) -> ExprGroup {
     ExprGroup { expr: expr.into(), is_non_greedy: false }
 }
@@ -199,6 +241,10 @@ impl Debug for NFA {
             if !state.non_greedy_finalizers.is_empty() {
                 f.write_str(&format!("  - Non-Greedy Finalizers: {:?}\n", state.non_greedy_finalizers))?;
             }
+
+            if !state.eof_finalizers.is_empty() {
+                f.write_str(&format!("  - EOF Finalizers: {:?}\n", state.eof_finalizers))?;
+            }
         }
 
         Ok(())
@@ -220,6 +266,10 @@ impl Debug for DFA {
                 f.write_str(&format!("  - Finalizers: {:?}\n", state.finalizers))?;
             }
 
+            if !state.eof_finalizers.is_empty() {
+                f.write_str(&format!("  - EOF Finalizers: {:?}\n", state.eof_finalizers))?;
+            }
+
             if !state.possible_group_ids.is_empty() {
                 f.write_str(&format!("  - Possible Group IDs: {:?}\n", state.possible_group_ids))?;
             }
@@ -243,6 +293,7 @@ impl NFAState {
             epsilon_transitions: Vec::new(),
             finalizers: BTreeSet::new(),
             non_greedy_finalizers: BTreeSet::new(),
+            eof_finalizers: BTreeSet::new(),
         }
     }
 }
@@ -254,14 +305,44 @@ impl ExprGroups {
         Regex { dfa }
     }
 
-    fn build_nfa(self) -> NFA {
+    /// Like `build`, but returns an error instead of panicking if a group has a lookahead assertion anywhere but
+    /// at its end, or one that doesn't match exactly one byte.
+    pub fn try_build(self) -> Result<Regex, Error> {
+        self.validate_lookaheads()?;
+        Ok(self.build())
+    }
+
+    /// Checks that every lookahead assertion is one that `build` supports.
+    pub fn validate_lookaheads(&self) -> Result<(), Error> {
+        for (group, ExprGroup { expr, .. }) in self.groups.iter().enumerate() {
+            expr.validate_lookaheads(true).map_err(|message| Error::InvalidGrammar(format!("group {}: {}", group, message)))?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn build_nfa(self) -> NFA {
         let mut nfa = NFA {
             states: vec![NFAState::new()],
             start_state: 0,
+            lookahead_group_ids: BTreeSet::new(),
         };
 
         for (group, ExprGroup { expr, is_non_greedy }) in self.groups.into_iter().enumerate() {
-            let end_state = Expr::handle_expr(expr, &mut nfa, 0);
+            let (expr, lookahead) = expr.split_trailing_lookahead();
+            let mut end_state = Expr::handle_expr(expr, &mut nfa, 0);
+            if let Some((next_u8set, matches_at_eof)) = lookahead {
+                // Consume the lookahead byte and finalize after it. The match position is corrected back
+                // by one byte when the finalizer is reached (see `RegexState::execute`).
+                let lookahead_state = nfa.add_state();
+                for ch in next_u8set.iter() {
+                    nfa.add_transition(end_state, ch, lookahead_state);
+                }
+                if matches_at_eof {
+                    nfa.states[end_state].eof_finalizers.insert(group);
+                }
+                nfa.lookahead_group_ids.insert(group);
+                end_state = lookahead_state;
+            }
             if is_non_greedy {
                 nfa.states[end_state].finalizers.insert(group);
                 // Additionally, track that this finalizer is non-greedy
@@ -280,6 +361,70 @@ impl Expr {
         ExprGroups { groups: vec![ExprGroup { expr: self, is_non_greedy: false }] }.build()
     }
 
+    /// Splits a trailing lookahead assertion off the end of a group's expression. Returns the rest of
+    /// the expression, and if there was a lookahead, the set of bytes allowed to follow the match and
+    /// whether the end of the input is allowed to follow it.
+    fn split_trailing_lookahead(self) -> (Expr, Option<(U8Set, bool)>) {
+        match self {
+            Expr::Lookahead(expr) => {
+                (Expr::Epsilon, Some((expr.lookahead_u8set(), false)))
+            }
+            Expr::NegativeLookahead(expr) => {
+                (Expr::Epsilon, Some((expr.lookahead_u8set().complement(), true)))
+            }
+            Expr::Seq(mut exprs) => {
+                let Some(last) = exprs.pop() else {
+                    return (Expr::Seq(exprs), None);
+                };
+                let (last, lookahead) = last.split_trailing_lookahead();
+                exprs.push(last);
+                (Expr::Seq(exprs), lookahead)
+            }
+            expr => (expr, None),
+        }
+    }
+
+    /// Returns an error if this expression has a lookahead that `split_trailing_lookahead` wouldn't split off the
+    /// end of a group (where `at_end` says whether this expression ends the group), or that isn't a single byte.
+    fn validate_lookaheads(&self, at_end: bool) -> Result<(), String> {
+        match self {
+            Expr::Lookahead(expr) | Expr::NegativeLookahead(expr) => {
+                if !at_end {
+                    Err("lookahead assertions are only supported at the end of a group".to_string())
+                } else if expr.single_u8_u8set().is_none() {
+                    Err(format!("lookahead must match exactly one byte, got {:?}", expr))
+                } else {
+                    Ok(())
+                }
+            }
+            Expr::Seq(exprs) => {
+                let last = exprs.len().saturating_sub(1);
+                exprs.iter().enumerate().try_for_each(|(i, expr)| expr.validate_lookaheads(at_end && i == last))
+            }
+            Expr::Choice(exprs) => exprs.iter().try_for_each(|expr| expr.validate_lookaheads(false)),
+            Expr::Quantifier(expr, _) | Expr::Not(expr) => expr.validate_lookaheads(false),
+            Expr::And(a, b) | Expr::Diff(a, b) => {
+                a.validate_lookaheads(false)?;
+                b.validate_lookaheads(false)
+            }
+            Expr::U8Seq(_) | Expr::U8Class(_) | Expr::Epsilon => Ok(()),
+        }
+    }
+
+    fn lookahead_u8set(&self) -> U8Set {
+        self.single_u8_u8set().unwrap_or_else(|| panic!("Lookahead must match exactly one byte, got {:?}", self))
+    }
+
+    fn single_u8_u8set(&self) -> Option<U8Set> {
+        match self {
+            Expr::U8Seq(u8s) if u8s.len() == 1 => Some(U8Set::from_u8(u8s[0])),
+            Expr::U8Class(u8s) => Some(*u8s),
+            Expr::Choice(exprs) => exprs.iter().try_fold(U8Set::none(), |u8set, expr| Some(u8set.union(&expr.single_u8_u8set()?))),
+            Expr::Seq(exprs) if exprs.len() == 1 => exprs[0].single_u8_u8set(),
+            _ => None,
+        }
+    }
+
     fn handle_expr(expr: Expr, nfa: &mut NFA, mut current_state: usize) -> usize {
         match expr {
             Expr::U8Seq(u8s) => {
@@ -320,17 +465,20 @@ impl Expr {
                         loop_end_state
                     }
                     QuantifierType::OneOrMore => {
+                        // Use a fresh state to loop back to. Looping back to `current_state` itself would also
+                        // loop back into anything else that leaves `current_state` (e.g. other groups at the
+                        // NFA start state).
                         let loop_start_state = nfa.add_state();
 
+                        // Epsilon transition from current state to loop start state
+                        nfa.add_epsilon_transition(current_state, loop_start_state);
+
                         // Process the expr first to ensure at least one occurrence
-                        let expr_end_state = Self::handle_expr(*expr, nfa, current_state);
+                        let expr_end_state = Self::handle_expr(*expr, nfa, loop_start_state);
 
                         // Epsilon transition from expr end state back to loop start state for repetition
                         nfa.add_epsilon_transition(expr_end_state, loop_start_state);
 
-                        // Epsilon transition from loop start state back to expr start state to allow repetition
-                        nfa.add_epsilon_transition(loop_start_state, current_state);
-
                         // The expr end state becomes the new current state
                         expr_end_state
                     }
@@ -384,8 +532,100 @@ impl Expr {
                 nfa.add_epsilon_transition(current_state, new_state);
                 new_state
             }
+            Expr::Lookahead(_) | Expr::NegativeLookahead(_) => {
+                panic!("Lookahead assertions are only supported at the end of a group");
+            }
+            Expr::And(a, b) => {
+                let dfa = a.into_dfa().product(&b.into_dfa(), |a, b| a && b);
+                nfa.add_dfa(&dfa, current_state)
+            }
+            Expr::Not(expr) => {
+                let dfa = rep(Expr::U8Class(U8Set::all())).into_dfa().product(&expr.into_dfa(), |a, b| a && !b);
+                nfa.add_dfa(&dfa, current_state)
+            }
+            Expr::Diff(a, b) => {
+                let dfa = a.into_dfa().product(&b.into_dfa(), |a, b| a && !b);
+                nfa.add_dfa(&dfa, current_state)
+            }
         }
     }
+
+    /// Builds a minimized DFA for this expression on its own, with a single group 0.
+    fn into_dfa(self) -> DFA {
+        let mut nfa = NFA {
+            states: vec![NFAState::new()],
+            start_state: 0,
+            lookahead_group_ids: BTreeSet::new(),
+        };
+        let end_state = Self::handle_expr(self, &mut nfa, 0);
+        nfa.states[end_state].finalizers.insert(0);
+        let mut dfa = nfa.to_dfa();
+        dfa.minimize();
+        dfa
+    }
+}
+
+/// A set of NFA state IDs with O(1) insertion and clearing (Briggs & Torczon), used to collect the NFA states
+/// reached from a DFA state without allocating a new set for each transition.
+struct SparseSet {
+    dense: Vec<usize>,
+    sparse: Vec<usize>,
+}
+
+impl SparseSet {
+    fn new(capacity: usize) -> Self {
+        SparseSet { dense: Vec::new(), sparse: vec![0; capacity] }
+    }
+
+    fn contains(&self, value: usize) -> bool {
+        let index = self.sparse[value];
+        index < self.dense.len() && self.dense[index] == value
+    }
+
+    fn insert(&mut self, value: usize) {
+        if !self.contains(value) {
+            self.sparse[value] = self.dense.len();
+            self.dense.push(value);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.dense.is_empty()
+    }
+
+    fn clear(&mut self) {
+        self.dense.clear();
+    }
+
+    fn to_sorted_vec(&self) -> Vec<usize> {
+        let mut values = self.dense.clone();
+        values.sort_unstable();
+        values
+    }
+}
+
+/// Partitions the bytes into classes that every transition map treats the same way (i.e. bytes in a class
+/// lead to the same place in every map). Bytes that no map uses form their own class.
+pub(crate) fn byte_classes<'a, T: Ord + 'a>(transition_maps: impl Iterator<Item = &'a TrieMap<T>>) -> Vec<U8Set> {
+    let mut class_of = [0usize; 256];
+    for transitions in transition_maps {
+        let mut refined_classes: BTreeMap<(usize, Option<&T>), usize> = BTreeMap::new();
+        let mut new_class_of = [0usize; 256];
+        for byte in 0..=255u8 {
+            let next_class = refined_classes.len();
+            new_class_of[byte as usize] = *refined_classes
+                .entry((class_of[byte as usize], transitions.get(byte)))
+                .or_insert(next_class);
+        }
+        class_of = new_class_of;
+    }
+
+    let num_classes = class_of.iter().max().unwrap() + 1;
+    let mut classes = vec![U8Set::none(); num_classes];
+    for byte in 0..=255u8 {
+        classes[class_of[byte as usize]].insert(byte);
+    }
+    classes
 }
 
 impl NFA {
@@ -407,87 +647,95 @@ impl NFA {
         self.states[from].epsilon_transitions.push(to);
     }
 
+    /// Adds a copy of a single-group DFA starting from `current_state`. Returns the end state, which is reached
+    /// by an epsilon transition from every accepting state. States that can't reach an accepting state are left out.
+    fn add_dfa(&mut self, dfa: &DFA, current_state: usize) -> usize {
+        let end_state = self.add_state();
+        let state_mapping: BTreeMap<usize, usize> = dfa.states.iter().enumerate()
+            .filter(|(_, state)| !state.possible_group_ids.is_empty())
+            .map(|(dfa_state, _)| (dfa_state, self.add_state()))
+            .collect();
+        for (&dfa_state, &nfa_state) in &state_mapping {
+            for (u8, next_dfa_state) in &dfa.states[dfa_state].transitions {
+                if let Some(&next_nfa_state) = state_mapping.get(next_dfa_state) {
+                    self.add_transition(nfa_state, u8, next_nfa_state);
+                }
+            }
+            if !dfa.states[dfa_state].finalizers.is_empty() {
+                self.add_epsilon_transition(nfa_state, end_state);
+            }
+        }
+        if let Some(&start_state) = state_mapping.get(&dfa.start_state) {
+            self.add_epsilon_transition(current_state, start_state);
+        }
+        end_state
+    }
+
     pub fn to_dfa(self) -> DFA {
+        let epsilon_closures: Vec<Vec<usize>> = self.compute_epsilon_closures()
+            .into_iter()
+            .map(|closure| closure.into_iter().collect())
+            .collect();
+        // Bytes that no NFA state distinguishes lead to the same DFA state, so only one byte per class is explored.
+        let byte_classes = byte_classes(self.states.iter().map(|state| &state.transitions));
+
         let mut dfa_states: Vec<DFAState> = Vec::new();
-        let mut dfa_state_map: BTreeMap<FrozenSet<usize>, usize> = BTreeMap::new();
-        let mut worklist: Vec<FrozenSet<usize>> = Vec::new();
-
-        let mut epsilon_closures = self.compute_epsilon_closures();
-
-        // Compute the epsilon closure of the NFA start state and use it as the DFA start state
-        let start_closure = epsilon_closures[self.start_state].clone();
-        let start_state_set = FrozenSet::from_iter(start_closure.iter().cloned());
-        worklist.push(start_state_set.clone());
-        dfa_state_map.insert(start_state_set.clone(), 0);
-
-        // Initialize the first DFA state
-        let closure = epsilon_closures[self.start_state].clone();
-        let mut finalizers = BTreeSet::new();
-        let mut non_greedy_finalizers = BTreeSet::new();
-        for &state in &closure {
-            finalizers.extend(self.states[state].finalizers.iter().cloned());
-            non_greedy_finalizers.extend(self.states[state].non_greedy_finalizers.iter().cloned());
-        }
+        let mut nfa_state_sets: Vec<Vec<usize>> = Vec::new();
+        let mut dfa_state_map: HashMap<Vec<usize>, usize> = HashMap::new();
+        let mut worklist: Vec<usize> = Vec::new();
 
-        dfa_states.push(DFAState {
-            transitions: TrieMap::new(),
-            finalizers,
-            possible_group_ids: BTreeSet::new(), // Will be computed later
-            group_id_to_u8set: BTreeMap::new(),  // Will be computed later
-        });
+        let mut add_dfa_state = |nfa_state_set: Vec<usize>, dfa_states: &mut Vec<DFAState>, nfa_state_sets: &mut Vec<Vec<usize>>, worklist: &mut Vec<usize>| {
+            if let Some(&existing_state) = dfa_state_map.get(&nfa_state_set) {
+                return existing_state;
+            }
+            let mut finalizers = BTreeSet::new();
+            let mut eof_finalizers = BTreeSet::new();
+            for &state in &nfa_state_set {
+                finalizers.extend(self.states[state].finalizers.iter().cloned());
+                eof_finalizers.extend(self.states[state].eof_finalizers.iter().cloned());
+            }
+            let new_state_index = dfa_states.len();
+            dfa_states.push(DFAState {
+                transitions: TrieMap::new(),
+                finalizers,
+                eof_finalizers,
+                possible_group_ids: BTreeSet::new(), // Will be computed later
+                group_id_to_u8set: BTreeMap::new(),  // Will be computed later
+            });
+            dfa_state_map.insert(nfa_state_set.clone(), new_state_index);
+            nfa_state_sets.push(nfa_state_set);
+            worklist.push(new_state_index);
+            new_state_index
+        };
 
-        while let Some(current_set) = worklist.pop() {
-            let current_dfa_state = *dfa_state_map.get(&current_set).unwrap();
-            let mut transition_map: BTreeMap<u8, BTreeSet<usize>> = BTreeMap::new();
-
-            // For each state in the current DFA state, look at the NFA transitions
-            for &state in current_set.iter() {
-                for (input, next_states) in &self.states[state].transitions {
-                    for &next_state in next_states {
-                        transition_map
-                            .entry(input)
-                            .or_insert_with(BTreeSet::new)
-                            .insert(next_state);
+        // Use the epsilon closure of the NFA start state as the DFA start state
+        let start_state_set = epsilon_closures[self.start_state].clone();
+        add_dfa_state(start_state_set, &mut dfa_states, &mut nfa_state_sets, &mut worklist);
+
+        let mut next_states = SparseSet::new(self.states.len());
+        while let Some(current_dfa_state) = worklist.pop() {
+            for byte_class in &byte_classes {
+                let representative = byte_class.iter().next().unwrap();
+
+                // Collect the epsilon closure of everything reachable from the current set on this byte class
+                next_states.clear();
+                for &state in &nfa_state_sets[current_dfa_state] {
+                    if let Some(targets) = self.states[state].transitions.get(representative) {
+                        for &target in targets {
+                            for &closure_state in &epsilon_closures[target] {
+                                next_states.insert(closure_state);
+                            }
+                        }
                     }
                 }
-            }
-
-            // For each transition, compute the epsilon closure of the resulting state set
-            for (&input_u8, next_states) in &transition_map {
-                let mut closure = BTreeSet::new();
-                for &next_state in next_states {
-                    closure.extend(&epsilon_closures[next_state]);
+                if next_states.is_empty() {
+                    continue;
                 }
-                let frozen_closure = FrozenSet::from_iter(closure.iter().cloned());
-
-                // If this set of states is new, add it as a new DFA state
-                let next_dfa_state = if let Some(&existing_state) = dfa_state_map.get(&frozen_closure) {
-                    existing_state
-                } else {
-                    let new_state_index = dfa_states.len();
-                    dfa_state_map.insert(frozen_closure.clone(), new_state_index);
-                    worklist.push(frozen_closure.clone());
-
-                    // Compute finalizers for the new DFA state
-                    let mut new_finalizers = BTreeSet::new();
-                    let mut new_non_greedy_finalizers = BTreeSet::new();
-                    for &state in closure.iter() {
-                        new_finalizers.extend(self.states[state].finalizers.iter().cloned());
-                        new_non_greedy_finalizers.extend(self.states[state].non_greedy_finalizers.iter().cloned());
-                    }
-
-                    dfa_states.push(DFAState {
-                        transitions: TrieMap::new(),
-                        finalizers: new_finalizers,
-                        possible_group_ids: BTreeSet::new(), // Will be computed later
-                        group_id_to_u8set: BTreeMap::new(),  // Will be computed later
-                    });
-
-                    new_state_index
-                };
 
-                // Insert the transition into the DFA state
-                dfa_states[current_dfa_state].transitions.insert(input_u8, next_dfa_state);
+                let next_dfa_state = add_dfa_state(next_states.to_sorted_vec(), &mut dfa_states, &mut nfa_state_sets, &mut worklist);
+                for byte in byte_class.iter() {
+                    dfa_states[current_dfa_state].transitions.insert(byte, next_dfa_state);
+                }
             }
         }
 
@@ -495,6 +743,7 @@ impl NFA {
             states: dfa_states,
             start_state: 0,
             non_greedy_finalizers: BTreeSet::new(),
+            lookahead_group_ids: self.lookahead_group_ids.clone(),
         };
 
         for state in &self.states {
@@ -520,7 +769,7 @@ impl NFA {
         closure
     }
 
-    fn compute_epsilon_closures(&self) -> Vec<BTreeSet<usize>> {
+    pub(crate) fn compute_epsilon_closures(&self) -> Vec<BTreeSet<usize>> {
         (0..self.states.len())
             .map(|state| self.epsilon_closure(state))
             .collect()
@@ -529,29 +778,29 @@ impl NFA {
 
 impl DFA {
     pub fn compute_possible_group_ids(&mut self) {
+        // Propagate finalizers backwards along transitions until nothing changes.
+        let mut predecessors: Vec<Vec<usize>> = vec![Vec::new(); self.states.len()];
+        for (state_index, state) in self.states.iter().enumerate() {
+            for (_input, &next_state_index) in &state.transitions {
+                predecessors[next_state_index].push(state_index);
+            }
+        }
+
         for state in &mut self.states {
             state.possible_group_ids = state.finalizers.clone();
         }
 
-        loop {
-            let mut changed = false;
-            for state_index in 0..self.states.len() {
-                let state = self.states[state_index].clone();
-                for (_input, &next_state_index) in &state.transitions {
-                    let next_possible_groups = self.states[next_state_index].possible_group_ids.clone();
-                    let state_possible_groups = &mut self.states[state_index].possible_group_ids;
-
-                    let old_len = state_possible_groups.len();
-                    state_possible_groups.extend(next_possible_groups.iter());
-
-                    if state_possible_groups.len() > old_len {
-                        changed = true;
-                    }
+        let mut worklist: Vec<usize> = (0..self.states.len()).collect();
+        while let Some(state_index) = worklist.pop() {
+            let possible_groups = self.states[state_index].possible_group_ids.clone();
+            for &predecessor in &predecessors[state_index] {
+                let predecessor_possible_groups = &mut self.states[predecessor].possible_group_ids;
+                let old_len = predecessor_possible_groups.len();
+                predecessor_possible_groups.extend(possible_groups.iter());
+                if predecessor_possible_groups.len() > old_len {
+                    worklist.push(predecessor);
                 }
             }
-            if !changed {
-                break;
-            }
         }
     }
 
@@ -581,148 +830,216 @@ impl DFA {
         }
     }
 
-    fn remove_unreachable_states(&mut self) {
-        // Find reachable states using BFS
-        let mut reachable = vec![false; self.states.len()];
-        let mut queue = vec![self.start_state];
-        reachable[self.start_state] = true;
+    /// Runs two single-group DFAs in lockstep. A state of the result accepts if `accept` returns true for
+    /// whether each of the two DFAs accepts. A DFA with no transition for a byte stays rejecting from then on.
+    fn product(&self, other: &DFA, accept: impl Fn(bool, bool) -> bool) -> DFA {
+        let mut states: Vec<DFAState> = Vec::new();
+        let mut state_map: BTreeMap<(Option<usize>, Option<usize>), usize> = BTreeMap::new();
+        let mut worklist: Vec<(Option<usize>, Option<usize>)> = Vec::new();
+
+        let is_final = |dfa: &DFA, state: Option<usize>| state.is_some_and(|state| dfa.ends_match(state));
+        let mut add_state = |pair: (Option<usize>, Option<usize>), states: &mut Vec<DFAState>, worklist: &mut Vec<_>| {
+            *state_map.entry(pair).or_insert_with(|| {
+                states.push(DFAState {
+                    transitions: TrieMap::new(),
+                    finalizers: if accept(is_final(self, pair.0), is_final(other, pair.1)) { BTreeSet::from([0]) } else { BTreeSet::new() },
+                    eof_finalizers: BTreeSet::new(),
+                    possible_group_ids: BTreeSet::new(), // Will be computed later
+                    group_id_to_u8set: BTreeMap::new(),  // Will be computed later
+                });
+                worklist.push(pair);
+                states.len() - 1
+            })
+        };
 
-        while let Some(state) = queue.pop() {
-            for &next_state in self.states[state].transitions.values() {
-                if !reachable[next_state] {
-                    reachable[next_state] = true;
-                    queue.push(next_state);
-                }
+        let start = (Some(self.start_state), Some(other.start_state));
+        add_state(start, &mut states, &mut worklist);
+        while let Some(pair) = worklist.pop() {
+            let current_state = add_state(pair, &mut states, &mut worklist);
+            let transitions = |dfa: &DFA, state: Option<usize>| state.map(|state| dfa.states[state].transitions.clone()).unwrap_or_default();
+            let (a_transitions, b_transitions) = (transitions(self, pair.0), transitions(other, pair.1));
+            let u8s = a_transitions.keys_as_u8set().union(&b_transitions.keys_as_u8set());
+            for u8 in u8s.iter() {
+                let next_pair = (a_transitions.get(u8).copied(), b_transitions.get(u8).copied());
+                let next_state = add_state(next_pair, &mut states, &mut worklist);
+                states[current_state].transitions.insert(u8, next_state);
             }
         }
 
-        // Create mapping from old indices to new indices
-        let mut state_mapping = vec![0; self.states.len()];
-        let mut new_index = 0;
-        for (old_index, &is_reachable) in reachable.iter().enumerate() {
-            if is_reachable {
-                state_mapping[old_index] = new_index;
-                new_index += 1;
-            }
-        }
+        let mut dfa = DFA {
+            states,
+            start_state: 0,
+            non_greedy_finalizers: BTreeSet::new(),
+            lookahead_group_ids: BTreeSet::new(),
+        };
+        dfa.compute_possible_group_ids();
+        dfa.minimize();
+        dfa
+    }
 
-        // Keep only reachable states and update transitions
-        let mut new_states = Vec::new();
-        for (old_index, state) in self.states.iter().enumerate() {
-            if reachable[old_index] {
-                let mut new_state = state.clone();
-                // Update transitions to use new state indices
-                new_state.transitions = new_state.transitions
-                    .iter()
-                    .map(|(u8, &next)| (u8, state_mapping[next]))
-                    .collect();
-                new_states.push(new_state);
+    /// Returns true if a match of some group ends in `state`. A lookahead group's match ends in the state before its
+    /// finalizer, since the finalizer is only reached once the lookahead byte has been checked.
+    fn ends_match(&self, state: usize) -> bool {
+        let is_lookahead_group = |group_id: &GroupID| self.lookahead_group_ids.contains(group_id);
+        self.states[state].finalizers.iter().any(|group_id| !is_lookahead_group(group_id))
+            || !self.states[state].eof_finalizers.is_empty()
+            || self.states[state].transitions.iter().any(|(_, &next_state)| self.states[next_state].finalizers.iter().any(is_lookahead_group))
+    }
+
+    /// Returns the shortest string that fully matches some group (the lexicographically smallest, if there are
+    /// several), or `None` if no string does. The lookahead of a lookahead group isn't part of its match.
+    fn shortest_match(&self) -> Option<Vec<u8>> {
+        let mut parents: BTreeMap<usize, Option<(usize, u8)>> = BTreeMap::from([(self.start_state, None)]);
+        let mut queue = std::collections::VecDeque::from([self.start_state]);
+        while let Some(state) = queue.pop_front() {
+            if self.ends_match(state) {
+                let mut witness = Vec::new();
+                let mut current_state = state;
+                while let Some((parent, u8)) = parents[&current_state] {
+                    witness.push(u8);
+                    current_state = parent;
+                }
+                witness.reverse();
+                return Some(witness);
+            }
+            for (u8, &next_state) in &self.states[state].transitions {
+                if let std::collections::btree_map::Entry::Vacant(entry) = parents.entry(next_state) {
+                    entry.insert(Some((state, u8)));
+                    queue.push_back(next_state);
+                }
             }
         }
-
-        // Update the DFA
-        self.states = new_states;
-        // Start state remains at 0 since it's always reachable
-        self.start_state = 0;
+        None
     }
 
+    /// Minimizes the DFA with Hopcroft's partition refinement, over byte classes rather than individual bytes.
+    /// States that can't lead to any match are dropped, along with the transitions into them.
     fn minimize(&mut self) {
         if self.states.is_empty() {
             return;
         }
 
-        // Step 1: Create initial partition based on finalizers and transitions
-        let mut partitions = BTreeMap::<(BTreeSet<GroupID>, BTreeMap<u8, usize>), BTreeSet<usize>>::new();
+        // Add an explicit dead state, so that every state has a transition for every byte class.
+        let num_states = self.states.len();
+        let dead_state = num_states;
+        let byte_classes = byte_classes(self.states.iter().map(|state| &state.transitions));
+        let target = |state: usize, byte_class: &U8Set| -> usize {
+            if state == dead_state {
+                return dead_state;
+            }
+            let representative = byte_class.iter().next().unwrap();
+            self.states[state].transitions.get(representative).copied().unwrap_or(dead_state)
+        };
+
+        // predecessors[class][state] = states that transition to `state` on `class`
+        let mut predecessors: Vec<Vec<Vec<usize>>> = vec![vec![Vec::new(); num_states + 1]; byte_classes.len()];
+        for (class_index, byte_class) in byte_classes.iter().enumerate() {
+            for state in 0..=num_states {
+                predecessors[class_index][target(state, byte_class)].push(state);
+            }
+        }
 
-        for (state_idx, state) in self.states.iter().enumerate() {
-            let key = (
-                state.finalizers.clone(),
-                state.transitions.iter().map(|(u8, &next)| (u8, next)).collect()
-            );
-            partitions.entry(key).or_default().insert(state_idx);
+        // Step 1: Create the initial partition based on finalizers
+        let mut initial_blocks: BTreeMap<(&BTreeSet<GroupID>, &BTreeSet<GroupID>), Vec<usize>> = BTreeMap::new();
+        let no_groups = BTreeSet::new();
+        for state in 0..=num_states {
+            let key = if state == dead_state {
+                (&no_groups, &no_groups)
+            } else {
+                (&self.states[state].finalizers, &self.states[state].eof_finalizers)
+            };
+            initial_blocks.entry(key).or_default().push(state);
+        }
+        let mut blocks: Vec<Vec<usize>> = initial_blocks.into_values().collect();
+        let mut block_of = vec![0; num_states + 1];
+        for (block_index, block) in blocks.iter().enumerate() {
+            for &state in block {
+                block_of[state] = block_index;
+            }
         }
 
-        // Step 2: Refine partitions until no more refinement is possible
-        let mut partition_list: Vec<BTreeSet<usize>> = partitions.into_values().collect();
-        let mut changed = true;
-        while changed {
-            changed = false;
-            let mut new_partitions = Vec::new();
-
-            for partition in &partition_list {
-                let mut refined_partitions = BTreeMap::new();
-
-                for &state in partition {
-                    let mut signature = BTreeMap::new();
-
-                    // For each transition, record which partition it leads to
-                    for (u8, &next_state) in &self.states[state].transitions {
-                        let target_partition = new_partitions.iter()
-                            .chain(partition_list.iter())
-                            .position(|p| p.contains(&next_state))
-                            .unwrap_or(usize::MAX);
-                        signature.insert(u8, target_partition);
+        // Step 2: Refine the partition until no block can be split
+        let mut worklist: Vec<usize> = (0..blocks.len()).collect();
+        let mut in_worklist = vec![true; blocks.len()];
+        let mut in_preimage = vec![false; num_states + 1];
+        while let Some(splitter_index) = worklist.pop() {
+            in_worklist[splitter_index] = false;
+            let splitter = blocks[splitter_index].clone();
+            for class_predecessors in &predecessors {
+                // The states that move into the splitter on this byte class, grouped by their block
+                let mut preimage_by_block: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+                for &state in &splitter {
+                    for &predecessor in &class_predecessors[state] {
+                        if !in_preimage[predecessor] {
+                            in_preimage[predecessor] = true;
+                            preimage_by_block.entry(block_of[predecessor]).or_default().push(predecessor);
+                        }
                     }
+                }
 
-                    refined_partitions.entry(signature)
-                        .or_insert_with(BTreeSet::new)
-                        .insert(state);
+                for (block_index, preimage) in &preimage_by_block {
+                    if preimage.len() < blocks[*block_index].len() {
+                        let new_block_index = blocks.len();
+                        blocks[*block_index].retain(|state| !in_preimage[*state]);
+                        for &state in preimage {
+                            block_of[state] = new_block_index;
+                        }
+                        blocks.push(preimage.clone());
+                        in_worklist.push(false);
+
+                        // Only the smaller half needs to be used as a splitter, unless the block was already queued
+                        let to_queue = if in_worklist[*block_index] || preimage.len() < blocks[*block_index].len() {
+                            new_block_index
+                        } else {
+                            *block_index
+                        };
+                        if !in_worklist[to_queue] {
+                            in_worklist[to_queue] = true;
+                            worklist.push(to_queue);
+                        }
+                    }
                 }
 
-                if refined_partitions.len() > 1 {
-                    changed = true;
-                    new_partitions.extend(refined_partitions.into_values());
-                } else {
-                    new_partitions.push(partition.clone());
+                for preimage in preimage_by_block.values() {
+                    for &state in preimage {
+                        in_preimage[state] = false;
+                    }
                 }
             }
-
-            partition_list = new_partitions;
-        }
-
-        // Step 3: Build the minimized DFA
-        let mut state_mapping = vec![0; self.states.len()];
-
-        // Find which partition contains the start state
-        let start_partition_idx = partition_list.iter()
-            .position(|p| p.contains(&self.start_state))
-            .unwrap();
-
-        // Ensure the start partition is first in the list
-        if start_partition_idx != 0 {
-            partition_list.swap(0, start_partition_idx);
         }
 
-        // Build state mapping
-        for (new_state, partition) in partition_list.iter().enumerate() {
-            for &old_state in partition {
-                state_mapping[old_state] = new_state;
+        // Step 3: Build the minimized DFA from the blocks reachable from the start state, keeping the start
+        // state at index 0 and leaving out the dead block
+        let dead_block = block_of[dead_state];
+        let mut new_state_of_block: BTreeMap<usize, usize> = BTreeMap::from([(block_of[self.start_state], 0)]);
+        let mut representatives = vec![self.start_state];
+        let mut queue = vec![self.start_state];
+        while let Some(old_state) = queue.pop() {
+            for (_u8, &next) in &self.states[old_state].transitions {
+                let next_block = block_of[next];
+                if next_block != dead_block && !new_state_of_block.contains_key(&next_block) {
+                    new_state_of_block.insert(next_block, representatives.len());
+                    representatives.push(next);
+                    queue.push(next);
+                }
             }
         }
 
-        let mut new_states = Vec::with_capacity(partition_list.len());
-        for partition in &partition_list {
-            let old_state = partition.iter().next().unwrap();
-            let mut new_state = self.states[*old_state].clone();
-
-            // Update transitions according to the new state mapping
+        self.states = representatives.iter().map(|&old_state| {
+            let mut new_state = self.states[old_state].clone();
             new_state.transitions = new_state.transitions
                 .iter()
-                .map(|(u8, &next)| (u8, state_mapping[next]))
+                .filter_map(|(u8, &next)| new_state_of_block.get(&block_of[next]).map(|&new_next| (u8, new_next)))
                 .collect();
-
-            new_states.push(new_state);
-        }
-
-        // The start state should now be at index 0
+            new_state
+        }).collect();
         self.start_state = 0;
-        self.states = new_states;
-        self.remove_unreachable_states();
 
         // Recompute metadata
         self.compute_possible_group_ids();
         self.compute_group_id_to_u8set();
     }
+
 }
 
 impl RegexState<'_> {
@@ -741,11 +1058,17 @@ impl RegexState<'_> {
                 local_position += 1;
                 // Handle greedy finalizers
                 for &group_id in &dfa.states[self.current_state].finalizers {
+                    // Groups with a lookahead are finalized after the lookahead byte, which isn't part of the match.
+                    let position = if dfa.lookahead_group_ids.contains(&group_id) {
+                        self.position + local_position - 1
+                    } else {
+                        self.position + local_position
+                    };
                     if dfa.non_greedy_finalizers.contains(&group_id) {
-                        self.matches.entry(group_id).or_insert(self.position + local_position);
+                        self.matches.entry(group_id).or_insert(position);
                     } else {
                         // Overwrite existing match for greedy groups
-                        self.matches.insert(group_id, self.position + local_position);
+                        self.matches.insert(group_id, position);
                     }
                 }
 
@@ -779,6 +1102,21 @@ impl RegexState<'_> {
         self.done = true;
     }
 
+    /// Records the matches that are only decided by the end of the input (i.e. groups with a trailing
+    /// negative lookahead), and marks the state as done.
+    pub fn end_of_input(&mut self) {
+        if !self.done {
+            for &group_id in &self.regex.dfa.states[self.current_state].eof_finalizers {
+                if self.regex.dfa.non_greedy_finalizers.contains(&group_id) {
+                    self.matches.entry(group_id).or_insert(self.position);
+                } else {
+                    self.matches.insert(group_id, self.position);
+                }
+            }
+        }
+        self.end();
+    }
+
     pub fn ended(&self) -> bool {
         self.done
     }
@@ -821,11 +1159,11 @@ impl RegexState<'_> {
                 // Didn't end. We must have run out of input.
                 // If we're supposed to terminate, add the final match (if any) and terminate.
                 if terminate {
+                    self.end_of_input();
                     if let Some(m) = self.get_greedy_match() {
                         // Add the final match to the list of successful matches.
                         matches.push(m);
                     }
-                    self.end();
                     return matches;
                 }
                 // Return the successful matches.
@@ -960,9 +1298,11 @@ impl RegexState<'_> {
 impl Regex {
     pub fn init_to_state(&self, state: usize) -> RegexState {
         let done = self.dfa.states[state].transitions.is_empty();
+        // Finalizers of lookahead groups in the initial state belong to a match that ended before this input.
         let matches = self.dfa.states[state]
             .finalizers
             .iter()
+            .filter(|group_id| !self.dfa.lookahead_group_ids.contains(group_id))
             .map(|&group_id| (group_id, 0))
             .collect();
         RegexState {
@@ -1015,12 +1355,56 @@ impl Regex {
     pub fn could_fully_match(&self, text: &[u8]) -> bool {
         self.fully_matches(text).unwrap_or(true)
     }
+
+    // The queries below treat a regex as the set of strings that fully match any of its groups. Lookahead
+    // assertions are not taken into account: a lookahead group matches the text before its lookahead, whatever
+    // follows, and witnesses don't include the lookahead byte.
+
+    /// Returns true if no string fully matches this regex.
+    pub fn is_empty(&self) -> bool {
+        self.match_witness().is_none()
+    }
+
+    /// Returns true if some string fully matches both regexes.
+    pub fn intersects(&self, other: &Regex) -> bool {
+        self.intersection_witness(other).is_some()
+    }
+
+    /// Returns true if every string that fully matches this regex also fully matches `other`.
+    pub fn is_subset_of(&self, other: &Regex) -> bool {
+        self.difference_witness(other).is_none()
+    }
+
+    /// Returns true if both regexes fully match the same strings.
+    pub fn equivalent(&self, other: &Regex) -> bool {
+        self.symmetric_difference_witness(other).is_none()
+    }
+
+    /// Returns the shortest string that fully matches this regex, if any.
+    pub fn match_witness(&self) -> Option<Vec<u8>> {
+        self.dfa.shortest_match()
+    }
+
+    /// Returns the shortest string that fully matches both regexes, if any.
+    pub fn intersection_witness(&self, other: &Regex) -> Option<Vec<u8>> {
+        self.dfa.product(&other.dfa, |a, b| a && b).shortest_match()
+    }
+
+    /// Returns the shortest string that fully matches this regex but not `other`, if any.
+    pub fn difference_witness(&self, other: &Regex) -> Option<Vec<u8>> {
+        self.dfa.product(&other.dfa, |a, b| a && !b).shortest_match()
+    }
+
+    /// Returns the shortest string that fully matches exactly one of the regexes, if any.
+    pub fn symmetric_difference_witness(&self, other: &Regex) -> Option<Vec<u8>> {
+        self.dfa.product(&other.dfa, |a, b| a != b).shortest_match()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{choice, seq};
+    use crate::{choice, groups, seq};
 
     #[test]
     fn test_literal() {
@@ -1131,6 +1515,190 @@ mod tests {
         assert!(!regex.could_match(b"b"));
         assert!(!regex.could_match(b"ba"));
     }
+
+    #[test]
+    fn test_one_or_more_does_not_loop_into_other_groups() {
+        // Repeating the first group must not loop back into the shared start state, where the second group begins.
+        let regex = groups![rep1(eat_u8(b'a')), eat_u8(b' ')].build();
+        dbg!(&regex);
+
+        let mut state = regex.init();
+        state.execute(b"a ");
+        assert_eq!(state.matches, BTreeMap::from([(0, 1)]));
+        assert!(state.done());
+
+        assert_eq!(regex.init().possible_group_ids(), BTreeSet::from([0, 1]));
+        let mut state = regex.init();
+        state.execute(b"aa");
+        assert_eq!(state.possible_group_ids(), BTreeSet::from([0]));
+    }
+
+    #[test]
+    fn test_lookahead() {
+        // Group 0: a name not followed by '('. Group 1: a name followed by '('.
+        let name = rep1(eat_u8_set(U8Set::from_range(b'a', b'z')));
+        let regex = groups![
+            seq![name.clone(), negative_lookahead(eat_u8(b'('))],
+            seq![name, lookahead(eat_u8(b'('))],
+        ].build();
+        dbg!(&regex);
+
+        // The lookahead byte isn't part of the match. ("fo" is a name not followed by '(', too.)
+        let mut state = regex.init();
+        state.execute(b"foo(");
+        assert_eq!(state.matches, BTreeMap::from([(0, 2), (1, 3)]));
+        assert_eq!(state.get_greedy_match(), Some(Match { group_id: 1, position: 3 }));
+
+        let mut state = regex.init();
+        state.execute(b"foo)");
+        assert_eq!(state.matches, BTreeMap::from([(0, 3)]));
+
+        // Without the next byte, neither group has matched "foo" yet...
+        let mut state = regex.init();
+        state.execute(b"foo");
+        assert_eq!(state.matches, BTreeMap::from([(0, 2)]));
+        assert_eq!(state.possible_group_ids(), BTreeSet::from([0, 1]));
+
+        // ...unless the input ends, which satisfies the negative lookahead.
+        let mut state = regex.init();
+        assert_eq!(state.greedy_find_all(b"foo", true), vec![Match { group_id: 0, position: 3 }]);
+    }
+
+    #[test]
+    fn test_and() {
+        // A number of at most three digits.
+        let any = eat_u8_set(U8Set::all());
+        let expr = and(rep1(eat_u8_set(U8Set::from_range(b'0', b'9'))), seq![any.clone(), opt(any.clone()), opt(any)]);
+        let regex = expr.build();
+        dbg!(&regex);
+
+        assert!(regex.definitely_fully_matches(b"1"));
+        assert!(regex.definitely_fully_matches(b"123"));
+        assert_eq!(regex.fully_matches(b"1234"), Some(false));
+        assert!(!regex.could_match(b"a"));
+    }
+
+    #[test]
+    fn test_not() {
+        let regex = not(Expr::U8Seq(b"ab".to_vec())).build();
+        dbg!(&regex);
+
+        assert!(regex.definitely_fully_matches(b""));
+        assert!(regex.definitely_fully_matches(b"a"));
+        assert!(regex.definitely_fully_matches(b"abc"));
+        assert!(regex.definitely_fully_matches(b"b"));
+        assert!(!regex.definitely_fully_matches(b"ab"));
+    }
+
+    #[test]
+    fn test_diff() {
+        // An identifier, but not a keyword.
+        let identifier = rep1(eat_u8_set(U8Set::from_range(b'a', b'z')));
+        let keyword = choice![Expr::U8Seq(b"if".to_vec()), Expr::U8Seq(b"in".to_vec())];
+        let regex = diff(identifier, keyword).build();
+        dbg!(&regex);
+
+        assert!(regex.definitely_fully_matches(b"i"));
+        assert!(regex.definitely_fully_matches(b"ifx"));
+        assert!(regex.definitely_fully_matches(b"x"));
+        assert!(!regex.definitely_fully_matches(b"if"));
+        assert!(!regex.definitely_fully_matches(b"in"));
+    }
+
+    #[test]
+    fn test_comment_without_terminator_inside() {
+        // "/*", then anything not containing "*/", then "*/".
+        let any = rep(eat_u8_set(U8Set::all()));
+        let expr = seq![
+            Expr::U8Seq(b"/*".to_vec()),
+            not(seq![any.clone(), Expr::U8Seq(b"*/".to_vec()), any]),
+            Expr::U8Seq(b"*/".to_vec()),
+        ];
+        let regex = expr.build();
+        dbg!(&regex);
+
+        let mut state = regex.init();
+        state.execute(b"/* a */ b */");
+        assert_eq!(state.matches, BTreeMap::from([(0, 7)]));
+        assert!(state.done());
+    }
+
+    #[test]
+    fn test_language_queries() {
+        let identifier = rep1(eat_u8_set(U8Set::from_range(b'a', b'z'))).build();
+        let keyword = Expr::U8Seq(b"if".to_vec()).build();
+        let number = rep1(eat_u8_set(U8Set::from_range(b'0', b'9'))).build();
+        let empty = and(eat_u8(b'a'), eat_u8(b'b')).build();
+
+        assert!(empty.is_empty());
+        assert_eq!(empty.match_witness(), None);
+        assert!(!identifier.is_empty());
+        assert_eq!(identifier.match_witness(), Some(b"a".to_vec()));
+
+        assert!(identifier.intersects(&keyword));
+        assert_eq!(identifier.intersection_witness(&keyword), Some(b"if".to_vec()));
+        assert!(!identifier.intersects(&number));
+        assert_eq!(identifier.intersection_witness(&number), None);
+
+        assert!(keyword.is_subset_of(&identifier));
+        assert_eq!(keyword.difference_witness(&identifier), None);
+        assert!(!identifier.is_subset_of(&keyword));
+        assert_eq!(identifier.difference_witness(&keyword), Some(b"a".to_vec()));
+
+        let identifier_2 = seq![eat_u8_set(U8Set::from_range(b'a', b'z')), rep(eat_u8_set(U8Set::from_range(b'a', b'z')))].build();
+        assert!(identifier.equivalent(&identifier_2));
+        assert_eq!(identifier.symmetric_difference_witness(&identifier_2), None);
+        assert!(!identifier.equivalent(&keyword));
+        assert_eq!(identifier.symmetric_difference_witness(&keyword), Some(b"a".to_vec()));
+
+        // The lookahead byte isn't part of the match.
+        let callee = groups![seq![rep1(eat_u8_set(U8Set::from_range(b'a', b'z'))), lookahead(eat_u8(b'('))]].build();
+        let name = groups![seq![rep1(eat_u8_set(U8Set::from_range(b'a', b'z'))), negative_lookahead(eat_u8(b'('))]].build();
+        assert_eq!(callee.match_witness(), Some(b"a".to_vec()));
+        assert_eq!(name.match_witness(), Some(b"a".to_vec()));
+        assert!(callee.equivalent(&identifier));
+        assert!(name.equivalent(&identifier));
+        assert_eq!(keyword.intersection_witness(&callee), Some(b"if".to_vec()));
+        assert_eq!(callee.difference_witness(&keyword), Some(b"a".to_vec()));
+    }
+
+    #[test]
+    fn test_minimize_merges_equivalent_states() {
+        // "ab" and "cb" share their suffix, so the states after 'a' and after 'c' are equivalent.
+        let regex = choice![Expr::U8Seq(b"ab".to_vec()), Expr::U8Seq(b"cb".to_vec())].build();
+        dbg!(&regex);
+        assert_eq!(regex.dfa.states.len(), 3);
+        assert!(regex.definitely_fully_matches(b"ab"));
+        assert!(regex.definitely_fully_matches(b"cb"));
+        assert!(!regex.could_match(b"b"));
+
+        // States that can't lead to a match are dropped.
+        let regex = seq![eat_u8(b'a'), and(eat_u8(b'b'), eat_u8(b'c'))].build();
+        assert_eq!(regex.dfa.states.len(), 1);
+        assert!(!regex.could_match(b"a"));
+    }
+
+    #[test]
+    #[should_panic(expected = "only supported at the end of a group")]
+    fn test_lookahead_not_at_end_of_group() {
+        seq![lookahead(eat_u8(b'a')), eat_u8(b'a')].build();
+    }
+
+    #[test]
+    fn test_try_build_invalid_lookaheads() {
+        let invalid = [
+            seq![lookahead(eat_u8(b'a')), eat_u8(b'a')],
+            rep(seq![eat_u8(b'a'), lookahead(eat_u8(b'b'))]),
+            and(seq![eat_u8(b'a'), lookahead(eat_u8(b'b'))], eat_u8(b'a')),
+            not(seq![eat_u8(b'a'), negative_lookahead(eat_u8(b'b'))]),
+            seq![eat_u8(b'a'), lookahead(Expr::U8Seq(b"bc".to_vec()))],
+        ];
+        for expr in invalid {
+            let result = groups![eat_u8(b'x'), expr.clone()].try_build();
+            assert!(matches!(result, Err(Error::InvalidGrammar(_))), "{:?}", expr);
+        }
+        assert!(groups![seq![eat_u8(b'a'), seq![eat_u8(b'b'), negative_lookahead(eat_u8(b'c'))]]].try_build().is_ok());
+    }
 }
 
 #[cfg(test)]
@@ -1934,12 +2502,14 @@ mod group_u8set_tests {
             states: Vec::new(),
             start_state: 0,
             non_greedy_finalizers: BTreeSet::new(), // Initialize here
+            lookahead_group_ids: BTreeSet::new(),
         };
 
         // State 0: Start state
         dfa.states.push(DFAState {
             transitions: TrieMap::new(),
             finalizers: BTreeSet::new(),
+            eof_finalizers: BTreeSet::new(),
             possible_group_ids: BTreeSet::new(), // Will be computed
             group_id_to_u8set: BTreeMap::new(),   // Will be computed
         });
@@ -1948,6 +2518,7 @@ mod group_u8set_tests {
         dfa.states.push(DFAState {
             transitions: TrieMap::new(),
             finalizers: BTreeSet::new(),
+            eof_finalizers: BTreeSet::new(),
             possible_group_ids: BTreeSet::new(),
             group_id_to_u8set: BTreeMap::new(),
         });
@@ -1956,6 +2527,7 @@ mod group_u8set_tests {
         dfa.states.push(DFAState {
             transitions: TrieMap::new(),
             finalizers: BTreeSet::from([0]),
+            eof_finalizers: BTreeSet::new(),
             possible_group_ids: BTreeSet::new(),
             group_id_to_u8set: BTreeMap::new(),
         });
@@ -1964,6 +2536,7 @@ mod group_u8set_tests {
         dfa.states.push(DFAState {
             transitions: TrieMap::new(),
             finalizers: BTreeSet::from([1]),
+            eof_finalizers: BTreeSet::new(),
             possible_group_ids: BTreeSet::new(),
             group_id_to_u8set: BTreeMap::new(),
         });
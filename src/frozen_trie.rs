@@ -0,0 +1,501 @@
+// src/frozen_trie.rs
+use crate::error::Error;
+use crate::trie::TrieNode;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+/// An immutable, index-based copy of a `TrieNode` DAG.
+///
+/// Nodes live in an arena and are identified by their index. The children of each node are a contiguous slice of
+/// the arena's edges (sorted by edge), and the number of parents of each node is precomputed, so traversals need
+/// neither locks nor pointer identity. A `FrozenTrie` is `Send + Sync` whenever `E` and `T` are.
+///
+/// Nodes are hash-consed: identical subtrees (same values and same edges to identical children) are stored once,
+/// including across all the tries frozen together by `FrozenTrie::freeze_all`, which then share one arena.
+#[derive(Debug, Clone)]
+pub struct FrozenTrie<E, T> {
+    arena: Arc<FrozenArena<E, T>>,
+    root: u32,
+    /// The number of parents within this trie of the nodes that have more than one. Every other node except the
+    /// root has one parent.
+    shared_num_parents: HashMap<u32, u32>,
+}
+
+#[derive(Debug)]
+struct FrozenArena<E, T> {
+    nodes: Vec<FrozenTrieNode<T>>,
+    /// The children of all nodes, as (edge, child index).
+    edges: Vec<(E, u32)>,
+}
+
+#[derive(Debug)]
+struct FrozenTrieNode<T> {
+    value: T,
+    children_start: u32,
+    children_end: u32,
+}
+
+impl<E: Ord + Clone + Hash, T: Clone + Hash + Eq> FrozenTrie<E, T> {
+    /// Copies the DAG reachable from `root`.
+    pub fn from_trie(root: &TrieNode<E, T>) -> Self {
+        Self::from_trie_with(root, T::clone)
+    }
+}
+
+impl<E: Ord + Clone + Hash, U: Hash + Eq> FrozenTrie<E, U> {
+    /// Like `from_trie`, but converts each node's value with `f`.
+    pub fn from_trie_with<T>(root: &TrieNode<E, T>, f: impl FnMut(&T) -> U) -> Self {
+        Self::freeze_roots(&[root], f).pop().unwrap()
+    }
+
+    /// Freezes several tries into a single arena, so that subtrees shared between them are only stored once.
+    pub fn freeze_all<K: Ord + Clone, T>(roots: &BTreeMap<K, TrieNode<E, T>>, f: impl FnMut(&T) -> U) -> BTreeMap<K, Self> {
+        let tries = Self::freeze_roots(&roots.values().collect::<Vec<_>>(), f);
+        roots.keys().cloned().zip(tries).collect()
+    }
+
+    /// Like `freeze_all`, but takes the tries one at a time and drops each as soon as it's frozen, so that when
+    /// `roots` builds them lazily, only one unfrozen trie exists at a time.
+    pub fn freeze_each<K: Ord, T>(roots: impl IntoIterator<Item = (K, TrieNode<E, T>)>, mut f: impl FnMut(&T) -> U) -> BTreeMap<K, Self> {
+        let mut builder = FrozenArenaBuilder::new();
+        let root_ids: Vec<(K, u32)> = roots.into_iter().map(|(key, root)| (key, builder.add_node(&mut HashMap::new(), &root, &mut f))).collect();
+
+        let arena = Arc::new(builder.arena);
+        root_ids.into_iter().map(|(key, root)| (key, Self::with_root(arena.clone(), root))).collect()
+    }
+
+    fn freeze_roots<T>(roots: &[&TrieNode<E, T>], mut f: impl FnMut(&T) -> U) -> Vec<Self> {
+        let mut builder = FrozenArenaBuilder::new();
+        let root_ids: Vec<u32> = roots.iter().map(|root| builder.add_node(&mut HashMap::new(), root, &mut f)).collect();
+
+        let arena = Arc::new(builder.arena);
+        root_ids.into_iter().map(|root| Self::with_root(arena.clone(), root)).collect()
+    }
+}
+
+impl<E, T> FrozenTrie<E, T> {
+    fn with_root(arena: Arc<FrozenArena<E, T>>, root: u32) -> Self {
+        let mut num_parents: HashMap<u32, u32> = HashMap::new();
+        let mut queue = VecDeque::from([root]);
+        while let Some(node) = queue.pop_front() {
+            let node = &arena.nodes[node as usize];
+            for &(_, child) in &arena.edges[node.children_start as usize..node.children_end as usize] {
+                let count = num_parents.entry(child).or_default();
+                *count += 1;
+                if *count == 1 {
+                    queue.push_back(child);
+                }
+            }
+        }
+        num_parents.retain(|_, &mut count| count > 1);
+        FrozenTrie { arena, root, shared_num_parents: num_parents }
+    }
+
+    /// Serializes `tries` into `out`, using `save_key`, `save_edge` and `save_value` for the parts of type `K`, `E`
+    /// and `T` (e.g. with `write_u64`). Tries that share an arena are saved with a single copy of it, so
+    /// `load_all` restores the sharing.
+    pub fn save_all<K>(
+        tries: &BTreeMap<K, Self>,
+        out: &mut Vec<u8>,
+        mut save_key: impl FnMut(&K, &mut Vec<u8>),
+        mut save_edge: impl FnMut(&E, &mut Vec<u8>),
+        mut save_value: impl FnMut(&T, &mut Vec<u8>),
+    ) {
+        let mut arenas: Vec<&Arc<FrozenArena<E, T>>> = Vec::new();
+        let arena_indices: Vec<usize> = tries.values().map(|trie| {
+            arenas.iter().position(|arena| Arc::ptr_eq(arena, &trie.arena)).unwrap_or_else(|| {
+                arenas.push(&trie.arena);
+                arenas.len() - 1
+            })
+        }).collect();
+
+        write_u64(out, arenas.len() as u64);
+        for arena in arenas {
+            write_u64(out, arena.nodes.len() as u64);
+            for node in &arena.nodes {
+                save_value(&node.value, out);
+                write_u64(out, (node.children_end - node.children_start) as u64);
+                for (edge, child) in &arena.edges[node.children_start as usize..node.children_end as usize] {
+                    save_edge(edge, out);
+                    write_u64(out, *child as u64);
+                }
+            }
+        }
+        write_u64(out, tries.len() as u64);
+        for ((key, trie), arena_index) in tries.iter().zip(arena_indices) {
+            save_key(key, out);
+            write_u64(out, arena_index as u64);
+            write_u64(out, trie.root as u64);
+        }
+    }
+
+    /// Reads tries written by `save_all` from the start of `input`, advancing it past them. Returns
+    /// `Error::InvalidSavedData` if the data is truncated or doesn't describe valid tries.
+    pub fn load_all<K: Ord>(
+        input: &mut &[u8],
+        mut load_key: impl FnMut(&mut &[u8]) -> Result<K, Error>,
+        mut load_edge: impl FnMut(&mut &[u8]) -> Result<E, Error>,
+        mut load_value: impl FnMut(&mut &[u8]) -> Result<T, Error>,
+    ) -> Result<BTreeMap<K, Self>, Error> where E: Ord {
+        let invalid = |message: &str| Error::InvalidSavedData(message.to_string());
+        let num_arenas = read_u64(input)?;
+        let mut arenas = Vec::new();
+        for _ in 0..num_arenas {
+            let num_nodes = read_u64(input)?;
+            let mut arena = FrozenArena { nodes: Vec::new(), edges: Vec::new() };
+            for node_id in 0..num_nodes {
+                let value = load_value(input)?;
+                let children_start = arena.edges.len() as u32;
+                for _ in 0..read_u64(input)? {
+                    let edge = load_edge(input)?;
+                    let child = read_u64(input)?;
+                    // `special_map` relies on children coming before their parents in the arena.
+                    if child >= node_id {
+                        return Err(invalid("a trie node's child comes after it"));
+                    }
+                    // `get` binary searches each node's edges.
+                    if arena.edges.len() as u32 > children_start && arena.edges.last().unwrap().0 >= edge {
+                        return Err(invalid("a trie node's edges aren't sorted and unique"));
+                    }
+                    arena.edges.push((edge, child as u32));
+                }
+                arena.nodes.push(FrozenTrieNode { value, children_start, children_end: arena.edges.len() as u32 });
+            }
+            arenas.push(Arc::new(arena));
+        }
+        let mut tries = BTreeMap::new();
+        for _ in 0..read_u64(input)? {
+            let key = load_key(input)?;
+            let arena = arenas.get(read_u64(input)? as usize).ok_or_else(|| invalid("a trie refers to a missing arena"))?;
+            let root = read_u64(input)?;
+            if root >= arena.nodes.len() as u64 {
+                return Err(invalid("a trie's root is out of range"));
+            }
+            tries.insert(key, Self::with_root(arena.clone(), root as u32));
+        }
+        Ok(tries)
+    }
+}
+
+/// Appends `value` in little-endian order. See `FrozenTrie::save_all`.
+pub fn write_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Reads a value written by `write_u64` from the start of `input`, advancing it.
+pub fn read_u64(input: &mut &[u8]) -> Result<u64, Error> {
+    let (bytes, rest) = input.split_first_chunk::<8>().ok_or_else(|| Error::InvalidSavedData("unexpected end of data".to_string()))?;
+    *input = rest;
+    Ok(u64::from_le_bytes(*bytes))
+}
+
+struct FrozenArenaBuilder<E, U> {
+    arena: FrozenArena<E, U>,
+    node_ids_by_hash: HashMap<u64, Vec<u32>>,
+}
+
+impl<E: Ord + Clone + Hash, U: Hash + Eq> FrozenArenaBuilder<E, U> {
+    fn new() -> Self {
+        FrozenArenaBuilder {
+            arena: FrozenArena { nodes: Vec::new(), edges: Vec::new() },
+            node_ids_by_hash: HashMap::new(),
+        }
+    }
+
+    /// Adds the subtree rooted at `node` (children first) and returns its ID. `node_ids` maps the `TrieNode`s
+    /// that have already been added to their IDs.
+    fn add_node<T>(&mut self, node_ids: &mut HashMap<*const TrieNode<E, T>, u32>, node: &TrieNode<E, T>, f: &mut impl FnMut(&T) -> U) -> u32 {
+        if let Some(&node_id) = node_ids.get(&(node as *const TrieNode<E, T>)) {
+            return node_id;
+        }
+        let children: Vec<(E, u32)> = node.children().iter()
+            .map(|(edge, child)| (edge.clone(), self.add_node(node_ids, &child.try_lock().unwrap(), f)))
+            .collect();
+        let value = f(&node.value);
+
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        children.hash(&mut hasher);
+        let candidates = self.node_ids_by_hash.entry(hasher.finish()).or_default();
+        let existing_node_id = candidates.iter().cloned().find(|&candidate| {
+            let candidate = &self.arena.nodes[candidate as usize];
+            candidate.value == value && self.arena.edges[candidate.children_start as usize..candidate.children_end as usize] == children[..]
+        });
+        let node_id = existing_node_id.unwrap_or_else(|| {
+            let node_id = self.arena.nodes.len() as u32;
+            let children_start = self.arena.edges.len() as u32;
+            self.arena.edges.extend(children);
+            self.arena.nodes.push(FrozenTrieNode { value, children_start, children_end: self.arena.edges.len() as u32 });
+            candidates.push(node_id);
+            node_id
+        });
+        node_ids.insert(node as *const TrieNode<E, T>, node_id);
+        node_id
+    }
+}
+
+impl<E: Ord, T> FrozenTrie<E, T> {
+    pub fn root(&self) -> usize {
+        self.root as usize
+    }
+
+    /// Returns the IDs of the nodes of this trie in breadth-first order, starting with the root.
+    pub fn nodes(&self) -> Vec<usize> {
+        let mut seen = HashSet::from([self.root as usize]);
+        let mut nodes = vec![self.root as usize];
+        let mut i = 0;
+        while i < nodes.len() {
+            for (_, child) in self.children(nodes[i]) {
+                if seen.insert(child) {
+                    nodes.push(child);
+                }
+            }
+            i += 1;
+        }
+        nodes
+    }
+
+    pub fn num_nodes(&self) -> usize {
+        self.nodes().len()
+    }
+
+    /// Returns, for each node of this trie, the number of paths from the root to it. Shared nodes are reached
+    /// through more than one edge sequence.
+    pub fn num_paths(&self) -> BTreeMap<usize, usize> {
+        let mut nodes = self.nodes();
+        // Children have smaller IDs than their parents, so this visits every parent before its children.
+        nodes.sort_unstable_by(|a, b| b.cmp(a));
+        let mut num_paths: BTreeMap<usize, usize> = BTreeMap::from([(self.root as usize, 1)]);
+        for node in nodes {
+            let node_paths = num_paths[&node];
+            for (_, child) in self.children(node) {
+                let child_paths = num_paths.entry(child).or_insert(0);
+                *child_paths = (*child_paths).saturating_add(node_paths);
+            }
+        }
+        num_paths
+    }
+
+    pub fn num_edges(&self) -> usize {
+        self.nodes().into_iter().map(|node| self.children(node).count()).sum()
+    }
+
+    /// Returns the number of nodes in the arena, which may be shared with other tries.
+    pub fn arena_num_nodes(&self) -> usize {
+        self.arena.nodes.len()
+    }
+
+    /// Returns true if `self` and `other` were frozen together and share an arena.
+    pub fn shares_arena_with(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.arena, &other.arena)
+    }
+
+    pub fn value(&self, node: usize) -> &T {
+        &self.arena.nodes[node].value
+    }
+
+    /// Returns the number of parents of `node` within this trie.
+    pub fn num_parents(&self, node: usize) -> usize {
+        if node == self.root() {
+            0
+        } else {
+            self.shared_num_parents.get(&(node as u32)).map_or(1, |&count| count as usize)
+        }
+    }
+
+    /// Returns the children of `node` as (edge, child ID), sorted by edge.
+    pub fn children(&self, node: usize) -> impl Iterator<Item = (&E, usize)> + '_ {
+        let node = &self.arena.nodes[node];
+        self.arena.edges[node.children_start as usize..node.children_end as usize]
+            .iter()
+            .map(|(edge, child)| (edge, *child as usize))
+    }
+
+    pub fn get(&self, node: usize, edge: &E) -> Option<usize> {
+        let node = &self.arena.nodes[node];
+        let children = &self.arena.edges[node.children_start as usize..node.children_end as usize];
+        children.binary_search_by(|(e, _)| e.cmp(edge)).ok().map(|i| children[i].1 as usize)
+    }
+
+    /// Like `TrieNode::special_map`: propagates a value from the root along the edges with `step`, merges the
+    /// values arriving at a node from all of its parents with `merge`, then calls `process` on the node. An edge
+    /// for which `step` returns `None` is pruned, and nodes that are only reachable through pruned edges are never
+    /// visited.
+    pub fn special_map<V: Clone>(
+        &self,
+        initial_value: V,
+        mut step: impl FnMut(&V, &E, &T) -> Option<V>,
+        mut merge: impl FnMut(Vec<V>) -> V,
+        mut process: impl FnMut(&T, &V),
+    ) {
+        // Children are added to the arena before their parents, so visiting the nodes from the largest ID down
+        // visits each node after all of its parents, whether or not the paths through them were pruned.
+        let mut next = Some((self.root(), initial_value));
+        let mut pending: BTreeMap<usize, Vec<V>> = BTreeMap::new();
+        while let Some((node, value)) = next.take().or_else(|| pending.pop_last().map(|(node, values)| (node, merge(values)))) {
+            process(self.value(node), &value);
+            for (edge, child) in self.children(node) {
+                debug_assert!(child < node);
+                if let Some(new_value) = step(&value, edge, self.value(child)) {
+                    pending.entry(child).or_default().push(new_value);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_frozen_trie() {
+        // a -> b -> d, a -> c -> d
+        let d = Arc::new(Mutex::new(TrieNode::new("d")));
+        let mut b = TrieNode::new("b");
+        b.insert(3, d.clone());
+        let mut c = TrieNode::new("c");
+        c.insert(4, d);
+        let mut a = TrieNode::new("a");
+        a.insert(2, Arc::new(Mutex::new(c)));
+        a.insert(1, Arc::new(Mutex::new(b)));
+
+        let frozen = FrozenTrie::from_trie(&a);
+        assert_eq!(frozen.num_nodes(), 4);
+        assert_eq!(frozen.num_edges(), 4);
+        let children: Vec<_> = frozen.children(frozen.root()).map(|(&edge, child)| (edge, *frozen.value(child))).collect();
+        assert_eq!(children, vec![(1, "b"), (2, "c")]);
+        let d = frozen.get(frozen.get(frozen.root(), &1).unwrap(), &3).unwrap();
+        assert_eq!(*frozen.value(d), "d");
+        assert_eq!(frozen.num_parents(d), 2);
+        assert_eq!(frozen.get(frozen.root(), &3), None);
+        let num_paths = frozen.num_paths();
+        assert_eq!(num_paths[&frozen.root()], 1);
+        assert_eq!(num_paths[&d], 2);
+
+        // Sum the edges along every path, merging by collecting all sums.
+        let mut sums = BTreeMap::new();
+        frozen.special_map(
+            vec![0],
+            |values, edge, _| Some(values.iter().map(|value| value + edge).collect()),
+            |values| values.concat(),
+            |node, values| { sums.insert(*node, values.clone()); },
+        );
+        assert_eq!(sums[&"d"].iter().copied().collect::<std::collections::BTreeSet<_>>(), [4, 6].into());
+
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+        assert_send_sync(&frozen);
+    }
+
+    #[test]
+    fn test_special_map_pruning() {
+        // a -> b -> d -> e, a -> c -> d
+        let mut d = TrieNode::new("d");
+        d.insert(5, Arc::new(Mutex::new(TrieNode::new("e"))));
+        let d = Arc::new(Mutex::new(d));
+        let mut b = TrieNode::new("b");
+        b.insert(3, d.clone());
+        let mut c = TrieNode::new("c");
+        c.insert(4, d);
+        let mut a = TrieNode::new("a");
+        a.insert(1, Arc::new(Mutex::new(b)));
+        a.insert(2, Arc::new(Mutex::new(c)));
+        let frozen = FrozenTrie::from_trie(&a);
+
+        let sums_with_pruned_edges = |pruned_edges: &[i32]| {
+            let mut sums = BTreeMap::new();
+            frozen.special_map(
+                vec![0],
+                |values, edge, _| (!pruned_edges.contains(edge)).then(|| values.iter().map(|value| value + edge).collect()),
+                |values| values.concat(),
+                |node, values| { sums.insert(*node, values.clone()); },
+            );
+            sums
+        };
+        // d is still reached through c, with only the value from that path.
+        let sums = sums_with_pruned_edges(&[1]);
+        assert_eq!(sums.keys().copied().collect::<Vec<_>>(), vec!["a", "c", "d", "e"]);
+        assert_eq!(sums[&"d"], vec![6]);
+        // Nodes below pruned edges are never visited.
+        let sums = sums_with_pruned_edges(&[3, 4]);
+        assert_eq!(sums.keys().copied().collect::<Vec<_>>(), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_freeze_all_shares_identical_subtrees() {
+        // x -> y -> z and w -> y' -> z', where y' and z' are copies of y and z.
+        let new_chain = |root_value| {
+            let mut y = TrieNode::new("y");
+            y.insert(2, Arc::new(Mutex::new(TrieNode::new("z"))));
+            let mut root = TrieNode::new(root_value);
+            root.insert(1, Arc::new(Mutex::new(y)));
+            root
+        };
+        // The copies of y within the same trie are shared too.
+        let mut w = new_chain("w");
+        w.insert(3, Arc::new(Mutex::new(new_chain("y").children()[&1].try_lock().unwrap().clone())));
+
+        let frozen = FrozenTrie::freeze_all(&BTreeMap::from([(0, new_chain("x")), (1, w.clone())]), |value| *value);
+        assert!(frozen[&0].shares_arena_with(&frozen[&1]));
+        assert_eq!(frozen[&0].num_nodes(), 3);
+        assert_eq!(frozen[&1].num_nodes(), 3);
+        // x, w, y and z.
+        assert_eq!(frozen[&0].arena_num_nodes(), 4);
+        let y = frozen[&1].get(frozen[&1].root(), &1).unwrap();
+        assert_eq!(frozen[&1].get(frozen[&1].root(), &3), Some(y));
+        assert_eq!(frozen[&1].num_parents(y), 2);
+        assert_eq!(frozen[&0].num_parents(y), 1);
+
+        // Freezing the tries one at a time shares just the same.
+        let each = FrozenTrie::freeze_each([(0, new_chain("x")), (1, w)], |value| *value);
+        assert!(each[&0].shares_arena_with(&each[&1]));
+        assert_eq!(each[&0].arena_num_nodes(), 4);
+        assert_eq!(each[&1].num_nodes(), 3);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let new_chain = |root_value: u64| {
+            let mut y = TrieNode::new(10);
+            y.insert(2, Arc::new(Mutex::new(TrieNode::new(20))));
+            let mut root = TrieNode::new(root_value);
+            root.insert(1, Arc::new(Mutex::new(y)));
+            root
+        };
+        let frozen = FrozenTrie::freeze_all(&BTreeMap::from([(0u64, new_chain(0)), (1, new_chain(1))]), |value| *value);
+
+        let mut saved = Vec::new();
+        FrozenTrie::save_all(&frozen, &mut saved, |&key, out| write_u64(out, key), |&edge, out| write_u64(out, edge), |&value, out| write_u64(out, value));
+        let load = |mut input: &[u8]| FrozenTrie::<u64, u64>::load_all(&mut input, read_u64, read_u64, read_u64);
+        let loaded = load(&saved).unwrap();
+        assert_eq!(loaded.keys().collect::<Vec<_>>(), frozen.keys().collect::<Vec<_>>());
+        assert!(loaded[&0].shares_arena_with(&loaded[&1]));
+        assert_eq!(loaded[&0].arena_num_nodes(), frozen[&0].arena_num_nodes());
+        for (key, trie) in &frozen {
+            let values = |trie: &FrozenTrie<u64, u64>| trie.nodes().into_iter()
+                .map(|node| (*trie.value(node), trie.children(node).map(|(&edge, child)| (edge, *trie.value(child))).collect::<Vec<_>>()))
+                .collect::<Vec<_>>();
+            assert_eq!(values(&loaded[key]), values(trie));
+        }
+
+        assert!(matches!(load(&saved[..saved.len() - 1]), Err(Error::InvalidSavedData(_))));
+
+        // A root with two leaf children. Its edges must be sorted and unique.
+        for (edges, valid) in [([3, 5], true), ([5, 3], false), ([3, 3], false)] {
+            let mut saved = Vec::new();
+            for value in [1, 3, 0, 0, 0, 0, 0, 2, edges[0], 0, edges[1], 1, 1, 0, 0, 2] {
+                write_u64(&mut saved, value);
+            }
+            let loaded = load(&saved);
+            assert_eq!(loaded.is_ok(), valid, "{:?}", edges);
+            if valid {
+                assert_eq!(loaded.unwrap()[&0].get(2, &5), Some(1));
+            } else {
+                assert!(matches!(loaded, Err(Error::InvalidSavedData(_))));
+            }
+        }
+    }
+}
@@ -181,4 +181,80 @@ pub fn compute_follow_sets(
     }
 
     follow_sets
-}
\ No newline at end of file
+}
+
+/// Rewrites `productions` so that no production starts with a nullable non-terminal, without changing the language.
+/// The start production comes first in the result. It's kept as is if it's the only production of a non-terminal
+/// that no production refers to (e.g. `start' -> S`), and otherwise a new one is added.
+///
+/// An empty production at the start of a production is reduced before anything is shifted. With hidden left
+/// recursion (e.g. `S -> A S "a"`, `A -> ε`), that reduce leads back to the same item set, so a GLR parser without
+/// cycles in its stack would keep reducing it at the same input position. Here, each nullable non-terminal `A` gets
+/// a copy `A+` for its non-empty strings, with `A -> ε | A+`. A production `B -> A1 .. Ak C ..`, where `A1`..`Ak`
+/// are nullable and `C` isn't, becomes `B -> Ai+ A(i+1) .. Ak C ..` for each `i`, and `B -> C ..`. (`B+` takes
+/// these instead if `B` is nullable.) Empty productions are then only reduced after the kernel item they complete.
+pub fn without_nullable_prefixes(productions: &[Production], start_production_id: usize) -> Vec<Production> {
+    let mut nullable = compute_epsilon_nonterminals(productions);
+    let mut names: BTreeSet<String> = productions.iter().map(|production| production.lhs.0.clone()).collect();
+    let mut fresh_name = |name: &str, suffix: char| {
+        let mut fresh_name = name.to_string();
+        fresh_name.push(suffix);
+        while names.contains(&fresh_name) {
+            fresh_name.push(suffix);
+        }
+        names.insert(fresh_name.clone());
+        NonTerminal(fresh_name)
+    };
+
+    let start_production = &productions[start_production_id];
+    let start = &start_production.lhs;
+    let is_augmented = productions.iter().filter(|production| &production.lhs == start).count() == 1
+        && !productions.iter().any(|production| production.rhs.contains(&Symbol::NonTerminal(start.clone())));
+    let mut result = if is_augmented {
+        // Its item is only in the initial item set, so reducing a nullable non-terminal after its dot can't repeat.
+        nullable.remove(start);
+        vec![start_production.clone()]
+    } else {
+        vec![Production { lhs: fresh_name(&start.0, '\''), rhs: vec![Symbol::NonTerminal(start.clone())] }]
+    };
+    let non_empty: BTreeMap<NonTerminal, NonTerminal> = nullable.iter().map(|non_terminal| (non_terminal.clone(), fresh_name(&non_terminal.0, '+'))).collect();
+    for (non_terminal, non_empty_non_terminal) in &non_empty {
+        result.push(Production { lhs: non_terminal.clone(), rhs: vec![] });
+        result.push(Production { lhs: non_terminal.clone(), rhs: vec![Symbol::NonTerminal(non_empty_non_terminal.clone())] });
+    }
+    for production in productions {
+        if is_augmented && &production.lhs == start {
+            continue;
+        }
+        let lhs = non_empty.get(&production.lhs).unwrap_or(&production.lhs);
+        let nullable_prefix_len = production.rhs.iter()
+            .take_while(|symbol| matches!(symbol, Symbol::NonTerminal(non_terminal) if nullable.contains(non_terminal)))
+            .count();
+        for i in 0..nullable_prefix_len {
+            let Symbol::NonTerminal(non_terminal) = &production.rhs[i] else {
+                unreachable!();
+            };
+            let mut rhs = vec![Symbol::NonTerminal(non_empty[non_terminal].clone())];
+            rhs.extend_from_slice(&production.rhs[i + 1..]);
+            result.push(Production { lhs: lhs.clone(), rhs });
+        }
+        if nullable_prefix_len < production.rhs.len() {
+            result.push(Production { lhs: lhs.clone(), rhs: production.rhs[nullable_prefix_len..].to_vec() });
+        }
+    }
+    // A production can come out of more than one original production.
+    let mut seen = BTreeSet::new();
+    result.retain(|production| seen.insert(production.clone()));
+    // `A+` has no productions if `A` only matches the empty string, so drop the productions that use it, and so on.
+    loop {
+        let lhs_non_terminals: BTreeSet<NonTerminal> = result.iter().map(|production| production.lhs.clone()).collect();
+        let len = result.len();
+        result.retain(|production| production.rhs.iter().all(|symbol| match symbol {
+            Symbol::NonTerminal(non_terminal) => lhs_non_terminals.contains(non_terminal),
+            Symbol::Terminal(_) => true,
+        }));
+        if result.len() == len {
+            return result;
+        }
+    }
+}
@@ -108,6 +108,23 @@ impl GLRParser {
         state.parse(input);
         state
     }
+
+    /// Returns the terminals that have a shift or reduce action in the top state of `parse_state`'s stack.
+    ///
+    /// This is a superset of the terminals that can actually be consumed next (a reduce may still fail
+    /// further down the stack), so it's safe to use for pruning.
+    pub fn acceptable_terminal_ids(&self, parse_state: &ParseState) -> BTreeSet<TerminalID> {
+        self.stage_7_table
+            .get(parse_state.stack.peek())
+            .map(|row| row.shifts_and_reduces.keys().copied().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn accepts_terminal_id(&self, parse_state: &ParseState, terminal_id: TerminalID) -> bool {
+        self.stage_7_table
+            .get(parse_state.stack.peek())
+            .is_some_and(|row| row.shifts_and_reduces.contains_key(&terminal_id))
+    }
 }
 
 
@@ -236,15 +253,30 @@ impl<'a> GLRParserState<'a> {
     pub fn step(&mut self, token_id: TerminalID) {
         let mut next_active_states = Vec::new();
         let mut inactive_states = Vec::new();
+        // The stacks reached so far in this step, by top state. A reduce that gives one of them again (e.g. through
+        // another derivation, or a cycle of unit productions) can only repeat its work, so it's dropped.
+        let mut reached_stacks: BTreeMap<StateID, Vec<Arc<GSSNode<StateID>>>> = BTreeMap::new();
+        for state in &self.active_states {
+            reached_stacks.entry(*state.stack.peek()).or_default().push(state.stack.clone());
+        }
+        let mut is_new_stack = |new_stack: &Arc<GSSNode<StateID>>| {
+            let stacks = reached_stacks.entry(*new_stack.peek()).or_default();
+            if stacks.iter().any(|stack| GSSNode::same_stacks(stack, new_stack)) {
+                return false;
+            }
+            stacks.push(new_stack.clone());
+            true
+        };
 
         while let Some(state) = self.active_states.pop() {
             let stack = state.stack;
             let action_stack = state.action_stack;
             let state_id = *stack.peek();
 
-            let row = self.parser.stage_7_table.get(&state_id).unwrap();
+            // A state that isn't in the table (e.g. from another parser) has no actions.
+            let action = self.parser.stage_7_table.get(&state_id).and_then(|row| row.shifts_and_reduces.get(&token_id));
 
-            if let Some(action) = row.shifts_and_reduces.get(&token_id) {
+            if let Some(action) = action {
                 match action {
                     Stage7ShiftsAndReduces::Shift(next_state_id) => {
                         crate::dbgprintln!("Shifting");
@@ -262,14 +294,19 @@ impl<'a> GLRParserState<'a> {
                         popped_stack_nodes.bulk_merge();
                         for stack_node in popped_stack_nodes {
                             let revealed_state = *stack_node.peek();
-                            let goto_row = self.parser.stage_7_table.get(&revealed_state).unwrap();
+                            let Some(goto_row) = self.parser.stage_7_table.get(&revealed_state) else {
+                                continue;
+                            };
 
                             if let Some(&goto_state) = goto_row.gotos.get(nonterminal) {
                                 crate::dbgprintln!("Going to state {:?}", goto_state);
-                                let new_stack = stack_node.push(goto_state);
+                                let new_stack = Arc::new(stack_node.push(goto_state));
+                                if !is_new_stack(&new_stack) {
+                                    continue;
+                                }
                                 let new_actions = action_stack.clone().push(Action::Reduce { production_id: *production_id, len: *len, nonterminal_id: *nonterminal });
                                 self.active_states.push(ParseState {
-                                    stack: Arc::new(new_stack),
+                                    stack: new_stack,
                                     action_stack: Some(Arc::new(new_actions)),
                                     status: ParseStatus::Active,
                                 });
@@ -301,9 +338,14 @@ impl<'a> GLRParserState<'a> {
                             for (nt_id, prod_ids) in nt_ids {
                                 for stack_node in &popped_stack_nodes {
                                     let revealed_state = *stack_node.peek();
-                                    let goto_row = self.parser.stage_7_table.get(&revealed_state).unwrap();
+                                    let Some(goto_row) = self.parser.stage_7_table.get(&revealed_state) else {
+                                        continue;
+                                    };
                                     if let Some(&goto_state) = goto_row.gotos.get(nt_id) {
                                         let new_stack = Arc::new(stack_node.push(goto_state));
+                                        if !is_new_stack(&new_stack) {
+                                            continue;
+                                        }
                                         for prod_id in prod_ids {
                                             let new_actions = action_stack.clone().push(Action::Reduce { production_id: *prod_id, len: *len, nonterminal_id: *nt_id });
                                             self.active_states.push(ParseState {
@@ -341,19 +383,17 @@ impl<'a> GLRParserState<'a> {
     }
 
     pub fn merge_active_states(&mut self) {
-        let mut active_state_map: BTreeMap<ParseStateKey, ParseState> = BTreeMap::new();
+        // Index into `new_active_states` by key, so that merges go into the state that's kept.
+        let mut active_state_indices: BTreeMap<ParseStateKey, usize> = BTreeMap::new();
 
-        let mut new_active_states = Vec::new();
+        let mut new_active_states: Vec<ParseState> = Vec::new();
 
-        for mut state in std::mem::take(&mut self.active_states) {
+        for state in std::mem::take(&mut self.active_states) {
             let key = state.key();
-            if let Some(existing) = active_state_map.get_mut(&key) {
-                Arc::make_mut(&mut existing.stack).merge(state.stack.as_ref().clone());
-                if let Some(existing_action_stack) = existing.action_stack.as_mut() {
-                    Arc::make_mut(existing_action_stack).merge(state.action_stack.unwrap().as_ref().clone());
-                }
+            if let Some(&index) = active_state_indices.get(&key) {
+                new_active_states[index].merge(state);
             } else {
-                active_state_map.insert(key, state.clone());
+                active_state_indices.insert(key, new_active_states.len());
                 new_active_states.push(state);
             }
         }
@@ -373,6 +413,15 @@ impl<'a> GLRParserState<'a> {
     pub fn is_ok(&self) -> bool {
         !self.active_states.is_empty() || self.fully_matches()
     }
+
+    /// Returns the union of `GLRParser::acceptable_terminal_ids` over all active states.
+    pub fn acceptable_terminal_ids(&self) -> BTreeSet<TerminalID> {
+        let mut result = BTreeSet::new();
+        for state in &self.active_states {
+            result.extend(self.parser.acceptable_terminal_ids(state));
+        }
+        result
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
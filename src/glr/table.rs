@@ -1,11 +1,12 @@
 use super::items::{compute_closure, compute_goto, split_on_dot, Item};
-use crate::glr::grammar::{compute_first_sets, compute_follow_sets, NonTerminal, Production, Symbol, Terminal};
+use crate::glr::grammar::{compute_first_sets, compute_follow_sets, without_nullable_prefixes, NonTerminal, Production, Symbol, Terminal};
 use crate::glr::parser::GLRParser;
 use bimap::BiBTreeMap;
 use std::collections::{HashMap, VecDeque};
 use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::Display;
 use crate::analyze_grammar::{drop_dead, validate};
+use crate::error::Error;
 
 type Stage1Table = BTreeMap<BTreeSet<Item>, Stage1Row>;
 type Stage2Table = BTreeMap<BTreeSet<Item>, Stage2Row>;
@@ -74,7 +75,7 @@ pub struct Stage7Row {
     pub gotos: BTreeMap<NonTerminalID, StateID>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct StateID(pub usize);
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct ProductionID(pub usize);
@@ -139,7 +140,8 @@ fn stage_2(stage_1_table: Stage1Table, productions: &[Production]) -> Stage2Resu
         let mut gotos = BTreeMap::new();
         let mut reduces = BTreeSet::new();
 
-        for item in &item_set {
+        // Items of empty productions are only in the closure, never in the kernel.
+        for item in &compute_closure(&item_set, productions) {
             if item.dot_position >= item.production.rhs.len() {
                 // Reduce item
                 reduces.insert(item.clone());
@@ -374,9 +376,26 @@ fn stage_7(stage_6_table: Stage6Table, productions: &[Production], start_product
     (stage_7_table, item_set_map, start_state_id, eof_terminal_id)
 }
 
-pub fn generate_glr_parser_with_maps(productions: &[Production], start_production_id: usize, mut terminal_map: BiBTreeMap<Terminal, TerminalID>, non_terminal_map: BiBTreeMap<NonTerminal, NonTerminalID>) -> GLRParser {
+pub fn generate_glr_parser_with_maps(productions: &[Production], start_production_id: usize, terminal_map: BiBTreeMap<Terminal, TerminalID>, non_terminal_map: BiBTreeMap<NonTerminal, NonTerminalID>) -> GLRParser {
+    try_generate_glr_parser_with_maps(productions, start_production_id, terminal_map, non_terminal_map).unwrap_or_else(|err| panic!("{}", err))
+}
+
+/// Like `generate_glr_parser_with_maps`, but returns an error instead of panicking if a production refers to a
+/// non-terminal that has no productions.
+pub fn try_generate_glr_parser_with_maps(productions: &[Production], start_production_id: usize, mut terminal_map: BiBTreeMap<Terminal, TerminalID>, mut non_terminal_map: BiBTreeMap<NonTerminal, NonTerminalID>) -> Result<GLRParser, Error> {
     crate::dbgprintln2!("Validating");
-    validate(productions).expect("Validation error");
+    validate(productions).map_err(Error::InvalidGrammar)?;
+
+    // The tables are for an equivalent grammar that doesn't reduce empty productions before shifting, so that the
+    // parser terminates on hidden left recursion. Its new non-terminals get IDs after the existing ones.
+    let productions = &without_nullable_prefixes(productions, start_production_id);
+    let start_production_id = 0;
+    for production in productions {
+        if !non_terminal_map.contains_left(&production.lhs) {
+            let non_terminal_id = non_terminal_map.right_values().max().map_or(0, |non_terminal_id| non_terminal_id.0 + 1);
+            non_terminal_map.insert(production.lhs.clone(), NonTerminalID(non_terminal_id));
+        }
+    }
 
     // todo: this is messy
     assign_eof_terminal_id(&mut terminal_map);
@@ -397,7 +416,7 @@ pub fn generate_glr_parser_with_maps(productions: &[Production], start_productio
     let (stage_7_table, item_set_map, start_state_id, eof_terminal_id) = stage_7(stage_6_table, productions, start_production_id, &terminal_map, &non_terminal_map);
     crate::dbgprintln2!("Stage 8");
 
-    GLRParser::new(stage_7_table, productions.to_vec(), terminal_map, non_terminal_map, item_set_map, start_state_id, eof_terminal_id)
+    Ok(GLRParser::new(stage_7_table, productions.to_vec(), terminal_map, non_terminal_map, item_set_map, start_state_id, eof_terminal_id))
 }
 
 pub fn generate_glr_parser(productions: &[Production], start_production_id: usize) -> GLRParser {
@@ -426,8 +445,9 @@ pub fn assign_terminal_ids(productions: &[Production]) -> BiBTreeMap<Terminal, T
 
 pub fn assign_eof_terminal_id(terminal_map: &mut BiBTreeMap<Terminal, TerminalID>) {
     if !terminal_map.contains_left(&Terminal("$".to_string())) {
-        let max_terminal_id = terminal_map.right_values().max().unwrap().0;
-        terminal_map.insert(Terminal("$".to_string()), TerminalID(max_terminal_id + 1));
+        // A grammar may have no terminals at all (e.g. one that only matches the empty string).
+        let eof_terminal_id = terminal_map.right_values().max().map_or(0, |terminal_id| terminal_id.0 + 1);
+        terminal_map.insert(Terminal("$".to_string()), TerminalID(eof_terminal_id));
     }
 }
 
@@ -68,4 +68,150 @@ fn test_parse_simple_expression() {
     assert!(!parser.parse(&tokenize("i++i", &parser)).fully_matches());
     assert!(!parser.parse(&tokenize("", &parser)).fully_matches());
     assert!(!parser.parse(&tokenize(")", &parser)).fully_matches());
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_acceptable_terminal_ids() {
+    use std::collections::BTreeSet;
+
+    let productions = vec![
+        // S -> A
+        prod("S", vec![nt("A")]),
+        // A -> A a | b
+        prod("A", vec![nt("A"), t("a")]),
+        prod("A", vec![t("b")]),
+    ];
+
+    let parser = generate_glr_parser(&productions, 0);
+
+    let terminal_id = |name: &str| *parser.terminal_map.get_by_left(&Terminal(name.to_string())).unwrap();
+
+    let state = parser.init_glr_parser();
+    assert_eq!(state.acceptable_terminal_ids(), BTreeSet::from([terminal_id("b")]));
+
+    let mut state = parser.init_glr_parser();
+    state.step(terminal_id("b"));
+    assert_eq!(state.acceptable_terminal_ids(), BTreeSet::from([terminal_id("a"), parser.eof_terminal_id]));
+    assert!(state.active_states.iter().all(|parse_state| !parser.accepts_terminal_id(parse_state, terminal_id("b"))));
+}
+
+#[test]
+fn test_empty_productions() {
+    let tokenize = |input: &str, parser: &GLRParser| -> Vec<TerminalID> {
+        input.chars().map(|c| *parser.terminal_map.get_by_left(&Terminal(c.to_string())).unwrap()).collect()
+    };
+
+    // S -> A "b", A -> "a" | ε
+    let productions = vec![
+        prod("S", vec![nt("A"), t("b")]),
+        prod("A", vec![t("a")]),
+        prod("A", vec![]),
+    ];
+    let parser = generate_glr_parser(&productions, 0);
+    assert!(parser.parse(&tokenize("b", &parser)).fully_matches());
+    assert!(parser.parse(&tokenize("ab", &parser)).fully_matches());
+    assert!(!parser.parse(&tokenize("", &parser)).fully_matches());
+
+    // S -> A, A -> B, B -> "a" | ε
+    let productions = vec![
+        prod("S", vec![nt("A")]),
+        prod("A", vec![nt("B")]),
+        prod("B", vec![t("a")]),
+        prod("B", vec![]),
+    ];
+    let parser = generate_glr_parser(&productions, 0);
+    assert!(parser.parse(&tokenize("", &parser)).fully_matches());
+    assert!(parser.parse(&tokenize("a", &parser)).fully_matches());
+    assert!(!parser.parse(&tokenize("aa", &parser)).fully_matches());
+}
+
+#[test]
+fn test_grammar_without_terminals() {
+    // S -> ε
+    let productions = vec![prod("S", vec![])];
+    let parser = generate_glr_parser(&productions, 0);
+    assert_eq!(parser.eof_terminal_id, TerminalID(0));
+    assert!(parser.parse(&[]).fully_matches());
+}
+
+#[test]
+fn test_merge_active_states() {
+    // S -> A, A -> B A B | ε, B -> "a". After "aa", the second "a" either ends the outer A (with an empty inner A)
+    // or starts a nested A. Both parse states end in the same shift, so they have the same key.
+    let productions = vec![
+        prod("S", vec![nt("A")]),
+        prod("A", vec![nt("B"), nt("A"), nt("B")]),
+        prod("A", vec![]),
+        prod("B", vec![t("a")]),
+    ];
+    let parser = generate_glr_parser(&productions, 0);
+    let a = *parser.terminal_map.get_by_left(&Terminal("a".to_string())).unwrap();
+
+    let mut state = parser.init_glr_parser();
+    state.step(a);
+    state.step(a);
+    assert_eq!(state.active_states.len(), 2);
+    state.merge_active_states();
+    assert_eq!(state.active_states.len(), 1);
+
+    // The merged states keep every parse, so the input can end after any even number of "a".
+    for len in 3..=6 {
+        state.step(a);
+        state.merge_active_states();
+        let mut eof_state = parser.init_glr_parser_from_parse_states(state.active_states.clone());
+        eof_state.parse_eof();
+        assert_eq!(eof_state.fully_matches(), len % 2 == 0, "after {} \"a\"", len);
+    }
+}
+
+#[test]
+fn test_hidden_left_recursion() {
+    let tokenize = |input: &str, parser: &GLRParser| -> Vec<TerminalID> {
+        input.chars().map(|c| *parser.terminal_map.get_by_left(&Terminal(c.to_string())).unwrap()).collect()
+    };
+
+    // start -> S, S -> A S "a" | "b", A -> "c" | ε. Reducing the empty A before S leads back to the same item set,
+    // so the parser must not keep reducing it at the same input position.
+    let productions = vec![
+        prod("start", vec![nt("S")]),
+        prod("S", vec![nt("A"), nt("S"), t("a")]),
+        prod("S", vec![t("b")]),
+        prod("A", vec![t("c")]),
+        prod("A", vec![]),
+    ];
+    let parser = generate_glr_parser(&productions, 0);
+    for input in ["b", "ba", "baa", "baaa", "cba", "cbaa", "ccbaa"] {
+        assert!(parser.parse(&tokenize(input, &parser)).fully_matches(), "{:?}", input);
+    }
+    for input in ["", "a", "c", "cb", "ccba", "bb", "bac"] {
+        assert!(!parser.parse(&tokenize(input, &parser)).fully_matches(), "{:?}", input);
+    }
+
+    // A cycle of unit productions (S -> T, T -> S) parses the same text forever.
+    let productions = vec![
+        prod("start", vec![nt("S")]),
+        prod("S", vec![nt("T")]),
+        prod("S", vec![t("a")]),
+        prod("T", vec![nt("S")]),
+        prod("T", vec![nt("A"), nt("T"), t("b")]),
+        prod("A", vec![]),
+    ];
+    let parser = generate_glr_parser(&productions, 0);
+    assert!(parser.parse(&tokenize("a", &parser)).fully_matches());
+    assert!(parser.parse(&tokenize("abb", &parser)).fully_matches());
+    assert!(!parser.parse(&tokenize("b", &parser)).fully_matches());
+}
+
+#[test]
+fn test_highly_ambiguous_grammar() {
+    // S -> S S | "a" has a Catalan number of parses, but only a few different stacks after each terminal.
+    let productions = vec![
+        prod("start", vec![nt("S")]),
+        prod("S", vec![nt("S"), nt("S")]),
+        prod("S", vec![t("a")]),
+    ];
+    let parser = generate_glr_parser(&productions, 0);
+    let a = *parser.terminal_map.get_by_left(&Terminal("a".to_string())).unwrap();
+    assert!(parser.parse(&[a; 20]).fully_matches());
+    assert!(!parser.parse(&[]).fully_matches());
+}
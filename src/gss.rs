@@ -97,6 +97,17 @@ impl<T> GSSNode<T> {
         self.predecessors.append(&mut other.predecessors);
     }
 
+    /// Returns true if `a` and `b` hold the same stacks. Nodes at the same address are equal without comparing their
+    /// predecessors, so this is fast for stacks that share most of their nodes.
+    pub fn same_stacks(a: &Arc<Self>, b: &Arc<Self>) -> bool
+    where
+        T: PartialEq,
+    {
+        Arc::ptr_eq(a, b) || (a.value == b.value
+            && a.predecessors.len() == b.predecessors.len()
+            && a.predecessors.iter().zip(&b.predecessors).all(|(a, b)| Self::same_stacks(a, b)))
+    }
+
     pub fn map<F, U>(&self, f: F) -> GSSNode<U>
     where
         F: Copy + Fn(&T) -> U,
@@ -245,9 +256,9 @@ impl<T: Clone + Ord> BulkMerge<T> for Vec<Arc<GSSNode<T>>> {
             if group.is_empty() {
                 self.push(first);
             } else {
-                // address map
+                // address map, including the predecessors of `first` itself
                 let mut predecessors_set: BTreeMap<_, _> = BTreeMap::new();
-                for sibling in group {
+                for sibling in group.iter().chain([&first]) {
                     for predecessor in &sibling.predecessors {
                         predecessors_set.insert(Arc::as_ptr(predecessor), predecessor.clone());
                     }
@@ -259,3 +270,19 @@ impl<T: Clone + Ord> BulkMerge<T> for Vec<Arc<GSSNode<T>>> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bulk_merge_keeps_all_predecessors() {
+        // 1 -> 3 and 2 -> 3 merge into a single 3 whose predecessors are 1 and 2.
+        let mut nodes = vec![Arc::new(GSSNode::new(1).push(3)), Arc::new(GSSNode::new(2).push(3))];
+        nodes.bulk_merge();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].value, 3);
+        let predecessor_values: BTreeSet<_> = nodes[0].pop().iter().map(|predecessor| predecessor.value).collect();
+        assert_eq!(predecessor_values, BTreeSet::from([1, 2]));
+    }
+}
@@ -1,16 +1,20 @@
 // src/interface.rs
 use crate::finite_automata::{greedy_group, groups, non_greedy_group, ExprGroup, ExprGroups};
 use crate::finite_automata::{Expr, Regex};
+use crate::compiled_regex::CompiledRegex;
 use crate::glr::grammar::{NonTerminal, Production, Symbol, Terminal};
 use crate::glr::parser::{GLRParser, ParseState};
-use crate::glr::table::{assign_non_terminal_ids, generate_glr_parser, generate_glr_parser_with_maps, NonTerminalID, StateID, TerminalID};
-use crate::precompute::{precompute, LLMTokenID, Token, Tokenizer};
+use crate::glr::table::{assign_non_terminal_ids, generate_glr_parser, try_generate_glr_parser_with_maps, NonTerminalID, TerminalID};
+use crate::precompute::{precompute_frozen, LLMTokenID, Tokenizer};
 use bimap::BiBTreeMap;
 use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::fmt::{Debug, Formatter};
 use kdam::tqdm;
 use crate::analyze_grammar::drop_dead;
-use crate::constraint::{precompute_add_eof, GrammarConstraint};
+use crate::analyze_grammar::{validate, validate_terminals};
+use crate::constraint::{precompute_add_special_tokens, validate_llm_token_ids, GrammarConstraint, SpecialTokenPolicy};
+use crate::error::Error;
+use crate::reference::ReferenceConstraint;
 
 type LLMToken<'a> = &'a [u8];
 type LLMTokenMap = BiBTreeMap<Vec<u8>, LLMTokenID>;
@@ -22,6 +26,9 @@ pub struct Grammar<T> {
     pub literal_map: BTreeMap<String, String>,
     pub terminal_name_to_group_id: BiBTreeMap<String, usize>,
     pub terminal_expr_to_group_id: BiBTreeMap<Expr, usize>,
+    /// Group IDs of terminals that may appear between any two grammar tokens (e.g. whitespace and comments).
+    /// They are lexed by the tokenizer but never reach the parser.
+    pub ignore_group_ids: BTreeSet<usize>,
     pub tokenizer: T,
 }
 
@@ -59,6 +66,8 @@ impl<T> Debug for Grammar<T> where T: Debug {
             writeln!(f, "    {:?}: {}", name, group_id)?;
         }
 
+        writeln!(f, "  Ignored group IDs: {:?}", self.ignore_group_ids)?;
+
         writeln!(f, "Tokenizer:");
         writeln!(f, "{:?}", &self.tokenizer);
 
@@ -116,12 +125,71 @@ impl<T> Grammar<T> {
     pub fn glr_parser(&self) -> GLRParser {
         generate_glr_parser(&self.productions, self.start_production_id)
     }
+
+    /// Like `glr_parser`, but with terminal IDs that are the tokenizer's group IDs, as the constraints need.
+    pub(crate) fn tokenizer_glr_parser(&self) -> Result<GLRParser, Error> {
+        let terminal_map = self.terminal_name_to_group_id.iter().map(|(name, group_id)| { (Terminal(name.clone()), TerminalID(*group_id)) }).collect();
+        let non_terminal_map = assign_non_terminal_ids(&self.productions);
+        try_generate_glr_parser_with_maps(&self.productions, self.start_production_id, terminal_map, non_terminal_map)
+    }
+
+    /// Returns warnings about terminals that overlap (i.e. can match the same string), which makes tokenization
+    /// ambiguous.
+    pub fn validate_terminals(&self) -> Vec<String> {
+        let terminals = self.terminal_expr_to_group_id.iter().map(|(expr, group_id)| {
+            (self.terminal_name_to_group_id.get_by_right(group_id).unwrap().clone(), expr.clone())
+        }).collect();
+        validate_terminals(&terminals)
+    }
+
+    /// Replaces the tokenizer, keeping the productions and terminal maps.
+    /// The new tokenizer must use the same group IDs as the old one.
+    pub fn with_tokenizer<U>(self, tokenizer: U) -> Grammar<U> {
+        Grammar {
+            productions: self.productions,
+            start_production_id: self.start_production_id,
+            literal_map: self.literal_map,
+            terminal_name_to_group_id: self.terminal_name_to_group_id,
+            terminal_expr_to_group_id: self.terminal_expr_to_group_id,
+            ignore_group_ids: self.ignore_group_ids,
+            tokenizer,
+        }
+    }
 }
 
 impl Grammar<Regex> {
+    /// Compiles the tokenizer into a `CompiledRegex`, which has the same states and group IDs but lexes faster.
+    /// Use this before `GrammarConstraint::from_grammar` unless the `Regex` itself is needed.
+    pub fn compile(self) -> Grammar<CompiledRegex> {
+        let tokenizer = self.tokenizer.compile();
+        self.with_tokenizer(tokenizer)
+    }
+
     /// Constructs a `Grammar` and `Regex` tokenizer from a list of grammar expressions.
     /// The first non-terminal in the list is treated as the start symbol.
     pub fn from_exprs(exprs: Vec<(String, GrammarExpr)>) -> Self {
+        Self::from_exprs_with_ignore(exprs, vec![])
+    }
+
+    /// Like `from_exprs`, but additionally takes `%ignore`-style terminals (e.g. whitespace and comments).
+    /// These may appear between any two grammar tokens and are skipped without being passed to the parser.
+    pub fn from_exprs_with_ignore(exprs: Vec<(String, GrammarExpr)>, ignore: Vec<Expr>) -> Self {
+        Self::try_from_exprs_with_ignore(exprs, ignore).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `from_exprs`, but returns an error if the grammar is empty, refers to an undefined rule, has a
+    /// terminal that matches the empty string, or has a lookahead that isn't a single byte at the end of a terminal.
+    pub fn try_from_exprs(exprs: Vec<(String, GrammarExpr)>) -> Result<Self, Error> {
+        Self::try_from_exprs_with_ignore(exprs, vec![])
+    }
+
+    /// Like `from_exprs_with_ignore`, but returns an error instead of panicking. It's also an error for an ignored
+    /// terminal to be a terminal of the grammar.
+    pub fn try_from_exprs_with_ignore(exprs: Vec<(String, GrammarExpr)>, ignore: Vec<Expr>) -> Result<Self, Error> {
+        if exprs.is_empty() {
+            return Err(Error::InvalidGrammar("the grammar has no rules".to_string()));
+        }
+
         let mut productions = Vec::new();
         let mut literal_map = BTreeMap::new();
         let mut terminal_name_to_group_id = BiBTreeMap::new();
@@ -289,39 +357,95 @@ impl Grammar<Regex> {
         // crate::dbgprintln2!("Dropping dead productions");
         // let productions = drop_dead(&productions);
 
-        let tokenizer_exprs_vec: Vec<ExprGroup> = tokens
-            .into_iter()
-            .map(|(_, expr)| greedy_group(expr))
+        let mut ignore_group_ids = BTreeSet::new();
+        for expr in ignore {
+            if let Some(&group_id) = terminal_expr_to_group_id.get_by_left(&expr) {
+                // Ignoring a terminal the grammar uses would skip every occurrence of it before the parser sees it.
+                if !ignore_group_ids.contains(&group_id) {
+                    return Err(Error::InvalidGrammar(format!("ignored terminal {:?} is also a grammar terminal", expr)));
+                }
+            } else {
+                let group_id = next_terminal_id;
+                let terminal_name = format!("__regex_{}", group_id);
+                terminal_name_to_group_id.insert(terminal_name.clone(), group_id);
+                terminal_expr_to_group_id.insert(expr.clone(), group_id);
+                tokens.insert(terminal_name, expr);
+                ignore_group_ids.insert(group_id);
+                next_terminal_id += 1;
+            }
+        }
+
+        // The tokenizer's group IDs must line up with `terminal_name_to_group_id`, so order the groups by ID
+        // rather than by terminal name (`__regex_10` sorts before `__regex_2`).
+        let tokenizer_exprs_vec: Vec<ExprGroup> = (0..next_terminal_id)
+            .map(|group_id| {
+                let terminal_name = terminal_name_to_group_id.get_by_right(&group_id).unwrap();
+                greedy_group(tokens[terminal_name].clone())
+            })
             .collect();
         let tokenizer_expr_groups = groups(tokenizer_exprs_vec);
         crate::dbgprintln2!("Building tokenizer");
-        let tokenizer = tokenizer_expr_groups.clone().build();
+        let tokenizer = tokenizer_expr_groups.clone().try_build()?;
+        validate(&productions).map_err(Error::InvalidGrammar)?;
 
         crate::dbgprintln2!("Done defining grammar");
-        Self {
+        let grammar = Self {
             productions,
             start_production_id: 0,
             literal_map,
             terminal_name_to_group_id,
             terminal_expr_to_group_id,
+            ignore_group_ids,
             tokenizer,
-        }
+        };
+        grammar.check_no_empty_matches()?;
+        Ok(grammar)
+    }
+}
+
+impl<T: Tokenizer> Grammar<T> {
+    /// Fails with the name of a terminal that matches the empty string, if there is one. The lexer could emit it
+    /// forever without consuming anything.
+    pub(crate) fn check_no_empty_matches(&self) -> Result<(), Error> {
+        let Some(group_id) = self.tokenizer.tokens_matching_empty_input().first().copied() else {
+            return Ok(());
+        };
+        let terminal = match (self.terminal_name_to_group_id.get_by_right(&group_id), self.terminal_expr_to_group_id.get_by_right(&group_id)) {
+            (Some(name), Some(expr)) => format!("{} ({:?})", name, expr),
+            (Some(name), None) => name.clone(),
+            _ => format!("with group ID {}", group_id),
+        };
+        Err(Error::EmptyMatchingTerminal(terminal))
     }
 }
 
 impl<T: Tokenizer> GrammarConstraint<T> {
     pub fn from_grammar(grammar: Grammar<T>, llm_tokens: LLMTokenMap, eof_llm_token_id: usize, max_llm_token_id: usize) -> Self {
+        Self::from_grammar_with_special_tokens(grammar, llm_tokens, SpecialTokenPolicy::eos(eof_llm_token_id), max_llm_token_id)
+    }
+
+    /// Like `from_grammar`, but with any number of EOS tokens and other special tokens.
+    pub fn from_grammar_with_special_tokens(grammar: Grammar<T>, llm_tokens: LLMTokenMap, special_tokens: SpecialTokenPolicy, max_llm_token_id: usize) -> Self {
+        Self::try_from_grammar_with_special_tokens(grammar, llm_tokens, special_tokens, max_llm_token_id).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `from_grammar`, but returns an error instead of panicking.
+    pub fn try_from_grammar(grammar: Grammar<T>, llm_tokens: LLMTokenMap, eof_llm_token_id: usize, max_llm_token_id: usize) -> Result<Self, Error> {
+        Self::try_from_grammar_with_special_tokens(grammar, llm_tokens, SpecialTokenPolicy::eos(eof_llm_token_id), max_llm_token_id)
+    }
+
+    /// Like `from_grammar_with_special_tokens`, but returns an error instead of panicking.
+    pub fn try_from_grammar_with_special_tokens(grammar: Grammar<T>, llm_tokens: LLMTokenMap, special_tokens: SpecialTokenPolicy, max_llm_token_id: usize) -> Result<Self, Error> {
+        validate_llm_token_ids(&llm_tokens, &special_tokens, max_llm_token_id)?;
+        grammar.check_no_empty_matches()?;
         crate::dbgprintln2!("GrammarConstraint::from_grammar");
-        let terminal_map = grammar.terminal_name_to_group_id.iter().map(|(name, group_id)| { (Terminal(name.clone()), TerminalID(*group_id)) }).collect();
-        let non_terminal_map = assign_non_terminal_ids(&grammar.productions);
         crate::dbgprintln2!("Generating GLR parser");
-        let parser = generate_glr_parser_with_maps(&grammar.productions, grammar.start_production_id, terminal_map, non_terminal_map);
+        let parser = grammar.tokenizer_glr_parser()?;
 
         crate::dbgprintln2!("Precomputing");
-        let mut precomputed = precompute(&grammar.tokenizer, &llm_tokens, LLMTokenID(eof_llm_token_id), max_llm_token_id);
-        crate::dbgprintln2!("precomputed.len(): {}", precomputed.len());
-        precompute_add_eof(&mut precomputed, LLMTokenID(eof_llm_token_id), parser.eof_terminal_id.0, max_llm_token_id);
-        // precompute_add_eof(&mut precomputed, LLMTokenID(eof_llm_token_id), llm_tokens.len(), max_llm_token_id);
+        let precomputed = precompute_frozen(&grammar.tokenizer, &llm_tokens, max_llm_token_id, |state_id, trie| {
+            precompute_add_special_tokens(state_id, trie, &grammar.tokenizer, &special_tokens, parser.eof_terminal_id.0, max_llm_token_id);
+        })?;
         crate::dbgprintln2!("precomputed.len(): {}", precomputed.len());
         crate::dbgprintln2!("Done precomputing");
 
@@ -332,12 +456,41 @@ impl<T: Tokenizer> GrammarConstraint<T> {
         // crate::dbgprintln2!("Generating GLR parser");
         // let parser = generate_glr_parser_with_maps(&grammar.productions, grammar.start_production_id, terminal_map, non_terminal_map);
 
-        Self {
+        Ok(Self {
             tokenizer: grammar.tokenizer,
             parser,
             precomputed,
+            lazily_precomputed: Default::default(),
+            healing_masks: Default::default(),
             max_llm_token_id,
-        }
+            ignore_terminal_ids: grammar.ignore_group_ids,
+            llm_tokens,
+            special_tokens,
+        })
+    }
+}
+
+impl<T: Tokenizer> ReferenceConstraint<T> {
+    pub fn from_grammar(grammar: Grammar<T>, llm_tokens: LLMTokenMap, eof_llm_token_id: usize, max_llm_token_id: usize) -> Self {
+        Self::from_grammar_with_special_tokens(grammar, llm_tokens, SpecialTokenPolicy::eos(eof_llm_token_id), max_llm_token_id)
+    }
+
+    /// Like `from_grammar`, but with any number of EOS tokens and other special tokens.
+    pub fn from_grammar_with_special_tokens(grammar: Grammar<T>, llm_tokens: LLMTokenMap, special_tokens: SpecialTokenPolicy, max_llm_token_id: usize) -> Self {
+        Self::try_from_grammar_with_special_tokens(grammar, llm_tokens, special_tokens, max_llm_token_id).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `from_grammar`, but returns an error instead of panicking.
+    pub fn try_from_grammar(grammar: Grammar<T>, llm_tokens: LLMTokenMap, eof_llm_token_id: usize, max_llm_token_id: usize) -> Result<Self, Error> {
+        Self::try_from_grammar_with_special_tokens(grammar, llm_tokens, SpecialTokenPolicy::eos(eof_llm_token_id), max_llm_token_id)
+    }
+
+    /// Like `from_grammar_with_special_tokens`, but returns an error instead of panicking.
+    pub fn try_from_grammar_with_special_tokens(grammar: Grammar<T>, llm_tokens: LLMTokenMap, special_tokens: SpecialTokenPolicy, max_llm_token_id: usize) -> Result<Self, Error> {
+        validate_llm_token_ids(&llm_tokens, &special_tokens, max_llm_token_id)?;
+        grammar.check_no_empty_matches()?;
+        let parser = grammar.tokenizer_glr_parser()?;
+        Ok(Self::new(grammar.tokenizer, parser, grammar.ignore_group_ids, llm_tokens, special_tokens, max_llm_token_id))
     }
 }
 
@@ -346,12 +499,16 @@ mod tests {
     use std::sync::{Arc, Mutex};
     use bitvec::prelude::*;
     use super::*;
-    use crate::finite_automata::eat_u8;
-    use crate::glr::table::generate_glr_parser;
-    use crate::precompute::{print_precomputed, LLMTokenID};
+    use crate::finite_automata::{eat_u8, lookahead, negative_lookahead};
+    use crate::glr::table::{generate_glr_parser, StateID};
+    use crate::constraint::ConstraintMode;
+    use crate::consistency::check_consistency;
+    use crate::precompute::{freeze_precomputed, precompute, print_precomputed, LLMTokenID};
     use crate::{choice_fast, groups, seq_fast};
-    use crate::tokenizer_combinators::{eat_u8_fast, eat_u8_negation_fast, eat_u8_range_fast, repeat0_fast};
+    use crate::tokenizer_combinators::{eat_string_fast, eat_u8_fast, eat_u8_negation_fast, eat_u8_range_fast, repeat0_fast, repeat1_fast};
     use crate::trie::TrieNode;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
 
 
     fn bitvec_with_capacity_and_values(capacity: usize, values: Vec<usize>) -> BitVec {
@@ -424,13 +581,13 @@ mod tests {
             }
         }
 
-        for (tokenizer_state, root) in &grammar_constraint_state.parent.precomputed {
+        for (tokenizer_state, trie) in &grammar_constraint_state.parent.precomputed {
             crate::dbgprintln!("Tokenizer state: {}", tokenizer_state.0);
-            for node in TrieNode::all_nodes(Arc::new(Mutex::new(root.clone()))) {
-                crate::dbgprintln!("Node address: {:p}, value: {:?}", Arc::as_ptr(&node), node.try_lock().unwrap().value);
-                // print edge values and destination addresses
-                for (edge, dest) in node.try_lock().unwrap().children() {
-                    crate::dbgprintln!("    Edge value: {:?}, destination address: {:p}", edge, Arc::as_ptr(&dest));
+            for node in trie.nodes() {
+                crate::dbgprintln!("Node {}, value: {:?}", node, trie.value(node));
+                // print edge values and destinations
+                for (edge, dest) in trie.children(node) {
+                    crate::dbgprintln!("    Edge value: {:?}, destination: {}", edge, dest);
                 }
             }
         }
@@ -494,13 +651,13 @@ mod tests {
         let grammar_constraint = GrammarConstraint::from_grammar(grammar, llm_token_map.clone(), eof_llm_token_id, max_llm_token_id);
         let mut grammar_constraint_state = grammar_constraint.init();
 
-        for (tokenizer_state, root) in &grammar_constraint_state.parent.precomputed {
+        for (tokenizer_state, trie) in &grammar_constraint_state.parent.precomputed {
             crate::dbgprintln!("Tokenizer state: {}", tokenizer_state.0);
-            for node in TrieNode::all_nodes(Arc::new(Mutex::new(root.clone()))) {
-                crate::dbgprintln!("Node address: {:p}, value: {:?}", Arc::as_ptr(&node), node.try_lock().unwrap().value);
-                // print edge values and destination addresses
-                for (edge, dest) in node.try_lock().unwrap().children() {
-                    crate::dbgprintln!("    Edge value: {:?}, destination address: {:p}", edge, Arc::as_ptr(&dest));
+            for node in trie.nodes() {
+                crate::dbgprintln!("Node {}, value: {:?}", node, trie.value(node));
+                // print edge values and destinations
+                for (edge, dest) in trie.children(node) {
+                    crate::dbgprintln!("    Edge value: {:?}, destination: {}", edge, dest);
                 }
             }
         }
@@ -530,6 +687,543 @@ mod tests {
         assert_eq!(mask, expected_mask);
     }
 
+    #[test]
+    fn test_grammar_with_many_terminals() {
+        // S -> "a" "b" ... "l". With more than ten terminals, the tokenizer's group IDs must follow the terminal IDs
+        // rather than the terminal names (`__regex_10` sorts before `__regex_2`).
+        let letters = b"abcdefghijkl";
+        let exprs = vec![("S".to_string(), sequence(letters.iter().map(|&letter| regex(eat_u8(letter))).collect()))];
+        let grammar = Grammar::from_exprs(exprs);
+        let llm_token_map: LLMTokenMap = letters.iter().enumerate().map(|(i, &letter)| (vec![letter], LLMTokenID(i))).collect();
+        let eof_llm_token_id = letters.len();
+        let grammar_constraint = GrammarConstraint::from_grammar(grammar, llm_token_map, eof_llm_token_id, eof_llm_token_id);
+        let mut grammar_constraint_state = grammar_constraint.init();
+        for i in 0..letters.len() {
+            assert_eq!(grammar_constraint_state.get_mask(), bitvec_with_capacity_and_values(letters.len() + 1, vec![i]));
+            grammar_constraint_state.commit(LLMTokenID(i));
+        }
+        assert_eq!(grammar_constraint_state.get_mask(), bitvec_with_capacity_and_values(letters.len() + 1, vec![eof_llm_token_id]));
+    }
+
+    #[test]
+    fn test_special_tokens() {
+        // S -> NAME | NAME CALL NAME, where NAME is [a-z]+ and CALL can only be produced by a special token.
+        let name = repeat1_fast(eat_u8_range_fast(b'a', b'z'));
+        let call = crate::finite_automata::eat_u8_set(crate::u8set::U8Set::none());
+        let exprs = vec![
+            (
+                "S".to_string(),
+                choice(vec![
+                    regex(name.clone()),
+                    sequence(vec![regex(name.clone()), regex(call.clone()), regex(name)]),
+                ]),
+            ),
+        ];
+        let grammar = Grammar::from_exprs(exprs);
+        let call_token_id = *grammar.terminal_expr_to_group_id.get_by_left(&call).unwrap();
+
+        // "a", "b", a forbidden "c", two EOS tokens and the special CALL token.
+        let llm_tokens: Vec<Vec<u8>> = vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()];
+        let llm_token_map: LLMTokenMap = llm_tokens.iter().enumerate().map(|(i, token)| (token.clone(), LLMTokenID(i))).collect();
+        let (eos_1, eos_2, forbidden, call_llm_token) = (3, 4, 2, 5);
+        let special_tokens = SpecialTokenPolicy {
+            eos_llm_token_ids: BTreeSet::from([LLMTokenID(eos_1), LLMTokenID(eos_2)]),
+            forbidden_llm_token_ids: BTreeSet::from([LLMTokenID(forbidden)]),
+            terminal_llm_token_ids: BTreeMap::from([(LLMTokenID(call_llm_token), call_token_id)]),
+            ..Default::default()
+        };
+        let grammar_constraint = GrammarConstraint::from_grammar_with_special_tokens(grammar, llm_token_map, special_tokens, 5);
+        let mut grammar_constraint_state = grammar_constraint.init();
+
+        // Neither EOS nor CALL can come first.
+        assert_eq!(grammar_constraint_state.get_mask(), bitvec_with_capacity_and_values(6, vec![0, 1]));
+
+        // After "a", NAME may continue, or end before EOS or CALL.
+        grammar_constraint_state.commit(LLMTokenID(0));
+        assert_eq!(grammar_constraint_state.get_mask(), bitvec_with_capacity_and_values(6, vec![0, 1, eos_1, eos_2, call_llm_token]));
+
+        // After CALL, only a NAME can follow.
+        grammar_constraint_state.commit(LLMTokenID(call_llm_token));
+        assert_eq!(grammar_constraint_state.get_mask(), bitvec_with_capacity_and_values(6, vec![0, 1]));
+        grammar_constraint_state.commit(LLMTokenID(1));
+        assert_eq!(grammar_constraint_state.get_mask(), bitvec_with_capacity_and_values(6, vec![0, 1, eos_1, eos_2]));
+    }
+
+    #[test]
+    fn test_alias_llm_tokens() {
+        // S -> "a" "b"+, where LLM token 2 has the same text as "a" and 3 the same as "b", but 3 is forbidden.
+        let exprs = vec![("S".to_string(), sequence(vec![regex(eat_u8_fast(b'a')), regex(repeat1_fast(eat_u8_fast(b'b')))]))];
+        let grammar = Grammar::from_exprs(exprs);
+        let llm_token_map: LLMTokenMap = [(b"a".to_vec(), LLMTokenID(0)), (b"b".to_vec(), LLMTokenID(1))].into_iter().collect();
+        let special_tokens = SpecialTokenPolicy {
+            eos_llm_token_ids: BTreeSet::from([LLMTokenID(4)]),
+            forbidden_llm_token_ids: BTreeSet::from([LLMTokenID(3)]),
+            alias_llm_token_ids: BTreeMap::from([(LLMTokenID(2), LLMTokenID(0)), (LLMTokenID(3), LLMTokenID(1))]),
+            ..Default::default()
+        };
+        let mut reference = ReferenceConstraint::from_grammar_with_special_tokens(grammar.clone(), llm_token_map.clone(), special_tokens.clone(), 4);
+        let grammar_constraint = GrammarConstraint::from_grammar_with_special_tokens(grammar, llm_token_map, special_tokens, 4);
+        let mut grammar_constraint_state = grammar_constraint.clone().init();
+
+        assert_eq!(grammar_constraint_state.get_mask(), bitvec_with_capacity_and_values(5, vec![0, 2]));
+        assert_eq!(reference.get_mask(), grammar_constraint_state.get_mask());
+        grammar_constraint_state.commit(LLMTokenID(2));
+        reference.commit(LLMTokenID(2));
+        assert_eq!(grammar_constraint_state.get_mask(), bitvec_with_capacity_and_values(5, vec![1]));
+        assert_eq!(reference.get_mask(), grammar_constraint_state.get_mask());
+        assert_eq!(grammar_constraint_state.decode(&[LLMTokenID(2), LLMTokenID(1)]), b"ab".to_vec());
+
+        // Healing a prompt that ends with an alias.
+        let mut grammar_constraint_state = grammar_constraint.clone().init();
+        assert_eq!(grammar_constraint_state.try_heal_prompt(&[LLMTokenID(2)], 1).unwrap(), &[] as &[LLMTokenID]);
+        assert_eq!(grammar_constraint_state.get_mask(), bitvec_with_capacity_and_values(5, vec![0, 2]));
+
+        let mut grammar_constraint_state = grammar_constraint.init();
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(check_consistency(&mut grammar_constraint_state, &mut rng, 10, 4), Ok(()));
+    }
+
+    #[test]
+    fn test_errors() {
+        // S -> "a" "b"
+        let grammar = || Grammar::from_exprs(vec![("S".to_string(), sequence(vec![regex(eat_u8_fast(b'a')), regex(eat_u8_fast(b'b'))]))]);
+        let llm_token_map: LLMTokenMap = [(b"a".to_vec(), LLMTokenID(0)), (b"b".to_vec(), LLMTokenID(1))].into_iter().collect();
+
+        assert!(matches!(Grammar::try_from_exprs(vec![]), Err(Error::InvalidGrammar(_))));
+        assert!(matches!(Grammar::try_from_exprs(vec![("S".to_string(), r#ref("T"))]), Err(Error::InvalidGrammar(_))));
+        let misplaced_lookahead = vec![("S".to_string(), regex(Expr::Seq(vec![lookahead(eat_u8(b'a')), eat_u8(b'a')])))];
+        assert!(matches!(Grammar::try_from_exprs(misplaced_lookahead), Err(Error::InvalidGrammar(_))));
+        let empty_matching = vec![("S".to_string(), regex(crate::finite_automata::rep(eat_u8(b'a'))))];
+        let result = Grammar::try_from_exprs(empty_matching);
+        assert!(matches!(result, Err(Error::EmptyMatchingTerminal(terminal)) if terminal.starts_with("__regex_0 (")));
+
+        let result = GrammarConstraint::try_from_grammar(grammar(), llm_token_map.clone(), 3, 2);
+        assert!(matches!(result, Err(Error::LLMTokenIDOutOfRange { llm_token_id: LLMTokenID(3), max_llm_token_id: 2 })));
+        let special_tokens = SpecialTokenPolicy {
+            eos_llm_token_ids: BTreeSet::from([LLMTokenID(2)]),
+            forbidden_llm_token_ids: BTreeSet::new(),
+            terminal_llm_token_ids: BTreeMap::from([(LLMTokenID(0), 0)]),
+            ..Default::default()
+        };
+        let result = GrammarConstraint::try_from_grammar_with_special_tokens(grammar(), llm_token_map.clone(), special_tokens, 2);
+        assert!(matches!(result, Err(Error::SpecialTokenIsText(LLMTokenID(0)))));
+        let special_tokens = SpecialTokenPolicy { alias_llm_token_ids: BTreeMap::from([(LLMTokenID(2), LLMTokenID(3))]), ..Default::default() };
+        let result = GrammarConstraint::try_from_grammar_with_special_tokens(grammar(), llm_token_map.clone(), special_tokens, 3);
+        assert!(matches!(result, Err(Error::NotText(LLMTokenID(3)))));
+        let result = ReferenceConstraint::try_from_grammar(grammar(), llm_token_map.clone(), 3, 2);
+        assert!(matches!(result, Err(Error::LLMTokenIDOutOfRange { llm_token_id: LLMTokenID(3), max_llm_token_id: 2 })));
+        // A tokenizer swapped in with `with_tokenizer` is checked too, and the error names the grammar's terminal.
+        let empty_matching_tokenizer = || groups(vec![greedy_group(crate::finite_automata::rep(eat_u8(b'a'))), greedy_group(eat_u8(b'b'))]).build();
+        let result = GrammarConstraint::try_from_grammar(grammar().with_tokenizer(empty_matching_tokenizer()), llm_token_map.clone(), 2, 2);
+        assert!(matches!(result, Err(Error::EmptyMatchingTerminal(terminal)) if terminal.starts_with("__regex_0 (")));
+        let result = ReferenceConstraint::try_from_grammar(grammar().with_tokenizer(empty_matching_tokenizer()), llm_token_map.clone(), 2, 2);
+        assert!(matches!(result, Err(Error::EmptyMatchingTerminal(terminal)) if terminal.starts_with("__regex_0 (")));
+
+        // Errors from the state leave it unchanged.
+        let mut grammar_constraint_state = GrammarConstraint::try_from_grammar(grammar(), llm_token_map, 2, 2).unwrap().init();
+        assert!(matches!(grammar_constraint_state.try_commit(LLMTokenID(3)), Err(Error::LLMTokenIDOutOfRange { .. })));
+        assert!(matches!(grammar_constraint_state.try_heal_prompt(&[LLMTokenID(0), LLMTokenID(2)], 2), Err(Error::NotText(LLMTokenID(2)))));
+        assert_eq!(grammar_constraint_state.try_get_mask().unwrap(), bitvec_with_capacity_and_values(3, vec![0]));
+        grammar_constraint_state.try_commit(LLMTokenID(0)).unwrap();
+        assert_eq!(grammar_constraint_state.try_get_mask().unwrap(), bitvec_with_capacity_and_values(3, vec![1]));
+    }
+
+    #[test]
+    fn test_trigger() {
+        // S -> "{" "a" "}"
+        let exprs = vec![
+            (
+                "S".to_string(),
+                sequence(vec![regex(eat_u8_fast(b'{')), regex(eat_u8_fast(b'a')), regex(eat_u8_fast(b'}'))]),
+            ),
+        ];
+        let grammar = Grammar::from_exprs(exprs);
+        let llm_tokens: Vec<Vec<u8>> = vec![b"Ans".to_vec(), b"wer:".to_vec(), b" ".to_vec(), b"{".to_vec(), b"a".to_vec(), b"}".to_vec()];
+        let llm_token_map: LLMTokenMap = llm_tokens.iter().enumerate().map(|(i, token)| (token.clone(), LLMTokenID(i))).collect();
+        let eof_llm_token_id = llm_tokens.len();
+        let grammar_constraint = GrammarConstraint::from_grammar(grammar, llm_token_map, eof_llm_token_id, eof_llm_token_id);
+        let all_tokens = bitvec_with_capacity_and_values(7, (0..7).collect());
+
+        let mut grammar_constraint_state = grammar_constraint.clone().init_with_trigger(Some(b"Answer: "));
+        assert_eq!(grammar_constraint_state.get_mask(), all_tokens);
+        // The trigger spans LLM tokens, and isn't complete until the space.
+        grammar_constraint_state.commit_many(&[LLMTokenID(0), LLMTokenID(1)]);
+        assert!(!grammar_constraint_state.is_active());
+        grammar_constraint_state.commit(LLMTokenID(2));
+        assert!(grammar_constraint_state.is_active());
+        assert_eq!(grammar_constraint_state.get_mask(), bitvec_with_capacity_and_values(7, vec![3]));
+        grammar_constraint_state.commit_many(&[LLMTokenID(3), LLMTokenID(4)]);
+        assert_eq!(grammar_constraint_state.get_mask(), bitvec_with_capacity_and_values(7, vec![5]));
+        // "}" completes the grammar, and nothing can follow it, so generation is free again.
+        grammar_constraint_state.commit(LLMTokenID(5));
+        assert_eq!(grammar_constraint_state.mode(), &ConstraintMode::Finished);
+        assert_eq!(grammar_constraint_state.get_mask(), all_tokens);
+
+        // Without a trigger, the grammar only starts on demand.
+        let mut grammar_constraint_state = grammar_constraint.init_with_trigger(None);
+        grammar_constraint_state.commit_many(&[LLMTokenID(0), LLMTokenID(1), LLMTokenID(2)]);
+        assert!(!grammar_constraint_state.is_active());
+        grammar_constraint_state.activate();
+        assert_eq!(grammar_constraint_state.get_mask(), bitvec_with_capacity_and_values(7, vec![3]));
+        grammar_constraint_state.deactivate();
+        assert_eq!(grammar_constraint_state.get_mask(), all_tokens);
+    }
+
+    #[test]
+    fn test_trigger_finishes_at_commit() {
+        // S -> "{" "a" "}", with spaces ignored, so a space can always continue the complete grammar.
+        let exprs = vec![
+            (
+                "S".to_string(),
+                sequence(vec![regex(eat_u8_fast(b'{')), regex(eat_u8_fast(b'a')), regex(eat_u8_fast(b'}'))]),
+            ),
+        ];
+        let grammar = Grammar::from_exprs_with_ignore(exprs, vec![eat_u8(b' ')]);
+        let llm_tokens: Vec<Vec<u8>> = vec![b"{".to_vec(), b"a".to_vec(), b"}".to_vec(), b" ".to_vec(), b"x".to_vec(), b" x".to_vec()];
+        let llm_token_map: LLMTokenMap = llm_tokens.iter().enumerate().map(|(i, token)| (token.clone(), LLMTokenID(i))).collect();
+        let grammar_constraint = GrammarConstraint::from_grammar(grammar, llm_token_map, 6, 6);
+        let all_tokens = bitvec_with_capacity_and_values(7, (0..7).collect());
+
+        let mut grammar_constraint_state = grammar_constraint.init_with_trigger(Some(b""));
+        grammar_constraint_state.commit_many(&[LLMTokenID(0), LLMTokenID(1)]);
+        assert_eq!(grammar_constraint_state.get_mask(), bitvec_with_capacity_and_values(7, vec![2, 3]));
+        // Once the grammar is complete, free text may follow, so every token is allowed.
+        grammar_constraint_state.commit(LLMTokenID(2));
+        assert!(grammar_constraint_state.is_active());
+        assert_eq!(grammar_constraint_state.get_mask(), all_tokens);
+        let complete = grammar_constraint_state.snapshot();
+
+        // A space continues the grammar.
+        grammar_constraint_state.commit(LLMTokenID(3));
+        assert!(grammar_constraint_state.is_active());
+        // "x" doesn't, so it ends the grammar.
+        grammar_constraint_state.commit(LLMTokenID(4));
+        assert_eq!(grammar_constraint_state.mode(), &ConstraintMode::Finished);
+        assert_eq!(grammar_constraint_state.get_mask(), all_tokens);
+
+        // Neither does " x", even though its space would.
+        grammar_constraint_state.restore(complete);
+        grammar_constraint_state.commit(LLMTokenID(5));
+        assert_eq!(grammar_constraint_state.mode(), &ConstraintMode::Finished);
+    }
+
+    #[test]
+    fn test_feed_bytes() {
+        // S -> "{" NAME "}" where NAME is [a-z]+.
+        let exprs = vec![
+            (
+                "S".to_string(),
+                sequence(vec![regex(eat_u8_fast(b'{')), regex(repeat1_fast(eat_u8_range_fast(b'a', b'z'))), regex(eat_u8_fast(b'}'))]),
+            ),
+        ];
+        let grammar = Grammar::from_exprs(exprs);
+        let llm_tokens: Vec<Vec<u8>> = vec![b"a".to_vec(), b"b}".to_vec(), b"}".to_vec(), b"{".to_vec()];
+        let llm_token_map: LLMTokenMap = llm_tokens.iter().enumerate().map(|(i, token)| (token.clone(), LLMTokenID(i))).collect();
+        let grammar_constraint = GrammarConstraint::from_grammar(grammar, llm_token_map, 4, 4);
+
+        // The prefix ends in the middle of NAME, which the next LLM token can continue or end.
+        let mut grammar_constraint_state = grammar_constraint.clone().init();
+        grammar_constraint_state.feed_bytes(b"{na");
+        assert_eq!(grammar_constraint_state.get_mask(), bitvec_with_capacity_and_values(5, vec![0, 1, 2]));
+        grammar_constraint_state.feed_bytes(b"me}");
+        assert!(grammar_constraint_state.fully_matches());
+        assert_eq!(grammar_constraint_state.get_mask(), bitvec_with_capacity_and_values(5, vec![4]));
+
+        // Bytes that can't continue the grammar leave no states.
+        let mut grammar_constraint_state = grammar_constraint.clone().init();
+        grammar_constraint_state.feed_bytes(b"{}");
+        assert_eq!(grammar_constraint_state.get_mask(), bitvec_with_capacity_and_values(5, vec![]));
+
+        // The grammar starts right after the trigger, even in the middle of the text.
+        let mut grammar_constraint_state = grammar_constraint.clone().init_with_trigger(Some(b"Answer: "));
+        grammar_constraint_state.feed_bytes(b"Answer: {x");
+        assert!(grammar_constraint_state.is_active());
+        assert_eq!(grammar_constraint_state.get_mask(), bitvec_with_capacity_and_values(5, vec![0, 1, 2]));
+        grammar_constraint_state.commit(LLMTokenID(1));
+        assert!(!grammar_constraint_state.is_active());
+
+        // "b}" completes the trigger "b", but "}" can't start the grammar, so the grammar doesn't apply.
+        let mut grammar_constraint_state = grammar_constraint.init_with_trigger(Some(b"b"));
+        grammar_constraint_state.commit(LLMTokenID(1));
+        assert_eq!(grammar_constraint_state.mode(), &ConstraintMode::Finished);
+        assert_eq!(grammar_constraint_state.get_mask(), bitvec_with_capacity_and_values(5, (0..5).collect()));
+    }
+
+    #[test]
+    fn test_token_healing() {
+        // S -> NAME ":" where NAME is [a-z]+.
+        let exprs = vec![
+            (
+                "S".to_string(),
+                sequence(vec![regex(repeat1_fast(eat_u8_range_fast(b'a', b'z'))), regex(eat_u8_fast(b':'))]),
+            ),
+        ];
+        let grammar = Grammar::from_exprs(exprs);
+        let llm_tokens: Vec<Vec<u8>> = ["n", "na", "name", "me", ":", "name:", "a", "ame"].iter().map(|token| token.as_bytes().to_vec()).collect();
+        let llm_token_map: LLMTokenMap = llm_tokens.iter().enumerate().map(|(i, token)| (token.clone(), LLMTokenID(i))).collect();
+        let grammar_constraint = GrammarConstraint::from_grammar(grammar, llm_token_map, 8, 8);
+
+        // The prompt ends with "na", which the model would rather continue as "name".
+        let mut grammar_constraint_state = grammar_constraint.clone().init();
+        let prompt = [LLMTokenID(1)];
+        assert_eq!(grammar_constraint_state.heal_prompt(&prompt, 1), &[]);
+        assert_eq!(grammar_constraint_state.get_mask(), bitvec_with_capacity_and_values(9, vec![0, 1, 2, 5]));
+
+        // "n" only heals part of the removed text.
+        grammar_constraint_state.commit(LLMTokenID(0));
+        assert_eq!(grammar_constraint_state.get_mask(), bitvec_with_capacity_and_values(9, vec![6, 7]));
+        grammar_constraint_state.commit(LLMTokenID(7));
+        assert_eq!(grammar_constraint_state.get_mask(), bitvec_with_capacity_and_values(9, (0..8).collect()));
+
+        // Committing a token outside the healing mask can't restore the removed text, so nothing is allowed after it.
+        let mut mismatched_state = grammar_constraint.clone().init();
+        mismatched_state.heal_prompt(&prompt, 1);
+        mismatched_state.commit(LLMTokenID(3));
+        assert!(!mismatched_state.get_mask().any());
+        let mut mismatched_state = grammar_constraint.init();
+        mismatched_state.heal_prompt(&prompt, 1);
+        mismatched_state.feed_bytes(b"nb");
+        assert!(!mismatched_state.get_mask().any());
+
+        // The masks for "na" and "a" are cached.
+        let healing_masks = grammar_constraint_state.parent.healing_masks.lock().unwrap();
+        assert_eq!(healing_masks.keys().cloned().collect::<BTreeSet<_>>(), BTreeSet::from([b"na".to_vec(), b"a".to_vec()]));
+        assert_eq!(healing_masks[&b"a".to_vec()].iter_ones().collect::<Vec<_>>(), vec![6, 7]);
+    }
+
+    #[test]
+    fn test_forced_bytes_and_tokens() {
+        // S -> "{" "\"name\"" ":" " " NAME "}" where NAME is [a-z]+.
+        let exprs = vec![
+            (
+                "S".to_string(),
+                sequence(vec![
+                    regex(eat_u8_fast(b'{')),
+                    regex(eat_string_fast("\"name\"")),
+                    regex(eat_u8_fast(b':')),
+                    regex(eat_u8_fast(b' ')),
+                    regex(repeat1_fast(eat_u8_range_fast(b'a', b'z'))),
+                    regex(eat_u8_fast(b'}')),
+                ]),
+            ),
+        ];
+        let grammar = Grammar::from_exprs(exprs);
+        let llm_tokens: Vec<Vec<u8>> = ["{", "\"", "name", "\"name\"", ":", " ", ": ", "x", "y", "}"].iter().map(|token| token.as_bytes().to_vec()).collect();
+        let llm_token_map: LLMTokenMap = llm_tokens.iter().enumerate().map(|(i, token)| (token.clone(), LLMTokenID(i))).collect();
+        let grammar_constraint = GrammarConstraint::from_grammar(grammar, llm_token_map, 10, 10);
+        let mut grammar_constraint_state = grammar_constraint.init();
+
+        // Only "{" can come first, but then there are two ways to tokenize the key.
+        assert_eq!(grammar_constraint_state.forced_tokens(), vec![LLMTokenID(0)]);
+        assert_eq!(grammar_constraint_state.forced_bytes(), b"{\"name\": ".to_vec());
+        // The state is unchanged.
+        assert_eq!(grammar_constraint_state.get_mask(), bitvec_with_capacity_and_values(11, vec![0]));
+
+        grammar_constraint_state.feed_bytes(b"{\"name\": x");
+        assert_eq!(grammar_constraint_state.forced_bytes(), b"".to_vec());
+        grammar_constraint_state.commit(LLMTokenID(9));
+        // Only EOS can follow.
+        assert_eq!(grammar_constraint_state.forced_bytes(), b"".to_vec());
+        assert_eq!(grammar_constraint_state.forced_tokens(), vec![LLMTokenID(10)]);
+    }
+
+    #[test]
+    fn test_completions() {
+        // S -> "a" ("b" | "cd")
+        let exprs = vec![
+            (
+                "S".to_string(),
+                sequence(vec![
+                    regex(eat_u8_fast(b'a')),
+                    choice(vec![regex(eat_u8_fast(b'b')), regex(eat_string_fast("cd"))]),
+                ]),
+            ),
+        ];
+        let grammar = Grammar::from_exprs(exprs);
+        let llm_tokens: Vec<Vec<u8>> = ["a", "b", "c", "d", "ab", "cd"].iter().map(|token| token.as_bytes().to_vec()).collect();
+        let llm_token_map: LLMTokenMap = llm_tokens.iter().enumerate().map(|(i, token)| (token.clone(), LLMTokenID(i))).collect();
+        let grammar_constraint = GrammarConstraint::from_grammar(grammar, llm_token_map, 6, 6);
+        let mut grammar_constraint_state = grammar_constraint.init();
+
+        assert_eq!(
+            grammar_constraint_state.shortest_completions(3, 5),
+            vec![vec![LLMTokenID(4)], vec![LLMTokenID(0), LLMTokenID(1)], vec![LLMTokenID(0), LLMTokenID(5)]],
+        );
+        assert_eq!(grammar_constraint_state.shortest_completions(10, 1), vec![vec![LLMTokenID(4)]]);
+        assert_eq!(
+            grammar_constraint_state.enumerate_completions(10),
+            BTreeSet::from([b"ab".to_vec(), b"acd".to_vec()]),
+        );
+        assert_eq!(grammar_constraint_state.enumerate_completions(2), BTreeSet::from([b"ab".to_vec()]));
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..20 {
+            let completion = grammar_constraint_state.random_walk_completion(&mut rng, 5).unwrap();
+            assert!([b"ab".to_vec(), b"acd".to_vec()].contains(&grammar_constraint_state.decode(&completion)));
+            // "a" can't be completed in one token, so the walk backs out of it.
+            assert_eq!(grammar_constraint_state.random_walk_completion(&mut rng, 1), Some(vec![LLMTokenID(4)]));
+        }
+        assert_eq!(grammar_constraint_state.random_walk_completion(&mut rng, 0), None);
+        // The state is unchanged.
+        assert_eq!(grammar_constraint_state.get_mask(), bitvec_with_capacity_and_values(7, vec![0, 4]));
+    }
+
+    #[test]
+    fn test_enumerate_completions_merges_tokenizations() {
+        // S -> "a"+. A text of n bytes has exponentially many tokenizations, which all lead to the same state.
+        let grammar = Grammar::from_exprs(vec![("S".to_string(), regex(repeat1_fast(eat_u8_fast(b'a'))))]);
+        let llm_tokens: Vec<Vec<u8>> = ["a", "aa", "aaa"].iter().map(|token| token.as_bytes().to_vec()).collect();
+        let llm_token_map: LLMTokenMap = llm_tokens.iter().enumerate().map(|(i, token)| (token.clone(), LLMTokenID(i))).collect();
+        let mut grammar_constraint_state = GrammarConstraint::from_grammar(grammar, llm_token_map, 3, 3).init();
+        let completions = grammar_constraint_state.enumerate_completions(40);
+        assert_eq!(completions, (1..=40).map(|len| vec![b'a'; len]).collect());
+    }
+
+    #[test]
+    fn test_consistency() {
+        let grammars = vec![
+            // S -> "a" ("b" | "cd")
+            (
+                Grammar::from_exprs(vec![(
+                    "S".to_string(),
+                    sequence(vec![
+                        regex(eat_u8_fast(b'a')),
+                        choice(vec![regex(eat_u8_fast(b'b')), regex(eat_string_fast("cd"))]),
+                    ]),
+                )]),
+                vec!["a", "b", "c", "d", "ab", "cd", "bc"],
+            ),
+            // S -> "if" IDENT, with spaces ignored.
+            (
+                Grammar::from_exprs_with_ignore(
+                    vec![(
+                        "S".to_string(),
+                        sequence(vec![
+                            regex(eat_string_fast("if")),
+                            regex(repeat1_fast(eat_u8_range_fast(b'a', b'z'))),
+                        ]),
+                    )],
+                    vec![eat_u8(b' ')],
+                ),
+                vec!["i", "f", "if", " if", "x", " x", " ", "fi"],
+            ),
+            // S -> "(" S ")" | ""
+            (
+                Grammar::from_exprs(vec![(
+                    "S".to_string(),
+                    optional(sequence(vec![regex(eat_u8_fast(b'(')), r#ref("S"), regex(eat_u8_fast(b')'))])),
+                )]),
+                vec!["(", ")", "()", "((", "))", ")("],
+            ),
+            // S -> A S "a" | "b", A -> "c" | "", i.e. hidden left recursion.
+            (
+                Grammar::from_exprs(vec![
+                    (
+                        "S".to_string(),
+                        choice(vec![
+                            sequence(vec![r#ref("A"), r#ref("S"), regex(eat_u8_fast(b'a'))]),
+                            regex(eat_u8_fast(b'b')),
+                        ]),
+                    ),
+                    ("A".to_string(), optional(regex(eat_u8_fast(b'c')))),
+                ]),
+                vec!["a", "b", "c", "ba", "cb"],
+            ),
+            // S -> NAME | CALLEE "(" ")", where NAME is not followed by '(' and CALLEE is.
+            (
+                Grammar::from_exprs(vec![(
+                    "S".to_string(),
+                    choice(vec![
+                        regex(seq_fast![repeat1_fast(eat_u8_range_fast(b'a', b'z')), negative_lookahead(eat_u8_fast(b'('))]),
+                        sequence(vec![
+                            regex(seq_fast![repeat1_fast(eat_u8_range_fast(b'a', b'z')), lookahead(eat_u8_fast(b'('))]),
+                            regex(eat_u8_fast(b'(')),
+                            regex(eat_u8_fast(b')')),
+                        ]),
+                    ]),
+                )]),
+                vec!["f", "foo", "(", ")", "foo(", "()", "o("],
+            ),
+        ];
+        for (grammar, llm_tokens) in grammars {
+            let llm_token_map: LLMTokenMap = llm_tokens.iter().enumerate().map(|(i, token)| (token.as_bytes().to_vec(), LLMTokenID(i))).collect();
+            let grammar_constraint = GrammarConstraint::from_grammar(grammar.clone(), llm_token_map.clone(), llm_tokens.len(), llm_tokens.len());
+            let mut grammar_constraint_state = grammar_constraint.init();
+            let mut rng = StdRng::seed_from_u64(0);
+            assert_eq!(check_consistency(&mut grammar_constraint_state, &mut rng, 10, 6), Ok(()));
+
+            // The compiled tokenizer must give the same results.
+            let grammar_constraint = GrammarConstraint::from_grammar(grammar.compile(), llm_token_map, llm_tokens.len(), llm_tokens.len());
+            let mut grammar_constraint_state = grammar_constraint.init();
+            let mut rng = StdRng::seed_from_u64(0);
+            assert_eq!(check_consistency(&mut grammar_constraint_state, &mut rng, 10, 6), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_reference_constraint() {
+        // S -> NAME | NAME CALL NAME, where NAME is [a-z]+ and CALL can only be produced by a special token.
+        let name = repeat1_fast(eat_u8_range_fast(b'a', b'z'));
+        let call = crate::finite_automata::eat_u8_set(crate::u8set::U8Set::none());
+        let exprs = vec![
+            (
+                "S".to_string(),
+                choice(vec![
+                    regex(name.clone()),
+                    sequence(vec![regex(name.clone()), regex(call.clone()), regex(name)]),
+                ]),
+            ),
+        ];
+        let grammar = Grammar::from_exprs(exprs);
+        let call_token_id = *grammar.terminal_expr_to_group_id.get_by_left(&call).unwrap();
+        let llm_tokens: Vec<Vec<u8>> = vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec(), b"1".to_vec()];
+        let llm_token_map: LLMTokenMap = llm_tokens.iter().enumerate().map(|(i, token)| (token.clone(), LLMTokenID(i))).collect();
+        let special_tokens = SpecialTokenPolicy {
+            eos_llm_token_ids: BTreeSet::from([LLMTokenID(4), LLMTokenID(5)]),
+            forbidden_llm_token_ids: BTreeSet::from([LLMTokenID(2)]),
+            terminal_llm_token_ids: BTreeMap::from([(LLMTokenID(6), call_token_id)]),
+            ..Default::default()
+        };
+        let mut reference = ReferenceConstraint::from_grammar_with_special_tokens(grammar.clone(), llm_token_map.clone(), special_tokens.clone(), 6);
+        let grammar_constraint = GrammarConstraint::from_grammar_with_special_tokens(grammar, llm_token_map, special_tokens, 6);
+        let mut grammar_constraint_state = grammar_constraint.init();
+
+        assert_eq!(reference.get_mask(), bitvec_with_capacity_and_values(7, vec![0, 1]));
+        for llm_token_id in [0, 6, 1] {
+            reference.commit(LLMTokenID(llm_token_id));
+            grammar_constraint_state.commit(LLMTokenID(llm_token_id));
+            assert_eq!(reference.get_mask(), grammar_constraint_state.get_mask());
+        }
+        assert_eq!(reference.text(), b"ab".to_vec());
+        assert!(reference.fully_matches());
+        // A token that isn't allowed leaves nothing allowed.
+        reference.commit(LLMTokenID(3));
+        assert!(!reference.get_mask().any());
+
+        let mut grammar_constraint_state = grammar_constraint_state.parent.init();
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(check_consistency(&mut grammar_constraint_state, &mut rng, 10, 6), Ok(()));
+    }
+
+    #[test]
+    fn test_reference_constraint_llm_token_boundaries() {
+        // S -> [bc]+ [bc]. Within an LLM token the first terminal matches greedily, but it can also end at the end of
+        // an LLM token, so "b" then "b" is a complete match although the lexer reads the text "bb" as one [bc]+.
+        let bc = crate::finite_automata::eat_u8_set(crate::u8set::U8Set::from_bytes(b"bc"));
+        let exprs = vec![("S".to_string(), sequence(vec![regex(crate::finite_automata::rep1(bc.clone())), regex(bc)]))];
+        let grammar = Grammar::from_exprs(exprs);
+        let llm_tokens: Vec<Vec<u8>> = vec![b"b".to_vec(), b"c".to_vec(), b"bb".to_vec()];
+        let llm_token_map: LLMTokenMap = llm_tokens.iter().enumerate().map(|(i, token)| (token.clone(), LLMTokenID(i))).collect();
+        let mut reference = ReferenceConstraint::from_grammar(grammar.clone(), llm_token_map.clone(), 3, 3);
+        let mut grammar_constraint_state = GrammarConstraint::from_grammar(grammar, llm_token_map, 3, 3).init();
+        for llm_token_id in [0, 0] {
+            reference.commit(LLMTokenID(llm_token_id));
+            grammar_constraint_state.commit(LLMTokenID(llm_token_id));
+            assert_eq!(reference.get_mask(), grammar_constraint_state.get_mask());
+        }
+        assert!(reference.fully_matches());
+    }
+
     #[test]
     fn test_grammar_from_exprs_very_simple() {
         let exprs = vec![
@@ -554,13 +1248,13 @@ mod tests {
 
         print_precomputed(&grammar_constraint_state.parent.precomputed);
 
-        for (tokenizer_state, root) in &grammar_constraint_state.parent.precomputed {
+        for (tokenizer_state, trie) in &grammar_constraint_state.parent.precomputed {
             crate::dbgprintln!("Tokenizer state: {}", tokenizer_state.0);
-            for node in TrieNode::all_nodes(Arc::new(Mutex::new(root.clone()))) {
-                crate::dbgprintln!("Node address: {:p}, value: {:?}", Arc::as_ptr(&node), node.try_lock().unwrap().value);
-                // print edge values and destination addresses
-                for (edge, dest) in node.try_lock().unwrap().children() {
-                    crate::dbgprintln!("    Edge value: {:?}, destination address: {:p}", edge, Arc::as_ptr(&dest));
+            for node in trie.nodes() {
+                crate::dbgprintln!("Node {}, value: {:?}", node, trie.value(node));
+                // print edge values and destinations
+                for (edge, dest) in trie.children(node) {
+                    crate::dbgprintln!("    Edge value: {:?}, destination: {}", edge, dest);
                 }
             }
         }
@@ -592,6 +1286,254 @@ mod tests {
         assert_eq!(mask, expected_mask);
     }
 
+    #[test]
+    fn test_ignore_grammar_terminal() {
+        // S -> " " "a", with spaces ignored: every " " would be skipped, so S could never match.
+        let exprs = vec![("S".to_string(), sequence(vec![regex(eat_u8(b' ')), regex(eat_u8(b'a'))]))];
+        assert!(matches!(Grammar::try_from_exprs_with_ignore(exprs, vec![eat_u8(b' ')]), Err(Error::InvalidGrammar(_))));
+
+        // Listing the same ignored terminal twice is fine.
+        let exprs = vec![("S".to_string(), regex(eat_u8(b'a')))];
+        let grammar = Grammar::try_from_exprs_with_ignore(exprs, vec![eat_u8(b' '), eat_u8(b' ')]).unwrap();
+        assert_eq!(grammar.ignore_group_ids.len(), 1);
+    }
+
+    #[test]
+    fn test_grammar_from_exprs_with_ignore() {
+        // E -> "a" "b", with spaces and `#` comments allowed between any two tokens.
+        let exprs = vec![
+            (
+                "E".to_string(),
+                sequence(vec![
+                    regex(eat_u8(b'a')),
+                    regex(eat_u8(b'b')),
+                ]),
+            ),
+        ];
+        let ignore = vec![
+            eat_u8(b' '),
+            seq_fast!(eat_u8_fast(b'#'), repeat0_fast(eat_u8_negation_fast(b'\n')), eat_u8_fast(b'\n')),
+        ];
+
+        let grammar = Grammar::from_exprs_with_ignore(exprs, ignore);
+        assert_eq!(grammar.ignore_group_ids.len(), 2);
+
+        let llm_tokens: Vec<Vec<u8>> = vec![b"a".to_vec(), b"b".to_vec(), b" ".to_vec(), b"#".to_vec(), b"\n".to_vec(), b"a b".to_vec()];
+        let llm_token_map: LLMTokenMap = llm_tokens.iter().enumerate().map(|(i, token)| (token.clone(), LLMTokenID(i))).collect();
+        let eof_llm_token_id = llm_tokens.len();
+        let max_llm_token_id = llm_tokens.len();
+        let grammar_constraint = GrammarConstraint::from_grammar(grammar, llm_token_map.clone(), eof_llm_token_id, max_llm_token_id);
+        let mut grammar_constraint_state = grammar_constraint.init();
+
+        macro_rules! llm_token_vec {
+            ($($token:expr),* $(,)?) => {
+                vec![
+                    $(
+                        llm_token_map.get_by_left(&$token.to_vec()).unwrap().0,
+                    )*
+                ]
+            }
+        }
+
+        // Leading whitespace and comments are allowed.
+        let mask = grammar_constraint_state.get_mask();
+        let expected_mask = bitvec_with_capacity_and_values(llm_tokens.len() + 1, llm_token_vec!(b"a", b" ", b"#", b"a b"));
+        assert_eq!(mask, expected_mask);
+
+        // Inside a comment, anything but a newline continues the comment.
+        let prefill: Vec<_> = llm_token_vec!(b"a", b" ", b"#").into_iter().map(LLMTokenID).collect();
+        grammar_constraint_state.commit_many(&prefill);
+        let mask = grammar_constraint_state.get_mask();
+        let expected_mask = bitvec_with_capacity_and_values(llm_tokens.len() + 1, llm_token_vec!(b"a", b"b", b" ", b"#", b"\n", b"a b"));
+        assert_eq!(mask, expected_mask);
+
+        // After the comment ends, the parser still expects "b".
+        let terminals: Vec<_> = llm_token_vec!(b"\n").into_iter().map(LLMTokenID).collect();
+        grammar_constraint_state.commit_many(&terminals);
+        let mask = grammar_constraint_state.get_mask();
+        let expected_mask = bitvec_with_capacity_and_values(llm_tokens.len() + 1, llm_token_vec!(b"b", b" ", b"#"));
+        assert_eq!(mask, expected_mask);
+
+        // Trailing whitespace is allowed before EOF.
+        let terminals: Vec<_> = llm_token_vec!(b"b", b" ").into_iter().map(LLMTokenID).collect();
+        grammar_constraint_state.commit_many(&terminals);
+        let mask = grammar_constraint_state.get_mask();
+        let mut expected_mask = bitvec_with_capacity_and_values(llm_tokens.len() + 1, llm_token_vec!(b" ", b"#"));
+        expected_mask.set(llm_tokens.len(), true);
+        assert_eq!(mask, expected_mask);
+    }
+
+    #[test]
+    fn test_contextual_lexing_keyword_vs_identifier() {
+        // S -> "if" IDENT, where "if" also matches IDENT. Only the parse context decides which one it is.
+        let exprs = vec![
+            (
+                "S".to_string(),
+                sequence(vec![
+                    regex(eat_string_fast("if")),
+                    regex(repeat1_fast(eat_u8_range_fast(b'a', b'z'))),
+                ]),
+            ),
+        ];
+        let grammar = Grammar::from_exprs_with_ignore(exprs, vec![eat_u8(b' ')]);
+
+        let llm_tokens: Vec<Vec<u8>> = vec![b"if".to_vec(), b" if".to_vec(), b"x".to_vec(), b" x".to_vec()];
+        let llm_token_map: LLMTokenMap = llm_tokens.iter().enumerate().map(|(i, token)| (token.clone(), LLMTokenID(i))).collect();
+        let eof_llm_token_id = llm_tokens.len();
+        let max_llm_token_id = llm_tokens.len();
+        let grammar_constraint = GrammarConstraint::from_grammar(grammar, llm_token_map.clone(), eof_llm_token_id, max_llm_token_id);
+        let mut grammar_constraint_state = grammar_constraint.init();
+
+        macro_rules! llm_token_vec {
+            ($($token:expr),* $(,)?) => {
+                vec![
+                    $(
+                        llm_token_map.get_by_left(&$token.to_vec()).unwrap().0,
+                    )*
+                ]
+            }
+        }
+
+        // At the start only the keyword is acceptable, so "x" (which can only be an identifier) is rejected.
+        let mask = grammar_constraint_state.get_mask();
+        let expected_mask = bitvec_with_capacity_and_values(llm_tokens.len() + 1, llm_token_vec!(b"if", b" if"));
+        assert_eq!(mask, expected_mask);
+
+        // The identifier edges are pruned, so the walk never visits the nodes below them.
+        let trie = &grammar_constraint_state.parent.precomputed[&StateID(0)];
+        let mut visited_nodes = 0;
+        grammar_constraint_state.parent.walk_trie(trie, &grammar_constraint_state.states[0].0, |_, _| visited_nodes += 1);
+        assert!(visited_nodes < trie.num_nodes(), "visited {} of {} nodes", visited_nodes, trie.num_nodes());
+
+        // After the keyword, "if" lexes as an identifier. Since the tokenizer is ambiguous, "ifx" may also
+        // be split as the keyword followed by the identifier "x".
+        let terminals: Vec<_> = llm_token_vec!(b"if").into_iter().map(LLMTokenID).collect();
+        grammar_constraint_state.commit_many(&terminals);
+        let mask = grammar_constraint_state.get_mask();
+        let expected_mask = bitvec_with_capacity_and_values(llm_tokens.len() + 1, llm_token_vec!(b"if", b" if", b"x", b" x"));
+        assert_eq!(mask, expected_mask);
+
+        let terminals: Vec<_> = llm_token_vec!(b" if").into_iter().map(LLMTokenID).collect();
+        grammar_constraint_state.commit_many(&terminals);
+        let mask = grammar_constraint_state.get_mask();
+        let mut expected_mask = bitvec_with_capacity_and_values(llm_tokens.len() + 1, llm_token_vec!(b"if", b"x"));
+        expected_mask.set(llm_tokens.len(), true);
+        assert_eq!(mask, expected_mask);
+    }
+
+    #[test]
+    fn test_validate_terminals() {
+        let exprs = vec![
+            (
+                "S".to_string(),
+                sequence(vec![
+                    regex(eat_string_fast("if")),
+                    regex(repeat1_fast(eat_u8_range_fast(b'a', b'z'))),
+                    regex(repeat1_fast(eat_u8_range_fast(b'0', b'9'))),
+                ]),
+            ),
+        ];
+        let grammar = Grammar::from_exprs(exprs);
+        assert_eq!(grammar.validate_terminals(), vec!["Terminals __regex_0 and __regex_1 overlap: both match \"if\"".to_string()]);
+    }
+
+    #[test]
+    fn test_stats() {
+        // E -> E "+" E | "a" is ambiguous, so the parse table has a split on "+".
+        let exprs = vec![
+            (
+                "E".to_string(),
+                choice(vec![
+                    sequence(vec![r#ref("E"), regex(eat_u8_fast(b'+')), r#ref("E")]),
+                    regex(eat_u8_fast(b'a')),
+                ]),
+            ),
+        ];
+        let grammar = Grammar::from_exprs(exprs);
+        let llm_tokens: Vec<Vec<u8>> = vec![b"a".to_vec(), b"+".to_vec(), b"a+".to_vec(), b"a+a".to_vec()];
+        let llm_token_map: LLMTokenMap = llm_tokens.iter().enumerate().map(|(i, token)| (token.clone(), LLMTokenID(i))).collect();
+        let grammar_constraint = GrammarConstraint::from_grammar(grammar, llm_token_map, llm_tokens.len(), llm_tokens.len());
+
+        let stats = grammar_constraint.stats(2);
+        assert_eq!(stats.num_tokenizer_states, grammar_constraint.tokenizer.max_state());
+        assert_eq!(stats.num_lr_states, grammar_constraint.parser.stage_7_table.len());
+        assert_eq!(stats.num_lr_splits, 1);
+        assert_eq!(stats.trie_nodes.keys().collect::<Vec<_>>(), grammar_constraint.precomputed.keys().collect::<Vec<_>>());
+        assert!(stats.trie_edges.values().sum::<usize>() >= stats.trie_nodes.len());
+        assert!(stats.bitset_bytes > 0);
+        assert!(stats.unique_trie_nodes <= stats.trie_nodes.values().sum::<usize>());
+        assert_eq!(stats.largest_llm_token_fan_outs.len(), 2);
+        assert!(stats.largest_llm_token_fan_outs[0].1 >= stats.largest_llm_token_fan_outs[1].1);
+        assert!(stats.to_string().contains("LR states: "));
+
+        // Tries loaded one at a time each get their own arena, and every arena counts.
+        let mut unshared_constraint = grammar_constraint.clone();
+        for (state_id, trie) in unshared_constraint.precomputed.iter_mut() {
+            let saved = crate::precompute::save_precomputed(&BTreeMap::from([(*state_id, trie.clone())]));
+            *trie = crate::precompute::load_precomputed(&saved, grammar_constraint.max_llm_token_id).unwrap().remove(state_id).unwrap();
+        }
+        let unshared_stats = unshared_constraint.stats(2);
+        assert!(grammar_constraint.precomputed.len() > 1);
+        assert_eq!(unshared_stats.unique_trie_nodes, stats.unique_trie_nodes * grammar_constraint.precomputed.len());
+        assert_eq!(unshared_stats.largest_llm_token_fan_outs, stats.largest_llm_token_fan_outs);
+    }
+
+    #[test]
+    fn test_lookahead_across_llm_token_boundary() {
+        // S -> NAME | CALLEE "(" ")", where NAME is not followed by '(' and CALLEE is.
+        let name = repeat1_fast(eat_u8_range_fast(b'a', b'z'));
+        let exprs = vec![
+            (
+                "S".to_string(),
+                choice(vec![
+                    regex(seq_fast![name.clone(), negative_lookahead(eat_u8_fast(b'('))]),
+                    sequence(vec![
+                        regex(seq_fast![name, lookahead(eat_u8_fast(b'('))]),
+                        regex(eat_u8_fast(b'(')),
+                        regex(eat_u8_fast(b')')),
+                    ]),
+                ]),
+            ),
+        ];
+        let grammar = Grammar::from_exprs(exprs);
+
+        let llm_tokens: Vec<Vec<u8>> = vec![b"foo".to_vec(), b"(".to_vec(), b")".to_vec(), b"foo(".to_vec()];
+        let llm_token_map: LLMTokenMap = llm_tokens.iter().enumerate().map(|(i, token)| (token.clone(), LLMTokenID(i))).collect();
+        let eof_llm_token_id = llm_tokens.len();
+        let max_llm_token_id = llm_tokens.len();
+        let grammar_constraint = GrammarConstraint::from_grammar(grammar, llm_token_map.clone(), eof_llm_token_id, max_llm_token_id);
+        let mut grammar_constraint_state = grammar_constraint.init();
+
+        macro_rules! llm_token_vec {
+            ($($token:expr),* $(,)?) => {
+                vec![
+                    $(
+                        llm_token_map.get_by_left(&$token.to_vec()).unwrap().0,
+                    )*
+                ]
+            }
+        }
+
+        let mask = grammar_constraint_state.get_mask();
+        let expected_mask = bitvec_with_capacity_and_values(llm_tokens.len() + 1, llm_token_vec!(b"foo", b"foo("));
+        assert_eq!(mask, expected_mask);
+
+        // After "foo", whether it's a NAME or a CALLEE is decided by the next LLM token: "(" makes it a CALLEE,
+        // ")" would make it a NAME (which can't be followed by ")"), and EOF makes it a NAME.
+        let terminals: Vec<_> = llm_token_vec!(b"foo").into_iter().map(LLMTokenID).collect();
+        grammar_constraint_state.commit_many(&terminals);
+        let mask = grammar_constraint_state.get_mask();
+        let mut expected_mask = bitvec_with_capacity_and_values(llm_tokens.len() + 1, llm_token_vec!(b"foo", b"(", b"foo("));
+        expected_mask.set(eof_llm_token_id, true);
+        assert_eq!(mask, expected_mask);
+
+        let terminals: Vec<_> = llm_token_vec!(b"(").into_iter().map(LLMTokenID).collect();
+        grammar_constraint_state.commit_many(&terminals);
+        let mask = grammar_constraint_state.get_mask();
+        let expected_mask = bitvec_with_capacity_and_values(llm_tokens.len() + 1, llm_token_vec!(b")"));
+        assert_eq!(mask, expected_mask);
+    }
+
     #[test]
     fn test_precompute_for_python_name_token() {
         // ignore = rep(choice([
@@ -630,10 +1572,10 @@ mod tests {
         let llm_token_map: LLMTokenMap = llm_tokens.iter().enumerate().map(|(i, token)| (token.clone(), LLMTokenID(i))).collect();
         let eof_llm_token_id = llm_tokens.len();
         let max_llm_token_id = llm_tokens.len();
-        let precomputed = precompute(&tokenizer, &llm_token_map, LLMTokenID(eof_llm_token_id), max_llm_token_id);
-        print_precomputed(&precomputed);
+        let precomputed = precompute(&tokenizer, &llm_token_map, LLMTokenID(eof_llm_token_id), max_llm_token_id).unwrap();
+        print_precomputed(&freeze_precomputed(&precomputed));
         println!("Done precomputing");
-        // print_precomputed(&precomputed);
+        // print_precomputed(&freeze_precomputed(&precomputed));
     }
 
     #[test]
@@ -663,8 +1605,8 @@ mod tests {
         let llm_token_map: LLMTokenMap = llm_tokens.iter().enumerate().map(|(i, token)| (token.clone(), LLMTokenID(i))).collect();
         let eof_llm_token_id = llm_tokens.len();
         let max_llm_token_id = llm_tokens.len();
-        let precomputed = precompute(&tokenizer, &llm_token_map, LLMTokenID(eof_llm_token_id), max_llm_token_id);
-        print_precomputed(&precomputed);
+        let precomputed = precompute(&tokenizer, &llm_token_map, LLMTokenID(eof_llm_token_id), max_llm_token_id).unwrap();
+        print_precomputed(&freeze_precomputed(&precomputed));
         println!("Done precomputing");
     }
 }
\ No newline at end of file
@@ -0,0 +1,466 @@
+// src/lazy_regex.rs
+use crate::finite_automata::{byte_classes, ExprGroups, GroupID, NFA};
+use crate::precompute::{ExecuteResult, Token, TokenID, Tokenizer};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+
+/// Marks a transition that hasn't been computed yet.
+const UNKNOWN: u32 = u32::MAX;
+/// Marks a transition to a set of NFA states that can't lead to any match.
+const DEAD: u32 = u32::MAX - 1;
+
+/// A tokenizer that determinizes its NFA lazily, building DFA states only when execution (or the precompute)
+/// reaches them. Use this instead of `Regex` when eager subset construction produces too many states.
+///
+/// DFA states built during execution live in a cache of at most `max_cached_states` states (with their NFA state
+/// sets and transitions). When the cache is full, it's cleared and states are rebuilt on demand.
+///
+/// The tokenizer state IDs it hands out (the initial state and each `new_state` of `execute_from_state`) are
+/// stable: once a set of NFA states has been given an ID, it keeps it, even across cache clears. Only the states
+/// execution stops in get an ID, not every state it passes through.
+///
+/// Stable states can't be evicted, since callers (e.g. the precompute and constraint states) keep their IDs. So each
+/// keeps only its set of NFA states, and everything derived from it (finalizers, reachable groups, transitions) lives
+/// in the cache and is rebuilt on demand. `max_cached_states` bounds all but the NFA state sets, which grow with the
+/// number of distinct states execution stops in. Use `num_states` to monitor them.
+///
+/// Matching semantics are the same as for `Regex` (greedy and non-greedy groups, lookahead), though state IDs
+/// differ, since the states aren't minimized.
+#[derive(Debug)]
+pub struct LazyRegex {
+    nfa: NFA,
+    epsilon_closures: Vec<Vec<usize>>,
+    /// Groups reachable from each NFA state, including its own finalizers.
+    reachable_group_ids: Vec<BTreeSet<GroupID>>,
+    /// Groups reachable from each NFA state after consuming at least one more byte.
+    reachable_group_ids_after_u8: Vec<BTreeSet<GroupID>>,
+    byte_to_class: [u8; 256],
+    class_representatives: Vec<u8>,
+    non_greedy_finalizers: BTreeSet<GroupID>,
+    max_cached_states: usize,
+    cache: Mutex<LazyDFACache>,
+    /// Every state ID handed out so far. Not bounded by `max_cached_states`.
+    stable_states: Mutex<StableStates>,
+}
+
+#[derive(Debug, Clone)]
+struct LazyDFAState {
+    finalizers: BTreeSet<GroupID>,
+    eof_finalizers: BTreeSet<GroupID>,
+    possible_group_ids: BTreeSet<GroupID>,
+    has_transitions: bool,
+}
+
+/// DFA states by ID, each with the set of NFA states it stands for.
+#[derive(Debug, Clone, Default)]
+struct LazyDFAStates {
+    nfa_state_sets: Vec<Vec<usize>>,
+    states: Vec<LazyDFAState>,
+    state_ids: HashMap<Vec<usize>, usize>,
+}
+
+/// The NFA state set of each stable state ID. Each set is stored once, shared by both fields.
+#[derive(Debug, Clone, Default)]
+struct StableStates {
+    nfa_state_sets: Vec<Arc<[usize]>>,
+    state_ids: HashMap<Arc<[usize]>, usize>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct LazyDFACache {
+    states: LazyDFAStates,
+    /// Transitions for each state, indexed by byte class.
+    transitions: Vec<Vec<u32>>,
+    num_cache_clears: usize,
+}
+
+impl Clone for LazyRegex {
+    fn clone(&self) -> Self {
+        LazyRegex {
+            nfa: self.nfa.clone(),
+            epsilon_closures: self.epsilon_closures.clone(),
+            reachable_group_ids: self.reachable_group_ids.clone(),
+            reachable_group_ids_after_u8: self.reachable_group_ids_after_u8.clone(),
+            byte_to_class: self.byte_to_class,
+            class_representatives: self.class_representatives.clone(),
+            non_greedy_finalizers: self.non_greedy_finalizers.clone(),
+            max_cached_states: self.max_cached_states,
+            cache: Mutex::new(self.cache.lock().unwrap().clone()),
+            stable_states: Mutex::new(self.stable_states.lock().unwrap().clone()),
+        }
+    }
+}
+
+impl ExprGroups {
+    /// Builds a lazily determinized tokenizer. See `LazyRegex`.
+    pub fn build_lazy(self, max_cached_states: usize) -> LazyRegex {
+        LazyRegex::new(self.build_nfa(), max_cached_states)
+    }
+}
+
+impl LazyDFAStates {
+    /// Returns the ID of the state for `nfa_state_set`, adding it with `make_state` if it's new.
+    fn intern(&mut self, nfa_state_set: &[usize], make_state: impl FnOnce() -> LazyDFAState) -> usize {
+        if let Some(&state_id) = self.state_ids.get(nfa_state_set) {
+            return state_id;
+        }
+        let state_id = self.states.len();
+        self.states.push(make_state());
+        self.state_ids.insert(nfa_state_set.to_vec(), state_id);
+        self.nfa_state_sets.push(nfa_state_set.to_vec());
+        state_id
+    }
+}
+
+impl StableStates {
+    /// Returns the stable ID of `nfa_state_set`, handing out a new one if it's new.
+    fn intern(&mut self, nfa_state_set: &[usize]) -> usize {
+        if let Some(&state_id) = self.state_ids.get(nfa_state_set) {
+            return state_id;
+        }
+        let state_id = self.nfa_state_sets.len();
+        let nfa_state_set: Arc<[usize]> = nfa_state_set.into();
+        self.state_ids.insert(nfa_state_set.clone(), state_id);
+        self.nfa_state_sets.push(nfa_state_set);
+        state_id
+    }
+}
+
+impl LazyRegex {
+    pub fn new(nfa: NFA, max_cached_states: usize) -> Self {
+        let epsilon_closures: Vec<Vec<usize>> = nfa.compute_epsilon_closures()
+            .into_iter()
+            .map(|closure| closure.into_iter().collect())
+            .collect();
+
+        // Propagate finalizers backwards through the NFA to find the groups reachable from each state.
+        let mut predecessors: Vec<Vec<usize>> = vec![Vec::new(); nfa.states.len()];
+        for (state_index, closure) in epsilon_closures.iter().enumerate() {
+            for &closure_state in closure {
+                for (_u8, targets) in nfa.states[closure_state].transitions.iter() {
+                    for &target in targets {
+                        predecessors[target].push(state_index);
+                    }
+                }
+            }
+        }
+        let mut reachable_group_ids: Vec<BTreeSet<GroupID>> = epsilon_closures.iter()
+            .map(|closure| closure.iter().flat_map(|&state| nfa.states[state].finalizers.iter().cloned()).collect())
+            .collect();
+        let mut worklist: Vec<usize> = (0..nfa.states.len()).collect();
+        while let Some(state_index) = worklist.pop() {
+            let group_ids = reachable_group_ids[state_index].clone();
+            for &predecessor in &predecessors[state_index] {
+                let old_len = reachable_group_ids[predecessor].len();
+                reachable_group_ids[predecessor].extend(group_ids.iter());
+                if reachable_group_ids[predecessor].len() > old_len {
+                    worklist.push(predecessor);
+                }
+            }
+        }
+        let reachable_group_ids_after_u8 = nfa.states.iter()
+            .map(|state| {
+                state.transitions.iter()
+                    .flat_map(|(_u8, targets)| targets.iter().flat_map(|&target| reachable_group_ids[target].iter().cloned()))
+                    .collect()
+            })
+            .collect();
+
+        let classes = byte_classes(nfa.states.iter().map(|state| &state.transitions));
+        let mut byte_to_class = [0; 256];
+        for (class_index, class) in classes.iter().enumerate() {
+            for byte in class.iter() {
+                byte_to_class[byte as usize] = class_index as u8;
+            }
+        }
+        let class_representatives = classes.iter().map(|class| class.iter().next().unwrap()).collect();
+        let non_greedy_finalizers = nfa.states.iter().flat_map(|state| state.non_greedy_finalizers.iter().cloned()).collect();
+
+        let lazy_regex = LazyRegex {
+            epsilon_closures,
+            reachable_group_ids,
+            reachable_group_ids_after_u8,
+            byte_to_class,
+            class_representatives,
+            non_greedy_finalizers,
+            max_cached_states,
+            cache: Mutex::new(LazyDFACache::default()),
+            stable_states: Mutex::new(StableStates::default()),
+            nfa,
+        };
+        // The initial state always gets ID 0.
+        let start_state_set = &lazy_regex.epsilon_closures[lazy_regex.nfa.start_state];
+        lazy_regex.stable_states.lock().unwrap().intern(start_state_set);
+        lazy_regex
+    }
+
+    /// Returns the number of tokenizer state IDs handed out so far. Each keeps its NFA state set for as long as the
+    /// `LazyRegex` lives, so this measures the memory that `max_cached_states` doesn't bound.
+    pub fn num_states(&self) -> usize {
+        self.stable_states.lock().unwrap().nfa_state_sets.len()
+    }
+
+    /// Returns the number of DFA states in the cache. This is at most `max_cached_states` (or 1 if that's 0).
+    pub fn num_cached_states(&self) -> usize {
+        self.cache.lock().unwrap().states.states.len()
+    }
+
+    /// Returns how many times the cache has been cleared for going over its bound.
+    pub fn num_cache_clears(&self) -> usize {
+        self.cache.lock().unwrap().num_cache_clears
+    }
+
+    fn dfa_state(&self, nfa_state_set: &[usize]) -> LazyDFAState {
+        let mut state = LazyDFAState {
+            finalizers: BTreeSet::new(),
+            eof_finalizers: BTreeSet::new(),
+            possible_group_ids: BTreeSet::new(),
+            has_transitions: false,
+        };
+        for &nfa_state in nfa_state_set {
+            state.finalizers.extend(self.nfa.states[nfa_state].finalizers.iter().cloned());
+            state.eof_finalizers.extend(self.nfa.states[nfa_state].eof_finalizers.iter().cloned());
+            state.possible_group_ids.extend(self.reachable_group_ids[nfa_state].iter().cloned());
+            state.has_transitions |= !self.reachable_group_ids_after_u8[nfa_state].is_empty();
+        }
+        state
+    }
+
+    /// Returns the cache ID of the state for `nfa_state_set`, clearing the cache first if it's full.
+    fn intern_cached_state(&self, cache: &mut LazyDFACache, nfa_state_set: &[usize]) -> usize {
+        if let Some(&state_id) = cache.states.state_ids.get(nfa_state_set) {
+            return state_id;
+        }
+        if cache.states.states.len() >= self.max_cached_states {
+            cache.states = LazyDFAStates::default();
+            cache.transitions.clear();
+            cache.num_cache_clears += 1;
+        }
+        cache.transitions.push(vec![UNKNOWN; self.class_representatives.len()]);
+        cache.states.intern(nfa_state_set, || self.dfa_state(nfa_state_set))
+    }
+
+    /// Returns `f` of the DFA state of stable state `state`, which is rebuilt in the cache if it's been dropped.
+    fn with_stable_state<R>(&self, state: usize, f: impl FnOnce(&LazyDFAState) -> R) -> R {
+        let nfa_state_set = self.stable_states.lock().unwrap().nfa_state_sets[state].clone();
+        let mut cache = self.cache.lock().unwrap();
+        let state = self.intern_cached_state(&mut cache, &nfa_state_set);
+        f(&cache.states.states[state])
+    }
+
+    /// Returns the cache ID of the state after `byte`. This may clear the cache, invalidating `state`.
+    fn next_state(&self, cache: &mut LazyDFACache, state: usize, byte: u8) -> Option<usize> {
+        let class = self.byte_to_class[byte as usize] as usize;
+        let next_state = cache.transitions[state][class];
+        if next_state != UNKNOWN {
+            return (next_state != DEAD).then_some(next_state as usize);
+        }
+
+        let representative = self.class_representatives[class];
+        let mut next_nfa_state_set: Vec<usize> = cache.states.nfa_state_sets[state].iter()
+            .filter_map(|&nfa_state| self.nfa.states[nfa_state].transitions.get(representative))
+            .flatten()
+            .flat_map(|&target| self.epsilon_closures[target].iter().cloned())
+            .collect();
+        next_nfa_state_set.sort_unstable();
+        next_nfa_state_set.dedup();
+        if next_nfa_state_set.iter().all(|&nfa_state| self.reachable_group_ids[nfa_state].is_empty()) {
+            cache.transitions[state][class] = DEAD;
+            return None;
+        }
+        let num_cache_clears = cache.num_cache_clears;
+        let next_state = self.intern_cached_state(cache, &next_nfa_state_set);
+        // If the cache was cleared, `state` is gone, so there's no transition to record.
+        if cache.num_cache_clears == num_cache_clears {
+            cache.transitions[state][class] = next_state as u32;
+        }
+        Some(next_state)
+    }
+}
+
+impl Tokenizer for LazyRegex {
+    fn initial_state_id(&self) -> usize {
+        0
+    }
+
+    /// Same as `RegexState::execute`, followed by the same filtering as `Regex`'s `execute_from_state`.
+    fn execute_from_state(&self, text: &[u8], state: usize) -> ExecuteResult {
+        let start_nfa_state_set = self.stable_states.lock().unwrap().nfa_state_sets[state].clone();
+        let mut cache = self.cache.lock().unwrap();
+        let is_lookahead_group = |group_id: &GroupID| self.nfa.lookahead_group_ids.contains(group_id);
+
+        let mut current_state = self.intern_cached_state(&mut cache, &start_nfa_state_set);
+        // Finalizers of lookahead groups in the initial state belong to a match that ended before this input.
+        let mut matches: BTreeMap<GroupID, usize> = cache.states.states[current_state].finalizers.iter()
+            .filter(|group_id| !is_lookahead_group(group_id))
+            .map(|&group_id| (group_id, 0))
+            .collect();
+        let mut done = !cache.states.states[current_state].has_transitions;
+        if !done {
+            for (i, &byte) in text.iter().enumerate() {
+                let Some(next_state) = self.next_state(&mut cache, current_state, byte) else {
+                    done = true;
+                    break;
+                };
+                current_state = next_state;
+                let position = i + 1;
+                for &group_id in &cache.states.states[current_state].finalizers {
+                    let position = if is_lookahead_group(&group_id) { position - 1 } else { position };
+                    if self.non_greedy_finalizers.contains(&group_id) {
+                        matches.entry(group_id).or_insert(position);
+                    } else {
+                        matches.insert(group_id, position);
+                    }
+                }
+                let should_terminate = cache.states.states[current_state].possible_group_ids.iter()
+                    .all(|group_id| self.non_greedy_finalizers.contains(group_id) && matches.contains_key(group_id));
+                if should_terminate {
+                    done = true;
+                    break;
+                }
+            }
+            done |= !cache.states.states[current_state].has_transitions;
+        }
+
+        let new_state = (!done).then(|| self.stable_states.lock().unwrap().intern(&cache.states.nfa_state_sets[current_state]));
+        let matches = matches.into_iter().map(|(id, width)| Token { id, width })
+            .filter(|token| token.width != 0 || (state != self.initial_state_id() && is_lookahead_group(&token.id)))
+            .collect();
+        ExecuteResult { matches, new_state }
+    }
+
+    fn tokens_accessible_from_state(&self, state: usize) -> Vec<TokenID> {
+        self.with_stable_state(state, |state| state.possible_group_ids.iter().cloned().collect())
+    }
+
+    fn tokens_matching_at_end_of_input(&self, state: usize) -> Vec<TokenID> {
+        self.with_stable_state(state, |state| state.eof_finalizers.iter().cloned().collect())
+    }
+
+    fn tokens_matching_empty_input(&self) -> Vec<TokenID> {
+        self.with_stable_state(self.initial_state_id(), |state| {
+            state.finalizers.iter().filter(|group_id| !self.nfa.lookahead_group_ids.contains(group_id)).cloned().collect()
+        })
+    }
+
+    /// Returns the number of state IDs handed out so far. More are handed out as execution reaches new states.
+    fn max_state(&self) -> usize {
+        self.num_states()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constraint::GrammarConstraint;
+    use crate::glr::table::StateID;
+    use crate::finite_automata::{eat_u8, eat_u8_set, greedy_group, groups, lookahead, negative_lookahead, non_greedy_group, rep, rep1, Expr};
+    use crate::interface::{choice, r#ref, regex, sequence, Grammar};
+    use crate::precompute::LLMTokenID;
+    use crate::u8set::U8Set;
+    use crate::{choice, groups, seq};
+    use bimap::BiBTreeMap;
+
+    #[test]
+    fn test_lazy_regex_matches_regex() {
+        let name = rep1(eat_u8_set(U8Set::from_range(b'a', b'z')));
+        let expr_groups = groups![
+            Expr::U8Seq(b"if".to_vec()),
+            seq![name.clone(), negative_lookahead(eat_u8(b'('))],
+            seq![name, lookahead(eat_u8(b'('))],
+            rep1(eat_u8_set(U8Set::from_range(b'0', b'9'))),
+            choice![eat_u8(b' '), eat_u8(b'\n')],
+            non_greedy_group(seq![eat_u8(b'"'), rep(eat_u8_set(U8Set::all())), eat_u8(b'"')]),
+        ];
+        let regex = expr_groups.clone().build();
+        // A tiny cache forces states to be dropped and rebuilt.
+        for max_cached_states in [0, 2, 1 << 20] {
+            let lazy_regex = expr_groups.clone().build_lazy(max_cached_states);
+            let texts: [&[u8]; 10] = [b"if", b"ifx(", b"foo)", b"foo", b"123a", b" \n", b"\"a\"b\"", b"", b"!", b"if(12 x"];
+            for text in texts {
+                // Split the text to check that execution resumes correctly.
+                for split in 0..=text.len() {
+                    let (left, right) = text.split_at(split);
+                    let result = regex.execute_from_state(left, 0);
+                    let lazy_result = lazy_regex.execute_from_state(left, 0);
+                    assert_eq!(lazy_result.matches, result.matches, "text {:?}, split {}", text, split);
+                    assert_eq!(lazy_result.new_state.is_some(), result.new_state.is_some());
+                    assert!(lazy_regex.num_cached_states() <= max_cached_states.max(1));
+                    let (Some(state), Some(lazy_state)) = (result.new_state, lazy_result.new_state) else {
+                        continue;
+                    };
+                    assert_eq!(lazy_regex.tokens_accessible_from_state(lazy_state), regex.tokens_accessible_from_state(state));
+                    assert_eq!(lazy_regex.tokens_matching_at_end_of_input(lazy_state), regex.tokens_matching_at_end_of_input(state));
+                    let result = regex.execute_from_state(right, state);
+                    let lazy_result = lazy_regex.execute_from_state(right, lazy_state);
+                    assert_eq!(lazy_result.matches, result.matches, "text {:?}, split {}", text, split);
+                    assert_eq!(lazy_result.new_state.is_some(), result.new_state.is_some());
+                }
+            }
+            if max_cached_states == 0 {
+                assert!(lazy_regex.num_cache_clears() > 0);
+                // Stable states outlive the cache clears.
+                assert!(lazy_regex.num_states() > lazy_regex.num_cached_states());
+            }
+        }
+    }
+
+    #[test]
+    fn test_lazy_regex_grammar_constraint() {
+        let name = rep1(eat_u8_set(U8Set::from_range(b'a', b'z')));
+        let number = rep1(eat_u8_set(U8Set::from_range(b'0', b'9')));
+        let exprs = vec![
+            ("S".to_string(), choice(vec![sequence(vec![r#ref("T"), r#ref("S")]), r#ref("T")])),
+            (
+                "T".to_string(),
+                choice(vec![
+                    regex(Expr::U8Seq(b"if".to_vec())),
+                    regex(name),
+                    regex(number),
+                    sequence(vec![regex(eat_u8(b'(')), regex(eat_u8(b')'))]),
+                ]),
+            ),
+        ];
+        let grammar = Grammar::from_exprs_with_ignore(exprs, vec![eat_u8(b' ')]);
+        let lazy_tokenizer = groups(
+            (0..grammar.terminal_expr_to_group_id.len())
+                .map(|group_id| greedy_group(grammar.terminal_expr_to_group_id.get_by_right(&group_id).unwrap().clone()))
+                .collect(),
+        ).build_lazy(16);
+        let lazy_grammar = grammar.clone().with_tokenizer(lazy_tokenizer);
+
+        let llm_tokens: Vec<Vec<u8>> = [&b"i"[..], b"f", b"if", b"foo", b"(", b")", b"1", b"12", b" ", b"if(", b" f"].iter().map(|token| token.to_vec()).collect();
+        let llm_token_map: BiBTreeMap<Vec<u8>, LLMTokenID> = llm_tokens.iter().enumerate().map(|(i, token)| (token.clone(), LLMTokenID(i))).collect();
+        let eof_llm_token_id = llm_tokens.len();
+        let max_llm_token_id = llm_tokens.len();
+        let mut state = GrammarConstraint::from_grammar(grammar, llm_token_map.clone(), eof_llm_token_id, max_llm_token_id).init();
+        let lazy_constraint = GrammarConstraint::from_grammar(lazy_grammar.clone(), llm_token_map.clone(), eof_llm_token_id, max_llm_token_id);
+        // Only the initial state is precomputed up front. The rest are precomputed as they're reached.
+        assert_eq!(lazy_constraint.precomputed.keys().collect::<Vec<_>>(), vec![&StateID(0)]);
+        let mut lazy_state = lazy_constraint.init();
+
+        assert_eq!(lazy_state.get_mask(), state.get_mask());
+        for token in [&b"if("[..], b")", b" f", b"foo", b"12", b"i", b"f", b" ", b"(", b")", b"1"] {
+            let llm_token_id = *llm_token_map.get_by_left(&token.to_vec()).unwrap();
+            state.commit(llm_token_id);
+            lazy_state.commit(llm_token_id);
+            assert_eq!(lazy_state.get_mask(), state.get_mask(), "after {:?}", String::from_utf8_lossy(token));
+            assert!(lazy_state.get_mask().any());
+        }
+        assert!(!lazy_state.parent.lazily_precomputed.lock().unwrap().is_empty());
+        assert!(lazy_state.parent.tokenizer.num_cached_states() <= 16);
+
+        // Fed bytes can end in tokenizer states that haven't been precomputed yet, also right after a trigger.
+        let lazy_constraint = GrammarConstraint::from_grammar(lazy_grammar, llm_token_map, eof_llm_token_id, max_llm_token_id);
+        let mut state = state.parent.init_with_trigger(Some(b"> "));
+        let mut lazy_state = lazy_constraint.init_with_trigger(Some(b"> "));
+        for bytes in [&b"> fo"[..], b"o 1", b"23 ("] {
+            state.feed_bytes(bytes);
+            lazy_state.feed_bytes(bytes);
+            assert!(lazy_state.is_active());
+            assert_eq!(lazy_state.get_mask(), state.get_mask(), "after {:?}", String::from_utf8_lossy(bytes));
+            assert!(lazy_state.get_mask().any());
+        }
+        assert!(!lazy_state.parent.lazily_precomputed.lock().unwrap().is_empty());
+    }
+}
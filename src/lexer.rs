@@ -0,0 +1,213 @@
+// src/lexer.rs
+use crate::finite_automata::{GroupID, Match, Regex, RegexState};
+
+/// How to pick a token when several groups match at the same position.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LexPolicy {
+    /// The longest match wins. Ties go to the lowest group ID.
+    #[default]
+    LongestMatch,
+    /// The lowest group ID that matches wins, regardless of length (i.e. groups are ordered alternatives).
+    Priority,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LexToken {
+    pub group_id: GroupID,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// No group matches a non-empty prefix of the input at `position`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LexError {
+    pub position: usize,
+}
+
+fn select_match(regex_state: &RegexState, policy: LexPolicy) -> Option<Match> {
+    // Tokens must be non-empty, or lexing would never advance.
+    let mut matches = regex_state.matches.iter().filter(|(_, &position)| position > 0);
+    let (&group_id, &position) = match policy {
+        // `matches` is ordered by group ID, so `max_by_key` would pick the highest group ID on ties.
+        LexPolicy::LongestMatch => matches.fold(None, |best: Option<(&GroupID, &usize)>, m| match best {
+            Some(best) if best.1 >= m.1 => Some(best),
+            _ => Some(m),
+        })?,
+        LexPolicy::Priority => matches.next()?,
+    };
+    Some(Match { group_id, position })
+}
+
+/// Iterator over the tokens of a complete input. See `Regex::lex`.
+pub struct Lexer<'a> {
+    regex: &'a Regex,
+    text: &'a [u8],
+    position: usize,
+    policy: LexPolicy,
+    failed: bool,
+}
+
+impl Iterator for Lexer<'_> {
+    type Item = Result<LexToken, LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.position == self.text.len() {
+            return None;
+        }
+        let mut regex_state = self.regex.init();
+        regex_state.execute(&self.text[self.position..]);
+        if !regex_state.done() {
+            regex_state.end_of_input();
+        }
+        let Some(m) = select_match(&regex_state, self.policy) else {
+            self.failed = true;
+            return Some(Err(LexError { position: self.position }));
+        };
+        let token = LexToken { group_id: m.group_id, start: self.position, end: self.position + m.position };
+        self.position = token.end;
+        Some(Ok(token))
+    }
+}
+
+/// A lexer that takes its input in chunks, emitting each token as soon as no longer match is possible.
+pub struct StreamingLexer<'a> {
+    regex: &'a Regex,
+    regex_state: RegexState<'a>,
+    policy: LexPolicy,
+    /// Input that hasn't been emitted as a token yet.
+    buffer: Vec<u8>,
+    /// Position of the start of `buffer` in the whole input.
+    buffer_start: usize,
+}
+
+impl<'a> StreamingLexer<'a> {
+    pub fn new(regex: &'a Regex, policy: LexPolicy) -> Self {
+        StreamingLexer {
+            regex,
+            regex_state: regex.init(),
+            policy,
+            buffer: Vec::new(),
+            buffer_start: 0,
+        }
+    }
+
+    /// Lexes the next chunk of input. Returns the tokens that are complete so far.
+    ///
+    /// After an error, every later call returns the same error.
+    pub fn push(&mut self, chunk: &[u8]) -> Result<Vec<LexToken>, LexError> {
+        self.buffer.extend_from_slice(chunk);
+        self.regex_state.execute(chunk);
+        self.drain(false)
+    }
+
+    /// Marks the end of the input and returns the remaining tokens.
+    pub fn finish(&mut self) -> Result<Vec<LexToken>, LexError> {
+        self.drain(true)
+    }
+
+    fn drain(&mut self, end_of_input: bool) -> Result<Vec<LexToken>, LexError> {
+        let mut tokens = Vec::new();
+        while !self.buffer.is_empty() {
+            if !self.regex_state.done() {
+                if !end_of_input {
+                    break;
+                }
+                self.regex_state.end_of_input();
+            }
+            let m = select_match(&self.regex_state, self.policy).ok_or(LexError { position: self.buffer_start })?;
+            tokens.push(LexToken { group_id: m.group_id, start: self.buffer_start, end: self.buffer_start + m.position });
+            self.buffer.drain(..m.position);
+            self.buffer_start += m.position;
+            // Rescan whatever followed the token.
+            self.regex_state = self.regex.init();
+            self.regex_state.execute(&self.buffer);
+        }
+        Ok(tokens)
+    }
+}
+
+impl Regex {
+    /// Splits `text` into tokens, yielding `(group_id, start, end)` for each. Ambiguity between groups is
+    /// resolved by `policy`. Non-greedy groups end at their shortest match. Iteration stops after an error.
+    pub fn lex<'a>(&'a self, text: &'a [u8], policy: LexPolicy) -> Lexer<'a> {
+        Lexer { regex: self, text, position: 0, policy, failed: false }
+    }
+
+    /// Like `lex`, but for input that arrives in chunks.
+    pub fn streaming_lexer(&self, policy: LexPolicy) -> StreamingLexer<'_> {
+        StreamingLexer::new(self, policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::finite_automata::{eat_u8, eat_u8_set, lookahead, negative_lookahead, non_greedy_group, rep, rep1, Expr};
+    use crate::u8set::U8Set;
+    use crate::{groups, seq};
+
+    fn tokens(lexer: impl Iterator<Item = Result<LexToken, LexError>>) -> Vec<(GroupID, usize, usize)> {
+        lexer.map(|token| token.map(|token| (token.group_id, token.start, token.end)).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_lex() {
+        let regex = groups![
+            Expr::U8Seq(b"if".to_vec()),
+            rep1(eat_u8_set(U8Set::from_range(b'a', b'z'))),
+            eat_u8(b' '),
+            non_greedy_group(seq![eat_u8(b'"'), rep(eat_u8_set(U8Set::all())), eat_u8(b'"')]),
+        ].build();
+
+        let text = b"if iffy \"a\"\"b\"";
+        assert_eq!(
+            tokens(regex.lex(text, LexPolicy::LongestMatch)),
+            vec![(0, 0, 2), (2, 2, 3), (1, 3, 7), (2, 7, 8), (3, 8, 11), (3, 11, 14)],
+        );
+        // With priority, "if" wins even as a prefix of "iffy".
+        assert_eq!(
+            tokens(regex.lex(b"iffy", LexPolicy::Priority)),
+            vec![(0, 0, 2), (1, 2, 4)],
+        );
+
+        let mut lexer = regex.lex(b"ab!cd", LexPolicy::LongestMatch);
+        assert_eq!(lexer.next(), Some(Ok(LexToken { group_id: 1, start: 0, end: 2 })));
+        assert_eq!(lexer.next(), Some(Err(LexError { position: 2 })));
+        assert_eq!(lexer.next(), None);
+    }
+
+    #[test]
+    fn test_streaming_lexer() {
+        let name = rep1(eat_u8_set(U8Set::from_range(b'a', b'z')));
+        let regex = groups![
+            seq![name.clone(), negative_lookahead(eat_u8(b'('))],
+            seq![name, lookahead(eat_u8(b'('))],
+            eat_u8(b'('),
+            eat_u8(b')'),
+        ].build();
+
+        let text = b"foo(bar)baz";
+        let expected = tokens(regex.lex(text, LexPolicy::LongestMatch));
+        assert_eq!(expected, vec![(1, 0, 3), (2, 3, 4), (0, 4, 7), (3, 7, 8), (0, 8, 11)]);
+        for chunk_size in 1..=text.len() {
+            let mut lexer = regex.streaming_lexer(LexPolicy::LongestMatch);
+            let mut streamed = Vec::new();
+            for chunk in text.chunks(chunk_size) {
+                streamed.extend(lexer.push(chunk).unwrap());
+            }
+            streamed.extend(lexer.finish().unwrap());
+            let streamed: Vec<_> = streamed.into_iter().map(|token| (token.group_id, token.start, token.end)).collect();
+            assert_eq!(streamed, expected, "chunk size {}", chunk_size);
+        }
+
+        // Tokens are emitted as soon as they can't be extended.
+        let mut lexer = regex.streaming_lexer(LexPolicy::LongestMatch);
+        assert_eq!(lexer.push(b"fo").unwrap(), vec![]);
+        assert_eq!(lexer.push(b"o(").unwrap(), vec![
+            LexToken { group_id: 1, start: 0, end: 3 },
+            LexToken { group_id: 2, start: 3, end: 4 },
+        ]);
+        assert_eq!(lexer.push(b"!"), Err(LexError { position: 4 }));
+        assert_eq!(lexer.finish(), Err(LexError { position: 4 }));
+    }
+}
@@ -6,11 +6,22 @@ pub mod precompute;
 pub mod tokenizer_combinators;
 pub mod u8set;
 pub mod finite_automata;
+pub mod compiled_regex;
+pub mod lazy_regex;
+pub mod lexer;
+pub mod export;
 mod gss;
 pub mod glr;
 pub mod constraint;
 pub mod interface;
 mod precompute_gss;
 mod trie;
+pub mod frozen_trie;
+pub mod compact_bitset;
+pub mod vocab;
+pub mod error;
+pub mod completions;
+pub mod reference;
+pub mod consistency;
 mod utils;
 mod analyze_grammar;
\ No newline at end of file
@@ -1,11 +1,14 @@
+use crate::compiled_regex::CompiledRegex;
+use crate::error::Error;
 use crate::finite_automata::{GroupID, Regex};
-use crate::glr;
 use crate::glr::table::StateID;
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::{Arc, Mutex};
 use bitvec::prelude::BitVec;
 use kdam::tqdm;
 use crate::trie::{dump_structure, AsPtr, TrieNode};
+use crate::frozen_trie::{read_u64, write_u64, FrozenTrie};
+use crate::compact_bitset::CompactBitSet;
 use bimap::BiBTreeMap;
 
 pub type TokenID = usize;
@@ -39,15 +42,29 @@ pub trait Tokenizer: Sized {
     /// Returns the list of tokens accessible from the given state.
     fn tokens_accessible_from_state(&self, state: usize) -> Vec<TokenID>;
 
+    /// Returns the tokens that match, ending at the current position, if the input ends in the given state.
+    /// These are tokens whose match can only be confirmed by the end of the input (e.g. a trailing negative
+    /// lookahead), so the precompute can't record them as a clean end of any LLM token.
+    fn tokens_matching_at_end_of_input(&self, _state: usize) -> Vec<TokenID> {
+        vec![]
+    }
+
+    /// Returns the tokens that match the empty string, from the initial state.
+    fn tokens_matching_empty_input(&self) -> Vec<TokenID>;
+
     /// Returns the maximum state ID in the DFA.
     fn max_state(&self) -> usize;
 
     /// Executes the tokenizer on the entire string and returns all possible token sequences and final states.
+    ///
+    /// `llm_token_id` is recorded at the trie node of every token sequence that `text` can be lexed into: as a clean
+    /// end if the text ends right after the last token, or as a dirty end if it ends in the middle of a further
+    /// token, in the tokenizer state that the next LLM token continues from.
     fn execute_all_from_state(
         &self,
         text: &[u8],
         state: usize,
-        state_map_root_arc: Arc<Mutex<TrieNode<GroupID, (BTreeMap<LLMTokenID, TokenizerStateInfoForLLMToken>, BTreeMap<TokenID, BitVec>, Option<BitVec>)>>>,
+        state_map_root_arc: Arc<Mutex<TrieNode<GroupID, PrecomputedNodeValue>>>,
         llm_token_id: LLMTokenID,
         max_llm_token_id: usize,
     ) {
@@ -61,9 +78,10 @@ pub trait Tokenizer: Sized {
         // (position, state) -> [node]
         let mut queue: BTreeMap<(usize, Option<usize>), BTreeMap<_, _>> = BTreeMap::new();
 
-        // let mut new_nodes: BTreeSet<*const TrieNode<GroupID, (BTreeMap<LLMTokenID, TokenizerStateInfoForLLMToken>, BTreeMap<TokenID, BitVec>, Option<BitVec>)>> = BTreeSet::new();
-        let mut queue_positions: BTreeMap<*const TrieNode<GroupID, (BTreeMap<LLMTokenID, TokenizerStateInfoForLLMToken>, BTreeMap<TokenID, BitVec>, Option<BitVec>)>, (usize, Option<usize>)> = BTreeMap::new();
-        let mut new_nodes_for_positions: BTreeMap<(usize, Option<usize>), Arc<Mutex<TrieNode<GroupID, (BTreeMap<LLMTokenID, TokenizerStateInfoForLLMToken>, BTreeMap<TokenID, BitVec>, Option<BitVec>)>>>> = BTreeMap::new();
+        // let mut new_nodes: BTreeSet<*const TrieNode<GroupID, PrecomputedNodeValue>> = BTreeSet::new();
+        let mut queue_positions: BTreeMap<*const TrieNode<GroupID, PrecomputedNodeValue>, (usize, Option<usize>)> = BTreeMap::new();
+        type Node = Arc<Mutex<TrieNode<GroupID, PrecomputedNodeValue>>>;
+        let mut new_nodes_for_positions: BTreeMap<(usize, Option<usize>), Node> = BTreeMap::new();
 
         // let root: Arc<Mutex<TrieNode<TokenID, TokenizerStateInfoForLLMToken>>> = Arc::new(Mutex::new(TrieNode::new(TokenizerStateInfoForLLMToken { tokenizer_state_id: state, position_in_llm_token: 0, dirty_end_state: None, clean_end: false })));
         let root = state_map_root_arc.clone();
@@ -82,29 +100,7 @@ pub trait Tokenizer: Sized {
             for (_, node) in nodes {
                 dbgprintln2!("Processing node {:?}", &*node.try_lock().unwrap() as *const TrieNode<_, _>);
                 if position == text.len() {
-                    assert!(!node.try_lock().unwrap().value.0.contains_key(&llm_token_id));
-                    node.try_lock().unwrap().value.0.insert(llm_token_id, TokenizerStateInfoForLLMToken {
-                        tokenizer_state_id: 99999999999,
-                        position_in_llm_token: position,
-                        dirty_end_state: maybe_state.map(|s| StateID(s)),
-                        clean_end: maybe_state.is_none()
-                    });
-                    if let Some(state) = maybe_state {
-                        for possible_grammar_token_id in &self.tokens_accessible_from_state(state) {
-                            node.try_lock().unwrap().value.1.entry(*possible_grammar_token_id).or_insert_with(|| {
-                                let mut bitset = BitVec::new();
-                                bitset.resize(max_llm_token_id + 1, false);
-                                bitset
-                            }).set(llm_token_id.0, true);
-                        }
-                    } else {
-                        dbgprintln2!("No state. Clean end");
-                        node.try_lock().unwrap().value.2.get_or_insert_with(|| {
-                            let mut bitset = BitVec::new();
-                            bitset.resize(max_llm_token_id + 1, false);
-                            bitset
-                        }).set(llm_token_id.0, true);
-                    }
+                    record_llm_token_end(&mut node.try_lock().unwrap().value, self, llm_token_id, position, maybe_state, max_llm_token_id);
                     continue;
                 }
 
@@ -114,7 +110,9 @@ pub trait Tokenizer: Sized {
                 // Process all matches
                 for token in &execute_result.matches {
                     let new_position = position + token.width;
-                    assert_ne!(token.width, 0);
+                    // A zero-width match is a token whose lookahead was only confirmed by the first byte of this
+                    // LLM token, so it can only happen at the start, continuing from a dirty state.
+                    assert!(token.width != 0 || (position == 0 && maybe_state.is_some_and(|s| s != self.initial_state_id())));
                     assert!(new_position <= text.len());
                     let new_state: Option<usize> = None;
                     dbgprintln2!("Processing token {:?}", token);
@@ -137,6 +135,13 @@ pub trait Tokenizer: Sized {
                                 // do nothing
                                 dbgprintln2!("Child exists and is already queued with same position and state. Doing nothing");
                             }
+                        } else if child.try_lock().unwrap().is_shared() {
+                            // Child exists but is reachable via other paths too. Anything we record for this LLM
+                            // token below it would also apply to those paths, so give this path its own copy.
+                            let new_child = node.try_lock().unwrap().replace_child_with_clone(&token.id);
+                            queue_positions.insert(&*new_child.try_lock().unwrap() as *const TrieNode<_, _>, (new_position, new_state));
+                            queue.entry((new_position, new_state)).or_default().insert(&*new_child.try_lock().unwrap() as *const TrieNode<_, _>, new_child.clone());
+                            dbgprintln2!("Child exists but is shared. Replacing child with clone");
                         } else {
                             // Child exists but is not already queued
                             // Need to add it to the queue
@@ -165,13 +170,49 @@ pub trait Tokenizer: Sized {
                     }
                     // dump_structure(state_map_root_arc.clone());
                 }
+
+                // If the tokenizer is still in the middle of a token when the text runs out, the LLM token
+                // ends dirty here: the next LLM token continues from `new_state`.
+                if let Some(new_state) = execute_result.new_state {
+                    record_llm_token_end(&mut node.try_lock().unwrap().value, self, llm_token_id, text.len(), Some(new_state), max_llm_token_id);
+                }
             }
         }
         dbgprintln2!("Done processing token");
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+/// Records that `llm_token_id` ends at a node, either cleanly (`maybe_state` is `None`) or in the middle
+/// of a token (`maybe_state` is the tokenizer state to continue from).
+///
+/// `execute_all_from_state` queues each node under a single position, and calls this once per node: when it reaches
+/// the end of the text, or when the text runs out in the middle of a token. So no LLM token ends twice at a node.
+/// `llm_token_id` must be at most `max_llm_token_id`, which `precompute` and `precompute_frozen` check.
+pub(crate) fn record_llm_token_end(
+    value: &mut PrecomputedNodeValue,
+    tokenizer: &impl Tokenizer,
+    llm_token_id: LLMTokenID,
+    position: usize,
+    maybe_state: Option<usize>,
+    max_llm_token_id: usize,
+) {
+    assert!(!value.0.contains_key(&llm_token_id));
+    value.0.insert(llm_token_id, TokenizerStateInfoForLLMToken {
+        tokenizer_state_id: 99999999999,
+        position_in_llm_token: position,
+        dirty_end_state: maybe_state.map(StateID),
+        clean_end: maybe_state.is_none()
+    });
+    if let Some(state) = maybe_state {
+        for possible_grammar_token_id in &tokenizer.tokens_accessible_from_state(state) {
+            value.1.entry(*possible_grammar_token_id).or_default().insert(llm_token_id.0, max_llm_token_id + 1);
+        }
+    } else {
+        value.2.get_or_insert_with(CompactBitSet::new).insert(llm_token_id.0, max_llm_token_id + 1);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct TokenizerStateInfoForLLMToken {
     pub tokenizer_state_id: usize,
     pub position_in_llm_token: usize,
@@ -181,48 +222,90 @@ pub struct TokenizerStateInfoForLLMToken {
 }
 
 /// Precomputes a map from state -> token sequence -> LLM token -> state.
+///
+/// Only the states the tokenizer knows about so far are precomputed. Lazily built tokenizers (see `LazyRegex`) can
+/// reach more states later; `GrammarConstraint` precomputes those on demand.
 pub fn precompute<'a>(
     tokenizer: &impl Tokenizer,
     llm_token_map: &BiBTreeMap<Vec<u8>, LLMTokenID>,
     eof_llm_token_id: LLMTokenID,
     max_llm_token_id: usize,
-) -> BTreeMap<StateID, TrieNode<TokenID, (BTreeMap<LLMTokenID, TokenizerStateInfoForLLMToken>, BTreeMap<TokenID, BitVec>, Option<BitVec>)>> {
+) -> Result<BTreeMap<StateID, TrieNode<TokenID, PrecomputedNodeValue>>, Error> {
     let mut result: BTreeMap<StateID, TrieNode<GroupID, _>> = BTreeMap::new();
 
-    // Ensure the tokenizer doesn't match on empty strings
-    crate::dbgprintln2!("Ensuring tokenizer doesn't match on empty strings");
-    let execute_result = tokenizer.execute_from_state(&[], 0);
-    if !execute_result.matches.is_empty() {
-        panic!("Tokenizer should not match on empty string. If it did, there would be infinitely many possible token sequences for any LLM token.");
-    }
+    check_no_empty_matches(tokenizer)?;
+    check_llm_token_ids(llm_token_map, max_llm_token_id)?;
 
     crate::dbgprintln2!("Precomputing in precompute");
     for state_id in tqdm!(0..tokenizer.max_state()) {
-        // crate::dbgprintln!("Precomputing state {}", state_id);
-        let mut state_map_root_arc: Arc<Mutex<TrieNode<GroupID, (BTreeMap<LLMTokenID, TokenizerStateInfoForLLMToken>, BTreeMap<TokenID, BitVec>, Option<BitVec>)>>> = Arc::new(Mutex::new(TrieNode::new((BTreeMap::new(), BTreeMap::new(), None))));
-
-        for (i, (llm_token, llm_token_id)) in llm_token_map.iter().enumerate() {
-            crate::dbgprintln!("Precomputing for token {:?} ({:?}) ({})", llm_token_id, llm_token, i);
-            // todo: REMOVE THIS
-            // if i < 121 {
-            //     continue;
-            // }
-            // dump_structure(state_map_root_arc.clone());
-            tokenizer.execute_all_from_state(
-                llm_token,
-                state_id,
-                state_map_root_arc.clone(),
-                *llm_token_id,
-                max_llm_token_id,
-            );
-        }
+        result.insert(StateID(state_id), precompute_state(tokenizer, llm_token_map, state_id, max_llm_token_id));
+    }
+
+    Ok(result)
+}
+
+/// Like `precompute` followed by `freeze_precomputed`, but freezes each tokenizer state's trie as soon as it's built,
+/// so that only one unfrozen trie is in memory at a time. `finish_state` is called on each trie before it's frozen
+/// (e.g. `precompute_add_special_tokens`).
+pub fn precompute_frozen(
+    tokenizer: &impl Tokenizer,
+    llm_token_map: &BiBTreeMap<Vec<u8>, LLMTokenID>,
+    max_llm_token_id: usize,
+    mut finish_state: impl FnMut(StateID, &mut TrieNode<TokenID, PrecomputedNodeValue>),
+) -> Result<BTreeMap<StateID, FrozenTrie<TokenID, FrozenPrecomputedNodeValue>>, Error> {
+    check_no_empty_matches(tokenizer)?;
+    check_llm_token_ids(llm_token_map, max_llm_token_id)?;
+
+    let tries = tqdm!(0..tokenizer.max_state()).map(|state_id| {
+        let mut trie = precompute_state(tokenizer, llm_token_map, state_id, max_llm_token_id);
+        finish_state(StateID(state_id), &mut trie);
+        (StateID(state_id), trie)
+    });
+    Ok(FrozenTrie::freeze_each(tries, freeze_value()))
+}
+
+/// Ensures the tokenizer doesn't match on empty strings. If it did, there would be infinitely many possible token
+/// sequences for any LLM token.
+fn check_no_empty_matches(tokenizer: &impl Tokenizer) -> Result<(), Error> {
+    crate::dbgprintln2!("Ensuring tokenizer doesn't match on empty strings");
+    if let Some(token_id) = tokenizer.tokens_matching_empty_input().first() {
+        return Err(Error::EmptyMatchingTerminal(format!("with group ID {}", token_id)));
+    }
+    Ok(())
+}
 
-        // crate::dbgprintln2!("Done precomputing state {}", state_id);
-        let state_map_root = state_map_root_arc.try_lock().unwrap().clone();
-        result.insert(glr::table::StateID(state_id), state_map_root);
+/// Ensures every LLM token ID fits in the bitsets of `max_llm_token_id + 1` bits that the precompute records them in.
+fn check_llm_token_ids(llm_token_map: &BiBTreeMap<Vec<u8>, LLMTokenID>, max_llm_token_id: usize) -> Result<(), Error> {
+    if let Some(&llm_token_id) = llm_token_map.right_values().find(|llm_token_id| llm_token_id.0 > max_llm_token_id) {
+        return Err(Error::LLMTokenIDOutOfRange { llm_token_id, max_llm_token_id });
     }
+    Ok(())
+}
 
-    result
+pub(crate) fn precompute_state(
+    tokenizer: &impl Tokenizer,
+    llm_token_map: &BiBTreeMap<Vec<u8>, LLMTokenID>,
+    state_id: usize,
+    max_llm_token_id: usize,
+) -> TrieNode<TokenID, PrecomputedNodeValue> {
+    // crate::dbgprintln!("Precomputing state {}", state_id);
+    let state_map_root_arc: Arc<Mutex<TrieNode<GroupID, PrecomputedNodeValue>>> = Arc::new(Mutex::new(TrieNode::new((BTreeMap::new(), BTreeMap::new(), None))));
+
+    for (i, (llm_token, llm_token_id)) in llm_token_map.iter().enumerate() {
+        crate::dbgprintln!("Precomputing for token {:?} ({:?}) ({})", llm_token_id, llm_token, i);
+        // dump_structure(state_map_root_arc.clone());
+        tokenizer.execute_all_from_state(
+            llm_token,
+            state_id,
+            state_map_root_arc.clone(),
+            *llm_token_id,
+            max_llm_token_id,
+        );
+    }
+
+    // crate::dbgprintln2!("Done precomputing state {}", state_id);
+    let state_map_root = state_map_root_arc.try_lock().unwrap().clone();
+    state_map_root
 }
 
 impl Tokenizer for Regex {
@@ -235,8 +318,10 @@ impl Tokenizer for Regex {
         regex_state.execute(text);
 
         let matches: Vec<_> = regex_state.matches.iter().map(|(&id, &width)| Token { id, width })
-            // Filter out zero-width tokens
-            .filter(|token| token.width != 0).collect();
+            // Filter out zero-width tokens, except for lookahead tokens that ended before this text and were
+            // only confirmed by its first byte.
+            .filter(|token| token.width != 0 || (state != self.initial_state_id() && self.dfa.lookahead_group_ids.contains(&token.id)))
+            .collect();
 
         ExecuteResult {
             matches,
@@ -249,20 +334,209 @@ impl Tokenizer for Regex {
         regex_state.possible_group_ids().iter().cloned().collect()
     }
 
+    fn tokens_matching_at_end_of_input(&self, state: usize) -> Vec<TokenID> {
+        self.dfa.states[state].eof_finalizers.iter().cloned().collect()
+    }
+
+    fn tokens_matching_empty_input(&self) -> Vec<TokenID> {
+        self.init().matches.keys().cloned().collect()
+    }
+
     fn max_state(&self) -> usize {
         self.dfa.states.len()
     }
 }
 
-pub fn print_precomputed(precomputed: &BTreeMap<StateID, TrieNode<TokenID, (BTreeMap<LLMTokenID, TokenizerStateInfoForLLMToken>, BTreeMap<TokenID, BitVec>, Option<BitVec>)>>) {
+impl Tokenizer for CompiledRegex {
+    fn initial_state_id(&self) -> usize {
+        0
+    }
+
+    fn execute_from_state(&self, text: &[u8], state: usize) -> ExecuteResult {
+        let mut regex_state = self.init_to_state(state);
+        regex_state.execute(text);
+
+        let matches: Vec<_> = regex_state.matches().into_iter().map(|(id, width)| Token { id, width })
+            // Same filtering as for `Regex`.
+            .filter(|token| token.width != 0 || (state != self.initial_state_id() && self.is_lookahead_group(token.id)))
+            .collect();
+
+        ExecuteResult {
+            matches,
+            new_state: if regex_state.done { None } else { Some(regex_state.current_state) },
+        }
+    }
+
+    fn tokens_accessible_from_state(&self, state: usize) -> Vec<TokenID> {
+        self.possible_group_ids(state).collect()
+    }
+
+    fn tokens_matching_at_end_of_input(&self, state: usize) -> Vec<TokenID> {
+        self.eof_finalizers(state).collect()
+    }
+
+    fn tokens_matching_empty_input(&self) -> Vec<TokenID> {
+        self.init().matches().into_keys().collect()
+    }
+
+    fn max_state(&self) -> usize {
+        self.num_states()
+    }
+}
+
+/// Returns the LLM tokens whose bytes are consistent with the text starting with `prefix`: those that start with
+/// all of `prefix`, and those that are a prefix of it.
+pub fn llm_tokens_consistent_with_prefix(llm_token_map: &BiBTreeMap<Vec<u8>, LLMTokenID>, prefix: &[u8], max_llm_token_id: usize) -> BitVec {
+    let mut result = BitVec::new();
+    result.resize(max_llm_token_id + 1, false);
+    for (_, llm_token_id) in llm_token_map.left_range(prefix.to_vec()..).take_while(|(llm_token, _)| llm_token.starts_with(prefix)) {
+        result.set(llm_token_id.0, true);
+    }
+    for end in 1..prefix.len() {
+        if let Some(llm_token_id) = llm_token_map.get_by_left(&prefix[..end]) {
+            result.set(llm_token_id.0, true);
+        }
+    }
+    result
+}
+
+/// The value at each node of a precompute trie: the LLM tokens that end at the node, the LLM tokens allowed after
+/// each possible next grammar token, and the LLM tokens that end cleanly at the node.
+pub type PrecomputedNodeValue = (BTreeMap<LLMTokenID, TokenizerStateInfoForLLMToken>, BTreeMap<TokenID, CompactBitSet>, Option<CompactBitSet>);
+
+/// `PrecomputedNodeValue` with the bitsets shared, as stored in the frozen precompute tries.
+pub type FrozenPrecomputedNodeValue = (BTreeMap<LLMTokenID, TokenizerStateInfoForLLMToken>, BTreeMap<TokenID, Arc<CompactBitSet>>, Option<Arc<CompactBitSet>>);
+
+/// Converts the precompute tries into their immutable, lock-free form. Identical subtrees and bitsets are only
+/// stored once across all tokenizer states.
+pub fn freeze_precomputed(precomputed: &BTreeMap<StateID, TrieNode<TokenID, PrecomputedNodeValue>>) -> BTreeMap<StateID, FrozenTrie<TokenID, FrozenPrecomputedNodeValue>> {
+    FrozenTrie::freeze_all(precomputed, freeze_value())
+}
+
+/// Returns a function that converts node values to their frozen form, sharing identical bitsets between all the
+/// values it converts.
+fn freeze_value() -> impl FnMut(&PrecomputedNodeValue) -> FrozenPrecomputedNodeValue {
+    let mut bitsets: HashSet<Arc<CompactBitSet>> = HashSet::new();
+    let mut intern_bitset = move |bitset: &CompactBitSet| {
+        if let Some(existing) = bitsets.get(bitset) {
+            return existing.clone();
+        }
+        let bitset = Arc::new(bitset.clone());
+        bitsets.insert(bitset.clone());
+        bitset
+    };
+    move |(llm_token_ends, possible_next_tokens, clean_end_llm_tokens)| (
+        llm_token_ends.clone(),
+        possible_next_tokens.iter().map(|(&token_id, bitset)| (token_id, intern_bitset(bitset))).collect(),
+        clean_end_llm_tokens.as_ref().map(&mut intern_bitset),
+    )
+}
+
+/// Identifies the format written by `save_precomputed`.
+const SAVED_PRECOMPUTED_MAGIC: &[u8; 8] = b"sep1pre1";
+
+/// Serializes frozen precompute tries, e.g. to cache them on disk, since precomputing a large vocabulary is slow.
+/// Shared subtrees and bitsets are saved once and stay shared when loaded with `load_precomputed`.
+pub fn save_precomputed(precomputed: &BTreeMap<StateID, FrozenTrie<TokenID, FrozenPrecomputedNodeValue>>) -> Vec<u8> {
+    // The tries refer to bitsets by their index in a table that's written before them.
+    let mut bitsets: Vec<Arc<CompactBitSet>> = Vec::new();
+    let mut bitset_ids: HashMap<*const CompactBitSet, u64> = HashMap::new();
+    let mut save_bitset = |bitset: &Arc<CompactBitSet>, out: &mut Vec<u8>| {
+        let bitset_id = *bitset_ids.entry(Arc::as_ptr(bitset)).or_insert_with(|| {
+            bitsets.push(bitset.clone());
+            bitsets.len() as u64 - 1
+        });
+        write_u64(out, bitset_id);
+    };
+    let mut tries_out = Vec::new();
+    FrozenTrie::save_all(
+        precomputed,
+        &mut tries_out,
+        |state_id, out| write_u64(out, state_id.0 as u64),
+        |&token_id, out| write_u64(out, token_id as u64),
+        |(llm_token_ends, possible_next_tokens, clean_end_llm_tokens), out| {
+            write_u64(out, llm_token_ends.len() as u64);
+            for (llm_token_id, info) in llm_token_ends {
+                write_u64(out, llm_token_id.0 as u64);
+                write_u64(out, info.tokenizer_state_id as u64);
+                write_u64(out, info.position_in_llm_token as u64);
+                // The dirty end state, plus one, or zero for none.
+                write_u64(out, info.dirty_end_state.map_or(0, |state_id| state_id.0 as u64 + 1));
+                write_u64(out, info.clean_end as u64);
+            }
+            write_u64(out, possible_next_tokens.len() as u64);
+            for (&token_id, bitset) in possible_next_tokens {
+                write_u64(out, token_id as u64);
+                save_bitset(bitset, out);
+            }
+            match clean_end_llm_tokens {
+                Some(bitset) => {
+                    write_u64(out, 1);
+                    save_bitset(bitset, out);
+                }
+                None => write_u64(out, 0),
+            }
+        },
+    );
+
+    let mut out = SAVED_PRECOMPUTED_MAGIC.to_vec();
+    write_u64(&mut out, bitsets.len() as u64);
+    for bitset in bitsets {
+        bitset.save(&mut out);
+    }
+    out.extend(tries_out);
+    out
+}
+
+/// Loads precompute tries written by `save_precomputed`. They must be used with the same tokenizer, LLM
+/// vocabulary (whose largest ID is `max_llm_token_id`) and special tokens they were precomputed for.
+pub fn load_precomputed(data: &[u8], max_llm_token_id: usize) -> Result<BTreeMap<StateID, FrozenTrie<TokenID, FrozenPrecomputedNodeValue>>, Error> {
+    let invalid = |message: &str| Error::InvalidSavedData(message.to_string());
+    let mut input = data.strip_prefix(SAVED_PRECOMPUTED_MAGIC).ok_or_else(|| invalid("not saved precompute tries"))?;
+    let bitsets = (0..read_u64(&mut input)?)
+        .map(|_| CompactBitSet::load(&mut input, max_llm_token_id + 1).map(Arc::new))
+        .collect::<Result<Vec<_>, _>>()?;
+    let load_usize = |input: &mut &[u8]| read_u64(input).map(|value| value as usize);
+    let load_bitset = |input: &mut &[u8]| bitsets.get(read_u64(input)? as usize).cloned().ok_or_else(|| invalid("a bitset ID is out of range"));
+    let precomputed = FrozenTrie::load_all(
+        &mut input,
+        |input| load_usize(input).map(StateID),
+        load_usize,
+        |input| {
+            let mut llm_token_ends = BTreeMap::new();
+            for _ in 0..read_u64(input)? {
+                let llm_token_id = LLMTokenID(load_usize(input)?);
+                let info = TokenizerStateInfoForLLMToken {
+                    tokenizer_state_id: load_usize(input)?,
+                    position_in_llm_token: load_usize(input)?,
+                    dirty_end_state: load_usize(input)?.checked_sub(1).map(StateID),
+                    clean_end: read_u64(input)? != 0,
+                };
+                llm_token_ends.insert(llm_token_id, info);
+            }
+            let mut possible_next_tokens = BTreeMap::new();
+            for _ in 0..read_u64(input)? {
+                let token_id = load_usize(input)?;
+                possible_next_tokens.insert(token_id, load_bitset(input)?);
+            }
+            let clean_end_llm_tokens = if read_u64(input)? != 0 { Some(load_bitset(input)?) } else { None };
+            Ok((llm_token_ends, possible_next_tokens, clean_end_llm_tokens))
+        },
+    )?;
+    if !input.is_empty() {
+        return Err(invalid("trailing data"));
+    }
+    Ok(precomputed)
+}
+
+pub fn print_precomputed(precomputed: &BTreeMap<StateID, FrozenTrie<TokenID, FrozenPrecomputedNodeValue>>) {
     println!("Precomputed:");
-    for (tokenizer_state, root) in precomputed {
+    for (tokenizer_state, trie) in precomputed {
         println!("  Tokenizer state: {}", tokenizer_state.0);
-        for node in TrieNode::all_nodes(Arc::new(Mutex::new(root.clone()))) {
-            println!("    Node address: {:p}, value: {:?}", Arc::as_ptr(&node), node.try_lock().unwrap().value);
-            // print edge values and destination addresses
-            for (edge, dest) in node.try_lock().unwrap().children() {
-                println!("      Edge value: {:?}, destination address: {:p}", edge, Arc::as_ptr(&dest));
+        for node in trie.nodes() {
+            println!("    Node {}, value: {:?}", node, trie.value(node));
+            for (edge, dest) in trie.children(node) {
+                println!("      Edge value: {:?}, destination: {}", edge, dest);
             }
         }
     }
@@ -278,6 +552,59 @@ mod tests {
     use std::collections::{BTreeMap, BTreeSet};
     use bimap::BiBTreeMap;
 
+    #[test]
+    fn test_precompute_records_dirty_ends() {
+        // "a" stops in the middle of the "ab" token, so it must end dirty at the root, in the state after "a".
+        let tokenizer = groups![seq![eat_u8(b'a'), eat_u8(b'b')]].build();
+        let llm_token_map: BiBTreeMap<Vec<u8>, LLMTokenID> = [(b"a".to_vec(), LLMTokenID(0)), (b"ab".to_vec(), LLMTokenID(1)), (b"b".to_vec(), LLMTokenID(2))].into_iter().collect();
+        let result = precompute(&tokenizer, &llm_token_map, LLMTokenID(3), 3).unwrap();
+
+        let root = &result[&StateID(0)];
+        let info = root.value.0[&LLMTokenID(0)];
+        assert!(!info.clean_end);
+        let dirty_end_state = info.dirty_end_state.unwrap();
+        assert_ne!(dirty_end_state, StateID(0));
+        assert!(root.value.1[&0].contains(0));
+        // "ab" ends cleanly after the token.
+        let after_ab = root.get(&0).unwrap();
+        assert!(after_ab.try_lock().unwrap().value.0[&LLMTokenID(1)].clean_end);
+        // "b" finishes the token from the dirty end state.
+        assert!(result[&dirty_end_state].get(&0).unwrap().try_lock().unwrap().value.0[&LLMTokenID(2)].clean_end);
+    }
+
+    #[test]
+    fn test_precompute_one_or_more_group() {
+        // Group 0 is "a"+, group 1 is " ". "a " must lex as [0, 1] and nothing else: repeating group 0 must not
+        // loop back into the start of group 1.
+        let tokenizer = groups![crate::finite_automata::rep1(eat_u8(b'a')), eat_u8(b' ')].build();
+        let llm_token_map: BiBTreeMap<Vec<u8>, LLMTokenID> = [(b"a ".to_vec(), LLMTokenID(0))].into_iter().collect();
+        let result = precompute(&tokenizer, &llm_token_map, LLMTokenID(1), 1).unwrap();
+        let root = Arc::new(Mutex::new(result[&StateID(0)].clone()));
+        let token_sequences: Vec<Vec<TokenID>> = root.try_lock().unwrap().flatten(|value| value.0.contains_key(&LLMTokenID(0))).into_keys().collect();
+        assert_eq!(token_sequences, vec![vec![0, 1]]);
+    }
+
+    #[test]
+    fn test_precompute_rejects_empty_matches() {
+        // Group 1 is "b"*, which matches the empty string.
+        let tokenizer = groups![eat_u8(b'a'), crate::finite_automata::rep(eat_u8(b'b'))].build();
+        let llm_token_map: BiBTreeMap<Vec<u8>, LLMTokenID> = [(b"a".to_vec(), LLMTokenID(0))].into_iter().collect();
+        let result = precompute(&tokenizer, &llm_token_map, LLMTokenID(1), 1);
+        assert!(matches!(result, Err(Error::EmptyMatchingTerminal(terminal)) if terminal == "with group ID 1"));
+    }
+
+    #[test]
+    fn test_precompute_rejects_llm_token_ids_out_of_range() {
+        // Group 0 is "a"+, so "aa" reaches the node for [0] both at its end and in its middle.
+        let tokenizer = groups![crate::finite_automata::rep1(eat_u8(b'a'))].build();
+        let llm_token_map: BiBTreeMap<Vec<u8>, LLMTokenID> = [(b"aa".to_vec(), LLMTokenID(2))].into_iter().collect();
+        assert!(precompute(&tokenizer, &llm_token_map, LLMTokenID(2), 2).is_ok());
+        let result = precompute(&tokenizer, &llm_token_map, LLMTokenID(1), 1);
+        assert!(matches!(result, Err(Error::LLMTokenIDOutOfRange { llm_token_id: LLMTokenID(2), max_llm_token_id: 1 })));
+        let result = precompute_frozen(&tokenizer, &llm_token_map, 1, |_, _| {});
+        assert!(matches!(result, Err(Error::LLMTokenIDOutOfRange { llm_token_id: LLMTokenID(2), max_llm_token_id: 1 })));
+    }
+
     #[test]
     fn test_precompute() {
         let _tokenizer = groups![
@@ -293,6 +620,7 @@ mod tests {
                     DFAState {
                         transitions: TrieMap::from_iter(vec![(b'a', 1), (b'b', 2)]),
                         finalizers: BTreeSet::new(),
+                        eof_finalizers: BTreeSet::new(),
                         possible_group_ids: BTreeSet::from([0, 1, 2, 3]),
                         group_id_to_u8set: BTreeMap::from([
                             (0, U8Set::from_bytes(b"a")),
@@ -304,6 +632,7 @@ mod tests {
                     DFAState {
                         transitions: TrieMap::from_iter(vec![(b'b', 3)]),
                         finalizers: BTreeSet::from([0]),
+                        eof_finalizers: BTreeSet::new(),
                         possible_group_ids: BTreeSet::from([0, 2, 3]),
                         group_id_to_u8set: BTreeMap::from([
                             (2, U8Set::from_bytes(b"b")),
@@ -313,24 +642,28 @@ mod tests {
                     DFAState {
                         transitions: TrieMap::new(),
                         finalizers: BTreeSet::from([1]),
+                        eof_finalizers: BTreeSet::new(),
                         possible_group_ids: BTreeSet::from([1]),
                         group_id_to_u8set: BTreeMap::new(),
                     },
                     DFAState {
                         transitions: TrieMap::from_iter(vec![(b'c', 4)]),
                         finalizers: BTreeSet::from([2]),
+                        eof_finalizers: BTreeSet::new(),
                         possible_group_ids: BTreeSet::from([2, 3]),
                         group_id_to_u8set: BTreeMap::from([(3, U8Set::from_bytes(b"c"))]),
                     },
                     DFAState {
                         transitions: TrieMap::new(),
                         finalizers: BTreeSet::from([3]),
+                        eof_finalizers: BTreeSet::new(),
                         possible_group_ids: BTreeSet::from([3]),
                         group_id_to_u8set: BTreeMap::new(),
                     },
                 ],
                 start_state: 0,
                 non_greedy_finalizers: BTreeSet::new(),
+                lookahead_group_ids: BTreeSet::new(),
             },
         };
         assert_eq!(_tokenizer, tokenizer);
@@ -341,7 +674,7 @@ mod tests {
 
         // Run precompute
         let max_llm_token_id = llm_tokens.len() + 1;
-        let result = precompute(&tokenizer, &llm_token_map, LLMTokenID(max_llm_token_id), max_llm_token_id);
+        let result = precompute(&tokenizer, &llm_token_map, LLMTokenID(max_llm_token_id), max_llm_token_id).unwrap();
 
         // todo: update this for TrieNode
         // // Build the expected output
@@ -375,4 +708,43 @@ mod tests {
         //
         // assert_eq!(&expected, &result);
     }
+
+    #[test]
+    fn test_precompute_frozen() {
+        let tokenizer = groups![seq![eat_u8(b'a'), eat_u8(b'b')], eat_u8(b'b')].build();
+        let llm_token_map: BiBTreeMap<Vec<u8>, LLMTokenID> = [(b"a".to_vec(), LLMTokenID(0)), (b"ab".to_vec(), LLMTokenID(1)), (b"b".to_vec(), LLMTokenID(2))].into_iter().collect();
+        let mut finished_states = Vec::new();
+        let frozen = precompute_frozen(&tokenizer, &llm_token_map, 3, |state_id, _| finished_states.push(state_id)).unwrap();
+        assert_eq!(finished_states, frozen.keys().copied().collect::<Vec<_>>());
+        let expected = freeze_precomputed(&precompute(&tokenizer, &llm_token_map, LLMTokenID(3), 3).unwrap());
+        assert_eq!(crate::export::precomputed_to_json(&frozen), crate::export::precomputed_to_json(&expected));
+        let tries: Vec<_> = frozen.values().collect();
+        assert!(tries[1..].iter().all(|trie| trie.shares_arena_with(tries[0])));
+        assert_eq!(tries[0].arena_num_nodes(), expected[&StateID(0)].arena_num_nodes());
+    }
+
+    #[test]
+    fn test_save_and_load_precomputed() {
+        let tokenizer = groups![seq![eat_u8(b'a'), eat_u8(b'b')], eat_u8(b'b')].build();
+        let llm_token_map: BiBTreeMap<Vec<u8>, LLMTokenID> = [(b"a".to_vec(), LLMTokenID(0)), (b"ab".to_vec(), LLMTokenID(1)), (b"b".to_vec(), LLMTokenID(2))].into_iter().collect();
+        let frozen = freeze_precomputed(&precompute(&tokenizer, &llm_token_map, LLMTokenID(3), 3).unwrap());
+        assert!(frozen.len() > 1);
+
+        let saved = save_precomputed(&frozen);
+        let loaded = load_precomputed(&saved, 3).unwrap();
+        assert_eq!(crate::export::precomputed_to_json(&loaded), crate::export::precomputed_to_json(&frozen));
+        // The tries still share one arena.
+        let tries: Vec<_> = loaded.values().collect();
+        assert!(tries[1..].iter().all(|trie| trie.shares_arena_with(tries[0])));
+
+        // A single trie is saved with its whole arena, including nodes only the other tries reach.
+        let (&state_id, trie) = frozen.iter().last().unwrap();
+        let loaded = load_precomputed(&save_precomputed(&BTreeMap::from([(state_id, trie.clone())])), 3).unwrap();
+        assert_eq!(crate::export::precomputed_to_json(&loaded), crate::export::precomputed_to_json(&BTreeMap::from([(state_id, trie.clone())])));
+
+        assert!(load_precomputed(&saved[..saved.len() - 1], 3).is_err());
+        assert!(load_precomputed(b"not saved", 3).is_err());
+        // The bitsets are over a vocabulary of 4 tokens.
+        assert!(load_precomputed(&saved, 1).is_err());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,211 @@
+// src/reference.rs
+use crate::constraint::{GrammarConstraint, SpecialTokenPolicy};
+use crate::glr::parser::GLRParser;
+use crate::glr::table::TerminalID;
+use crate::precompute::{LLMTokenID, TokenID, Tokenizer};
+use bimap::BiBTreeMap;
+use bitvec::prelude::*;
+use std::collections::BTreeSet;
+
+type LLMTokenMap = BiBTreeMap<Vec<u8>, LLMTokenID>;
+
+/// Part of the generated output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    /// The text of one LLM token. Like the precomputed constraint, the lexer matches greedily within an LLM token, but
+    /// a grammar token can also end at its boundary.
+    Text(Vec<u8>),
+    /// A special token that stands for a grammar token. See `SpecialTokenPolicy::terminal_llm_token_ids`.
+    GrammarToken(TokenID),
+}
+
+/// A slow but simple constraint with the same `get_mask`/`commit` interface as `GrammarConstraintState`, for
+/// differential testing. It keeps everything generated so far, and for each candidate LLM token lexes the whole
+/// output in every possible way and runs the GLR parser on each token sequence. Nothing is precomputed.
+///
+/// Only the grammar is applied: there are no triggers and no token healing.
+#[derive(Debug, Clone)]
+pub struct ReferenceConstraint<T: Tokenizer> {
+    pub(crate) tokenizer: T,
+    pub(crate) parser: GLRParser,
+    pub(crate) ignore_terminal_ids: BTreeSet<TokenID>,
+    pub(crate) llm_tokens: LLMTokenMap,
+    pub(crate) special_tokens: SpecialTokenPolicy,
+    pub(crate) max_llm_token_id: usize,
+    generated: Vec<Segment>,
+    /// Whether an EOS token has been generated, after which nothing is allowed.
+    ended: bool,
+}
+
+impl<T: Tokenizer> ReferenceConstraint<T> {
+    /// Doesn't check `tokenizer`: the public constructors only pass tokenizers that don't match the empty string, which
+    /// `lex_text` relies on to terminate.
+    pub(crate) fn new(tokenizer: T, parser: GLRParser, ignore_terminal_ids: BTreeSet<TokenID>, llm_tokens: LLMTokenMap, special_tokens: SpecialTokenPolicy, max_llm_token_id: usize) -> Self {
+        Self {
+            tokenizer,
+            parser,
+            ignore_terminal_ids,
+            llm_tokens,
+            special_tokens,
+            max_llm_token_id,
+            generated: Vec::new(),
+            ended: false,
+        }
+    }
+
+    /// Returns a reference for the same grammar and vocabulary as `constraint`, at the start of the grammar.
+    pub fn from_constraint(constraint: &GrammarConstraint<T>) -> Self where T: Clone {
+        Self::new(
+            constraint.tokenizer.clone(),
+            constraint.parser.clone(),
+            constraint.ignore_terminal_ids.clone(),
+            constraint.llm_tokens.clone(),
+            constraint.special_tokens.clone(),
+            constraint.max_llm_token_id,
+        )
+    }
+
+    /// Returns the text generated so far, without special tokens.
+    pub fn text(&self) -> Vec<u8> {
+        self.generated.iter().flat_map(|segment| match segment {
+            Segment::Text(text) => text.as_slice(),
+            Segment::GrammarToken(_) => &[],
+        }).copied().collect()
+    }
+
+    /// Returns the LLM tokens that are allowed next.
+    pub fn get_mask(&self) -> BitVec {
+        let mut result = BitVec::new();
+        result.resize(self.max_llm_token_id + 1, false);
+        if self.ended {
+            return result;
+        }
+        for (llm_token, llm_token_id) in &self.llm_tokens {
+            let mut generated = self.generated.clone();
+            generated.push(Segment::Text(llm_token.clone()));
+            result.set(llm_token_id.0, self.allows(&generated));
+        }
+        for (llm_token_id, &token_id) in &self.special_tokens.terminal_llm_token_ids {
+            let mut generated = self.generated.clone();
+            generated.push(Segment::GrammarToken(token_id));
+            result.set(llm_token_id.0, self.allows(&generated));
+        }
+        if self.fully_matches() {
+            for eos_llm_token_id in &self.special_tokens.eos_llm_token_ids {
+                result.set(eos_llm_token_id.0, true);
+            }
+        }
+        for forbidden_llm_token_id in &self.special_tokens.forbidden_llm_token_ids {
+            result.set(forbidden_llm_token_id.0, false);
+        }
+        self.special_tokens.apply_aliases(&mut result);
+        result
+    }
+
+    /// Commits `llm_token_id`, whether or not it's allowed. After a token that isn't allowed, nothing is.
+    pub fn commit(&mut self, llm_token_id: LLMTokenID) {
+        let llm_token_id = self.special_tokens.resolve_alias(llm_token_id);
+        if self.special_tokens.eos_llm_token_ids.contains(&llm_token_id) {
+            self.ended = true;
+        } else if let Some(&token_id) = self.special_tokens.terminal_llm_token_ids.get(&llm_token_id) {
+            self.generated.push(Segment::GrammarToken(token_id));
+        } else if let Some(llm_token) = self.llm_tokens.get_by_right(&llm_token_id) {
+            self.generated.push(Segment::Text(llm_token.clone()));
+        } else {
+            self.ended = true;
+        }
+    }
+
+    /// Returns true if the output so far is a complete match of the grammar.
+    pub fn fully_matches(&self) -> bool {
+        !self.ended && self.lex_all(&self.generated).into_iter().any(|(token_ids, tokenizer_state)| {
+            self.end_of_input_token_ids(tokenizer_state).into_iter().any(|last_token_id| {
+                let mut glr_parse_state = self.parser.init_glr_parser();
+                for &token_id in token_ids.iter().chain(&last_token_id) {
+                    glr_parse_state.step(TerminalID(token_id));
+                }
+                glr_parse_state.parse_eof();
+                glr_parse_state.fully_matches()
+            })
+        })
+    }
+
+    /// Returns true if `generated` is the start of some output that matches the grammar.
+    fn allows(&self, generated: &[Segment]) -> bool {
+        self.lex_all(generated).into_iter().any(|(token_ids, tokenizer_state)| {
+            let mut glr_parse_state = self.parser.init_glr_parser();
+            for token_id in token_ids {
+                glr_parse_state.step(TerminalID(token_id));
+            }
+            if glr_parse_state.active_states.is_empty() {
+                return false;
+            }
+            if tokenizer_state == self.tokenizer.initial_state_id() {
+                return true;
+            }
+            // The incomplete last grammar token must be able to continue the parse.
+            self.tokenizer.tokens_accessible_from_state(tokenizer_state).into_iter().any(|token_id| {
+                if self.ignore_terminal_ids.contains(&token_id) {
+                    return true;
+                }
+                let mut glr_parse_state = glr_parse_state.clone();
+                glr_parse_state.step(TerminalID(token_id));
+                glr_parse_state.is_ok()
+            })
+        })
+    }
+
+    /// Returns every way to lex `generated`: the sequence of complete grammar tokens (without ignored ones) and the
+    /// tokenizer state that the rest of the text leaves the tokenizer in.
+    fn lex_all(&self, generated: &[Segment]) -> BTreeSet<(Vec<TokenID>, usize)> {
+        let mut results = BTreeSet::from([(Vec::new(), self.tokenizer.initial_state_id())]);
+        for segment in generated {
+            results = results.into_iter().flat_map(|(token_ids, tokenizer_state)| match segment {
+                Segment::Text(text) => self.lex_text(text, token_ids, tokenizer_state),
+                // A special token can only come at a grammar token boundary.
+                Segment::GrammarToken(token_id) => self.end_of_input_token_ids(tokenizer_state).into_iter().map(|last_token_id| {
+                    let mut token_ids = token_ids.clone();
+                    token_ids.extend(last_token_id);
+                    token_ids.push(*token_id);
+                    (token_ids, self.tokenizer.initial_state_id())
+                }).collect(),
+            }).collect();
+        }
+        results
+    }
+
+    /// Returns every way to lex `text`, starting after `token_ids` in `tokenizer_state`.
+    fn lex_text(&self, text: &[u8], token_ids: Vec<TokenID>, tokenizer_state: usize) -> BTreeSet<(Vec<TokenID>, usize)> {
+        let mut results = BTreeSet::new();
+        let mut stack = vec![(0, tokenizer_state, token_ids)];
+        while let Some((position, tokenizer_state, token_ids)) = stack.pop() {
+            if position == text.len() {
+                results.insert((token_ids, tokenizer_state));
+                continue;
+            }
+            let execute_result = self.tokenizer.execute_from_state(&text[position..], tokenizer_state);
+            for token in execute_result.matches {
+                let mut next_token_ids = token_ids.clone();
+                if !self.ignore_terminal_ids.contains(&token.id) {
+                    next_token_ids.push(token.id);
+                }
+                stack.push((position + token.width, self.tokenizer.initial_state_id(), next_token_ids));
+            }
+            if let Some(new_state) = execute_result.new_state {
+                results.insert((token_ids, new_state));
+            }
+        }
+        results
+    }
+
+    /// Returns the ways the input can end in `tokenizer_state`: nothing more at a grammar token boundary, or one of
+    /// the grammar tokens that only the end of the input confirms (`None` for ignored ones).
+    fn end_of_input_token_ids(&self, tokenizer_state: usize) -> Vec<Option<TokenID>> {
+        if tokenizer_state == self.tokenizer.initial_state_id() {
+            return vec![None];
+        }
+        self.tokenizer.tokens_matching_at_end_of_input(tokenizer_state).into_iter()
+            .map(|token_id| (!self.ignore_terminal_ids.contains(&token_id)).then_some(token_id))
+            .collect()
+    }
+}
@@ -53,6 +53,11 @@ impl<T, E: Ord> TrieNode<E, T> {
         self.children.is_empty()
     }
 
+    /// Returns true if more than one edge leads to this node, i.e. it's reachable via more than one path.
+    pub fn is_shared(&self) -> bool {
+        self.num_parents > 1
+    }
+
     pub fn deep_clone(&self) -> Arc<Mutex<TrieNode<E, T>>> where T: Clone, E: Clone {
         let mut new_children = BTreeMap::new();
         for (edge, child) in &self.children {
@@ -69,6 +74,8 @@ impl<T, E: Ord> TrieNode<E, T> {
     pub fn shallow_clone(&self) -> Arc<Mutex<TrieNode<E, T>>> where T: Clone, E: Clone {
         let mut new_children = BTreeMap::new();
         for (edge, child) in self.children.clone() {
+            // The clone is a new parent of each child.
+            child.try_lock().unwrap().num_parents += 1;
             new_children.insert(edge, child);
         }
         Arc::new(Mutex::new(TrieNode {
@@ -396,6 +403,23 @@ mod tests {
     use std::sync::{Arc, Mutex};
     use crate::trie::{dump_structure, TrieNode};
 
+    #[test]
+    fn test_shallow_clone_counts_parents() {
+        // a -> b -> c
+        let c = Arc::new(Mutex::new(TrieNode::new("c")));
+        let mut b = TrieNode::new("b");
+        b.insert(2, c.clone());
+        let mut a = TrieNode::new("a");
+        a.insert(1, Arc::new(Mutex::new(b)));
+        assert!(!c.try_lock().unwrap().is_shared());
+
+        // The copy of b is a second parent of c, so c must now be copied before it's modified for either path.
+        let b2 = a.replace_child_with_clone(&1);
+        assert!(c.try_lock().unwrap().is_shared());
+        assert!(Arc::ptr_eq(&b2.try_lock().unwrap().get(&2).unwrap(), &c));
+        assert!(!b2.try_lock().unwrap().is_shared());
+    }
+
     #[test]
     fn test_trie() {
         let mut a = TrieNode::new("a");
@@ -0,0 +1,315 @@
+// src/vocab.rs
+use crate::constraint::SpecialTokenPolicy;
+use crate::precompute::LLMTokenID;
+use base64::Engine;
+use bimap::BiBTreeMap;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+
+type LLMTokenMap = BiBTreeMap<Vec<u8>, LLMTokenID>;
+
+/// Special tokens that mark the end of generation, most specific first.
+const EOS_TOKEN_NAMES: &[&str] = &["<|eot_id|>", "<|end_of_text|>", "<|endoftext|>", "<|im_end|>", "<|end|>", "</s>", "<eos>"];
+
+/// An LLM vocabulary loaded from a tokenizer file, ready to be passed to `GrammarConstraint::from_grammar`.
+#[derive(Debug, Clone)]
+pub struct LLMVocab {
+    /// The exact bytes of each regular (non-special) token.
+    pub llm_tokens: LLMTokenMap,
+    /// Special tokens, by content. These are not text, so they are not in `llm_tokens`.
+    pub special_tokens: BTreeMap<String, LLMTokenID>,
+    /// The special tokens that end generation, most specific first.
+    pub eos_llm_token_ids: Vec<LLMTokenID>,
+    /// The first of `eos_llm_token_ids`, or one past the last token ID if there is none.
+    pub eof_llm_token_id: usize,
+    pub max_llm_token_id: usize,
+    /// Regular tokens whose bytes are the same as those of a lower token ID (e.g. `<0x20>` and `▁`), by that token
+    /// ID. The token map is a bijection, so these are left out of `llm_tokens`. `special_token_policy` makes them
+    /// aliases, so the constraint still allows them.
+    pub duplicate_llm_token_ids: BTreeMap<LLMTokenID, LLMTokenID>,
+}
+
+#[derive(Debug)]
+pub enum VocabError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    /// The file parsed, but isn't a vocabulary we understand.
+    Format(String),
+}
+
+impl Display for VocabError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VocabError::Io(err) => write!(f, "failed to read vocabulary: {}", err),
+            VocabError::Json(err) => write!(f, "invalid tokenizer.json: {}", err),
+            VocabError::Format(message) => write!(f, "unsupported vocabulary: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for VocabError {}
+
+impl From<std::io::Error> for VocabError {
+    fn from(err: std::io::Error) -> Self {
+        VocabError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for VocabError {
+    fn from(err: serde_json::Error) -> Self {
+        VocabError::Json(err)
+    }
+}
+
+/// How the token strings of a `tokenizer.json` encode bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TokenEncoding {
+    /// GPT-2 style byte-level BPE: every byte is mapped to a printable character (e.g. ' ' is 'Ġ').
+    ByteLevel,
+    /// SentencePiece: '▁' stands for ' ', and `<0xNN>` tokens are single raw bytes.
+    SentencePiece,
+    /// The token strings are the text itself.
+    Raw,
+}
+
+/// The inverse of GPT-2's `bytes_to_unicode`.
+fn byte_level_decoder() -> HashMap<char, u8> {
+    let mut decoder = HashMap::new();
+    let mut num_unprintable = 0;
+    for byte in 0..=255u8 {
+        let printable = matches!(byte, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+        let c = if printable {
+            char::from(byte)
+        } else {
+            num_unprintable += 1;
+            char::from_u32(255 + num_unprintable).unwrap()
+        };
+        decoder.insert(c, byte);
+    }
+    decoder
+}
+
+fn decode_token(token: &str, encoding: TokenEncoding, byte_level_decoder: &HashMap<char, u8>) -> Vec<u8> {
+    match encoding {
+        TokenEncoding::ByteLevel => token.chars().map(|c| byte_level_decoder.get(&c).copied()).collect::<Option<Vec<u8>>>()
+            // Not byte-level encoded, e.g. a non-special added token.
+            .unwrap_or_else(|| token.as_bytes().to_vec()),
+        TokenEncoding::SentencePiece => {
+            if let Some(hex) = token.strip_prefix("<0x").and_then(|rest| rest.strip_suffix('>')) {
+                if let Ok(byte) = u8::from_str_radix(hex, 16) {
+                    if hex.len() == 2 {
+                        return vec![byte];
+                    }
+                }
+            }
+            token.replace('▁', " ").into_bytes()
+        }
+        TokenEncoding::Raw => token.as_bytes().to_vec(),
+    }
+}
+
+fn detect_encoding(tokenizer: &Value) -> TokenEncoding {
+    // Look for the component types anywhere in the decoder and pre-tokenizer, which may be `Sequence`s.
+    fn has_type(value: &Value, types: &[&str]) -> bool {
+        match value {
+            Value::Object(map) => map.get("type").and_then(Value::as_str).is_some_and(|t| types.contains(&t))
+                || map.values().any(|value| has_type(value, types)),
+            Value::Array(values) => values.iter().any(|value| has_type(value, types)),
+            _ => false,
+        }
+    }
+    let components = [&tokenizer["decoder"], &tokenizer["pre_tokenizer"]];
+    if components.iter().any(|component| has_type(component, &["ByteLevel"])) {
+        TokenEncoding::ByteLevel
+    } else if components.iter().any(|component| has_type(component, &["Metaspace", "ByteFallback", "Replace"]))
+        || tokenizer["model"]["byte_fallback"].as_bool() == Some(true) {
+        TokenEncoding::SentencePiece
+    } else {
+        TokenEncoding::Raw
+    }
+}
+
+impl LLMVocab {
+    /// Loads a HuggingFace `tokenizer.json`.
+    pub fn from_tokenizer_json_file(path: impl AsRef<Path>) -> Result<Self, VocabError> {
+        Self::from_tokenizer_json(&std::fs::read_to_string(path)?)
+    }
+
+    /// Parses the contents of a HuggingFace `tokenizer.json`. Byte-level BPE, SentencePiece (including byte
+    /// fallback) and plain-text vocabularies are supported. Added tokens marked `special` go into `special_tokens`;
+    /// the other added tokens are regular text.
+    ///
+    /// WordPiece vocabularies are rejected: the decoder puts a space before each token that doesn't start with
+    /// `##` unless it starts the text, so their tokens don't have fixed bytes.
+    pub fn from_tokenizer_json(json: &str) -> Result<Self, VocabError> {
+        let tokenizer: Value = serde_json::from_str(json)?;
+        if tokenizer["model"]["type"].as_str() == Some("WordPiece") {
+            return Err(VocabError::Format("WordPiece tokens don't have fixed bytes".to_string()));
+        }
+        let encoding = detect_encoding(&tokenizer);
+
+        let mut tokens: BTreeMap<usize, Vec<u8>> = BTreeMap::new();
+        let byte_level_decoder = byte_level_decoder();
+        match &tokenizer["model"]["vocab"] {
+            // BPE: token -> ID.
+            Value::Object(vocab) => {
+                for (token, id) in vocab {
+                    let id = id.as_u64().ok_or_else(|| VocabError::Format(format!("bad ID for token {:?}", token)))?;
+                    tokens.insert(id as usize, decode_token(token, encoding, &byte_level_decoder));
+                }
+            }
+            // Unigram: [token, score], with the index as ID.
+            Value::Array(vocab) => {
+                for (id, entry) in vocab.iter().enumerate() {
+                    let token = entry[0].as_str().ok_or_else(|| VocabError::Format(format!("bad vocab entry {}", entry)))?;
+                    tokens.insert(id, decode_token(token, encoding, &byte_level_decoder));
+                }
+            }
+            _ => return Err(VocabError::Format("missing model.vocab".to_string())),
+        }
+
+        let mut special_tokens = BTreeMap::new();
+        for added_token in tokenizer["added_tokens"].as_array().into_iter().flatten() {
+            let (Some(id), Some(content)) = (added_token["id"].as_u64(), added_token["content"].as_str()) else {
+                return Err(VocabError::Format(format!("bad added token {}", added_token)));
+            };
+            if added_token["special"].as_bool() == Some(true) {
+                tokens.remove(&(id as usize));
+                special_tokens.insert(content.to_string(), LLMTokenID(id as usize));
+            } else {
+                tokens.insert(id as usize, content.as_bytes().to_vec());
+            }
+        }
+
+        Ok(Self::from_parts(tokens, special_tokens))
+    }
+
+    /// Loads a tiktoken rank file. These don't list special tokens, so pass them in `special_tokens` (e.g.
+    /// `("<|endoftext|>", 100257)`).
+    pub fn from_tiktoken_file(path: impl AsRef<Path>, special_tokens: &[(&str, usize)]) -> Result<Self, VocabError> {
+        Self::from_tiktoken(&std::fs::read_to_string(path)?, special_tokens)
+    }
+
+    /// Parses a tiktoken rank file: one `<base64 token> <rank>` pair per line, where the rank is the token ID.
+    pub fn from_tiktoken(ranks: &str, special_tokens: &[(&str, usize)]) -> Result<Self, VocabError> {
+        let mut tokens = BTreeMap::new();
+        for (line_number, line) in ranks.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            let bad_line = || VocabError::Format(format!("bad tiktoken line {}: {:?}", line_number + 1, line));
+            let (token, rank) = line.split_once(' ').ok_or_else(bad_line)?;
+            let token = base64::engine::general_purpose::STANDARD.decode(token).map_err(|_| bad_line())?;
+            let rank: usize = rank.trim().parse().map_err(|_| bad_line())?;
+            tokens.insert(rank, token);
+        }
+        let special_tokens = special_tokens.iter().map(|&(content, id)| (content.to_string(), LLMTokenID(id))).collect();
+        Ok(Self::from_parts(tokens, special_tokens))
+    }
+
+    /// A policy that ends generation at any of the detected EOS tokens, and allows duplicate tokens wherever the
+    /// token with the same bytes is. All other special tokens are disallowed.
+    pub fn special_token_policy(&self) -> SpecialTokenPolicy {
+        SpecialTokenPolicy {
+            eos_llm_token_ids: self.eos_llm_token_ids.iter().copied().collect(),
+            alias_llm_token_ids: self.duplicate_llm_token_ids.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn from_parts(tokens: BTreeMap<usize, Vec<u8>>, special_tokens: BTreeMap<String, LLMTokenID>) -> Self {
+        let mut llm_tokens = LLMTokenMap::new();
+        let mut duplicate_llm_token_ids = BTreeMap::new();
+        for (id, token) in tokens {
+            if let Err((token, _)) = llm_tokens.insert_no_overwrite(token, LLMTokenID(id)) {
+                duplicate_llm_token_ids.insert(LLMTokenID(id), *llm_tokens.get_by_left(&token).unwrap());
+            }
+        }
+
+        let eos_llm_token_ids: Vec<LLMTokenID> = EOS_TOKEN_NAMES.iter().filter_map(|name| special_tokens.get(*name).copied()).collect();
+        let last_token_id = llm_tokens.right_values().chain(special_tokens.values()).chain(duplicate_llm_token_ids.keys())
+            .map(|id| id.0)
+            .max();
+        let eof_llm_token_id = eos_llm_token_ids.first().map_or(last_token_id.map_or(0, |id| id + 1), |id| id.0);
+        let max_llm_token_id = last_token_id.map_or(eof_llm_token_id, |id| id.max(eof_llm_token_id));
+        LLMVocab { llm_tokens, special_tokens, eos_llm_token_ids, eof_llm_token_id, max_llm_token_id, duplicate_llm_token_ids }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(vocab: &LLMVocab, bytes: &[u8]) -> Option<usize> {
+        vocab.llm_tokens.get_by_left(&bytes.to_vec()).map(|id| id.0)
+    }
+
+    #[test]
+    fn test_tokenizer_json_byte_level() {
+        let json = r#"{
+            "added_tokens": [
+                {"id": 5, "content": "<|endoftext|>", "special": true},
+                {"id": 6, "content": "<tool>", "special": false}
+            ],
+            "pre_tokenizer": {"type": "ByteLevel", "add_prefix_space": false},
+            "decoder": {"type": "ByteLevel"},
+            "model": {"type": "BPE", "vocab": {"a": 0, "Ġa": 1, "Ċ": 2, "Ã©": 3, "Ġ": 4, "<|endoftext|>": 5}}
+        }"#;
+        let vocab = LLMVocab::from_tokenizer_json(json).unwrap();
+        assert_eq!(token(&vocab, b"a"), Some(0));
+        assert_eq!(token(&vocab, b" a"), Some(1));
+        assert_eq!(token(&vocab, b"\n"), Some(2));
+        assert_eq!(token(&vocab, "é".as_bytes()), Some(3));
+        assert_eq!(token(&vocab, b" "), Some(4));
+        assert_eq!(token(&vocab, b"<tool>"), Some(6));
+        assert_eq!(token(&vocab, b"<|endoftext|>"), None);
+        assert_eq!(vocab.special_tokens["<|endoftext|>"], LLMTokenID(5));
+        assert_eq!(vocab.eos_llm_token_ids, vec![LLMTokenID(5)]);
+        assert_eq!(vocab.special_token_policy(), SpecialTokenPolicy::eos(5));
+        assert_eq!((vocab.eof_llm_token_id, vocab.max_llm_token_id), (5, 6));
+    }
+
+    #[test]
+    fn test_tokenizer_json_sentencepiece() {
+        let json = r#"{
+            "added_tokens": [{"id": 1, "content": "</s>", "special": true}],
+            "decoder": {"type": "Sequence", "decoders": [
+                {"type": "Replace", "pattern": {"String": "▁"}, "content": " "},
+                {"type": "ByteFallback"}
+            ]},
+            "model": {"type": "BPE", "byte_fallback": true, "vocab": {"<unk>": 0, "</s>": 1, "<0x20>": 2, "<0x0A>": 3, "▁": 4, "▁hi": 5}}
+        }"#;
+        let vocab = LLMVocab::from_tokenizer_json(json).unwrap();
+        assert_eq!(token(&vocab, b" "), Some(2));
+        assert_eq!(token(&vocab, b"\n"), Some(3));
+        assert_eq!(token(&vocab, b" hi"), Some(5));
+        assert_eq!(vocab.duplicate_llm_token_ids, BTreeMap::from([(LLMTokenID(4), LLMTokenID(2))]));
+        assert_eq!(vocab.special_token_policy().alias_llm_token_ids, vocab.duplicate_llm_token_ids);
+        assert_eq!(vocab.eof_llm_token_id, 1);
+        assert_eq!(vocab.max_llm_token_id, 5);
+    }
+
+    #[test]
+    fn test_tokenizer_json_wordpiece() {
+        let json = r###"{
+            "decoder": {"type": "WordPiece", "prefix": "##"},
+            "model": {"type": "WordPiece", "continuing_subword_prefix": "##", "vocab": {"[UNK]": 0, "play": 1, "##ing": 2}}
+        }"###;
+        assert!(matches!(LLMVocab::from_tokenizer_json(json), Err(VocabError::Format(_))));
+    }
+
+    #[test]
+    fn test_tiktoken() {
+        // "a", " b" and "\xff".
+        let ranks = "YQ== 0\nIGI= 1\n/w== 2\n";
+        let vocab = LLMVocab::from_tiktoken(ranks, &[("<|endoftext|>", 3)]).unwrap();
+        assert_eq!(token(&vocab, b" b"), Some(1));
+        assert_eq!(token(&vocab, b"\xff"), Some(2));
+        assert_eq!((vocab.eof_llm_token_id, vocab.max_llm_token_id), (3, 3));
+
+        // Without an EOS token, EOF gets an ID past the end of the vocabulary.
+        let vocab = LLMVocab::from_tiktoken(ranks, &[]).unwrap();
+        assert_eq!((vocab.eof_llm_token_id, vocab.max_llm_token_id), (3, 3));
+
+        assert!(matches!(LLMVocab::from_tiktoken("YQ==\n", &[]), Err(VocabError::Format(_))));
+    }
+}